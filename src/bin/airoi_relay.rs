@@ -0,0 +1,59 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use clap::Parser;
+use airoi_core::message::limits::ReceiverLimits;
+use airoi_core::relay::server::{load_or_generate_keys, serve, RelayLimits, RelayStore, DEFAULT_BLOB_TTL, DEFAULT_MAX_BLOBS, DEFAULT_MAX_BLOBS_PER_SENDER, DEFAULT_MAX_BYTES, DEFAULT_RELAY_ADDRESS};
+use airoi_core::transport::{TcpTransport, Transport};
+
+/// Stores end-to-end encrypted messages for airoi users until they fetch them.
+/// Expose the listen address as a Tor hidden service to keep clients anonymous.
+#[derive(Parser, Debug)]
+#[clap(version = "0.1.0", author = "konni332")]
+struct Args {
+    /// Address to listen on
+    #[clap(long, default_value = DEFAULT_RELAY_ADDRESS)]
+    listen: String,
+    /// Directory for the relay key and stored messages (defaults to `airoi-relay` in the config directory)
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+    /// Hours a message is kept before it is deleted unfetched
+    #[clap(long, default_value_t = DEFAULT_BLOB_TTL.as_secs() / 3600)]
+    ttl_hours: u64,
+    /// Messages stored per recipient
+    #[clap(long, default_value_t = DEFAULT_MAX_BLOBS)]
+    max_blobs: usize,
+    /// Bytes stored per recipient
+    #[clap(long, default_value_t = DEFAULT_MAX_BYTES)]
+    max_bytes: u64,
+    /// Messages stored per recipient from a single sender
+    #[clap(long, default_value_t = DEFAULT_MAX_BLOBS_PER_SENDER)]
+    max_blobs_per_sender: usize,
+    /// Maximum number of connections open at the same time
+    #[clap(long, default_value_t = airoi_core::message::limits::DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    let dir = args.data_dir.unwrap_or_else(|| {
+        dirs::config_dir().unwrap_or_else(|| PathBuf::from(".")).join("airoi-relay")
+    });
+
+    let keys = load_or_generate_keys(&dir.join("relay_key.json"))?;
+    println!("Relay public key (ed25519): {}", keys.public_key().ed25519_key());
+
+    let limits = RelayLimits {
+        ttl: Duration::from_secs(args.ttl_hours * 3600),
+        max_blobs: args.max_blobs,
+        max_bytes: args.max_bytes,
+        max_blobs_per_sender: args.max_blobs_per_sender,
+        connections: ReceiverLimits { max_connections: args.max_connections, ..ReceiverLimits::default() },
+    };
+    let store = Arc::new(RelayStore::new(dir.join("mailboxes"), limits)?);
+    let listener = TcpTransport.listen(&args.listen).await?;
+    println!("airoi relay listening on {}", args.listen);
+    serve(listener, keys, store).await?;
+    Ok(())
+}
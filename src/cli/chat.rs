@@ -0,0 +1,172 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::bail;
+use rustyline::error::ReadlineError;
+use rustyline::{DefaultEditor, ExternalPrinter};
+use airoi_core::error::AiroiError;
+use airoi_core::keys::contacts::{get_contacts, Contact};
+use airoi_core::message::envelope::{Envelope, MessageId, MessageKind};
+use airoi_core::message::history::{DeliveryState, History};
+use airoi_core::message::retention::run_retention;
+use airoi_core::message::search::SearchIndex;
+use airoi_core::message::outbox::{run_outbox, Outbox, RetryPolicy};
+use airoi_core::message::receipt::DeliveryStatus;
+use airoi_core::message::receive::{receive, ReceiveConfig};
+use airoi_core::message::session::SessionRegistry;
+use airoi_core::storage::fetch_local_keypair;
+use airoi_core::transport::Transport;
+use crate::cli::execute::{key_change_warning, warn_history};
+
+const SEND_ATTEMPTS: u32 = 3;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+type Printer = Arc<Mutex<Box<dyn ExternalPrinter + Send>>>;
+
+fn print(printer: &Printer, line: String) {
+    let _ = printer.lock().unwrap().print(line);
+}
+
+pub async fn chat(carrier: Arc<dyn Transport>, name: &str, addr: Option<String>) -> anyhow::Result<()> {
+    let Some(contact) = get_contacts()?.into_iter().find(|c| c.name == name) else {
+        bail!("Contact not found")
+    };
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    let registry = SessionRegistry::new(carrier, fetch_local_keypair()?, tx, ReceiveConfig::default());
+
+    let history = Arc::new(History::open(registry.keys()));
+    let outbox = Arc::new(Outbox::open(registry.keys()));
+    tokio::spawn(run_outbox(registry.clone(), outbox, RetryPolicy::default()));
+    tokio::spawn(run_retention(history.clone(), Arc::new(SearchIndex::open(registry.keys()))));
+
+    // accept the contact connecting to us as well, their session is reused for our replies
+    {
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            if let Err(e) = receive(addr, registry).await {
+                eprintln!("receive error: {}", e);
+            }
+        });
+    }
+
+    let mut editor = DefaultEditor::new()?;
+    let printer: Printer = Arc::new(Mutex::new(Box::new(editor.create_external_printer()?)));
+
+    // rustyline blocks, so lines are read on a plain thread and handed over
+    let (line_tx, mut line_rx) = tokio::sync::mpsc::channel::<String>(16);
+    let prompt = format!("{}> ", contact.name);
+    std::thread::spawn(move || {
+        loop {
+            match editor.readline(&prompt) {
+                Ok(line) => {
+                    let _ = editor.add_history_entry(line.as_str());
+                    if line_tx.blocking_send(line).is_err() {
+                        break;
+                    }
+                }
+                Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => break,
+                Err(e) => {
+                    eprintln!("input error: {}", e);
+                    break;
+                }
+            }
+        }
+    });
+
+    println!("Chatting with {}. Type /quit or press Ctrl-D to leave.", contact.name);
+    let sent: Arc<Mutex<HashMap<MessageId, String>>> = Arc::new(Mutex::new(HashMap::new()));
+
+    loop {
+        tokio::select! {
+            line = line_rx.recv() => {
+                let Some(line) = line else {
+                    break;
+                };
+                let line = line.trim().to_string();
+                if line == "/quit" {
+                    break;
+                }
+                if line.is_empty() {
+                    continue;
+                }
+                let registry = registry.clone();
+                let contact = contact.clone();
+                let printer = printer.clone();
+                let sent = sent.clone();
+                let history = history.clone();
+                tokio::spawn(async move {
+                    send_with_reconnect(&registry, &contact, &line, &printer, &sent, &history).await;
+                });
+            }
+            msg = rx.recv() => {
+                let Some(msg) = msg else {
+                    break;
+                };
+                // messages from others are not shown here, but kept for `history`
+                if matches!(msg.kind, MessageKind::Text | MessageKind::GroupText) {
+                    warn_history(history.record_received(&msg));
+                }
+                if msg.sender.fingerprint_x() != contact.fingerprint_x() {
+                    continue;
+                }
+                match msg.kind {
+                    MessageKind::Text => {
+                        print(&printer, msg.to_string());
+                        msg.mark_read().await;
+                    }
+                    MessageKind::Read => {
+                        if let Some(text) = sent.lock().unwrap().remove(&msg.id) {
+                            warn_history(history.set_state(msg.id, DeliveryState::Read));
+                            print(&printer, format!("    read: {}", text));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    registry.close_all().await;
+    Ok(())
+}
+
+async fn send_with_reconnect(
+    registry: &SessionRegistry,
+    contact: &Contact,
+    text: &str,
+    printer: &Printer,
+    sent: &Mutex<HashMap<MessageId, String>>,
+    history: &History,
+) {
+    // the same envelope, id included, is resent on retries. Recognising a copy that did arrive
+    // is up to the receiver's replay guard, which acknowledges it again without showing it twice.
+    let envelope = Envelope::text(text).expiring(contact.disappearing);
+    sent.lock().unwrap().insert(envelope.id, text.to_string());
+    for attempt in 1..=SEND_ATTEMPTS {
+        match registry.send(contact, envelope.clone()).await {
+            Err(e @ AiroiError::KeyMismatch { .. }) => {
+                warn_history(history.record_sent(contact, &envelope, DeliveryState::Failed));
+                print(printer, key_change_warning(&e).unwrap_or_default());
+                return;
+            }
+            Ok(DeliveryStatus::TimedOut) | Err(_) if attempt < SEND_ATTEMPTS => {
+                print(printer, format!("    not confirmed, reconnecting ({}/{})", attempt, SEND_ATTEMPTS));
+                registry.close(contact).await;
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+            Ok(status) => {
+                warn_history(history.record_sent(contact, &envelope, status.into()));
+                print(printer, format!("    {}: {}", status, text));
+                return;
+            }
+            Err(e) => {
+                warn_history(history.record_sent(contact, &envelope, DeliveryState::Failed));
+                print(printer, format!("    failed: {} ({})", text, e));
+                return;
+            }
+        }
+    }
+    warn_history(history.record_sent(contact, &envelope, DeliveryState::Unconfirmed));
+    print(printer, format!("    not delivered: {}", text));
+}
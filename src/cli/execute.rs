@@ -1,10 +1,32 @@
+use std::sync::Arc;
+use std::time::Duration;
 use anyhow::bail;
-use airoi_core::keys::contacts::{get_contacts, Contact};
+use inquire::Confirm;
+use airoi_core::error::AiroiError;
+use airoi_core::keys::contacts::{get_contacts, repin_contact, set_cover, set_disappearing, set_padding, set_relay, Contact};
+use airoi_core::message::cover::CoverTraffic;
 use airoi_core::keys::key_gen::{generate_key_pair};
-use airoi_core::message::receive::{receive};
-use airoi_core::message::send::send;
+use airoi_core::keys::groups::{find_group, get_groups, Group};
+use airoi_core::keys::prekeys::{ContactCard, PrekeyStore};
+use airoi_core::message::envelope::{Envelope, MessageKind};
+use airoi_core::message::file::send_file_via;
+use airoi_core::message::group::{publish_update, send_group, FanOut};
+use airoi_core::message::history::{Conversation, DeliveryState, Direction, History};
+use airoi_core::message::retention::{open_history, run_retention, set_retention, Retention};
+use airoi_core::message::search::{SearchFilter, SearchIndex};
+use airoi_core::message::limits::ReceiverLimits;
+use airoi_core::message::outbox::{deliver, is_transient, run_outbox, Due, Outbox, RetryPolicy};
+use airoi_core::message::ratchet::RatchetStore;
+use airoi_core::message::receipt::DeliveryStatus;
+use airoi_core::message::receive::{receive, ReceiveConfig};
+use airoi_core::message::session::{SessionEvent, SessionRegistry};
+use airoi_core::message::send::send_envelope_via;
+use airoi_core::relay::server::MAX_ONE_TIME_PREKEYS;
+use airoi_core::relay::{deposit, fetch, publish_prekeys};
+use airoi_core::transport::{TcpTransport, TorTransport, Transport};
 use airoi_core::storage::{fetch_local_keypair, store_keypair};
-use crate::cli::parser::{AiroiCommand, Cli};
+use crate::cli::chat::chat;
+use crate::cli::parser::{AiroiCommand, Carrier, Cli, GroupCommand, OutboxCommand};
 
 
 
@@ -26,6 +48,21 @@ pub async fn execute_cli_command(cli: &Cli) -> anyhow::Result<()> {
             airoi_core::keys::contacts::add_contact(new_contact.clone())?;
             println!("Contact '{}' added. Public key (ed25519): {}", name, new_contact.public_key().ed25519_key());
         }
+        AiroiCommand::ExportCard { name, address, relay } => {
+            let keys = fetch_local_keypair()?;
+            let card = ContactCard {
+                name: name.clone(),
+                address: address.clone(),
+                relay: relay.clone(),
+                prekeys: PrekeyStore::open(&keys).card_bundle(&keys)?,
+            };
+            println!("{}", card.encode()?);
+        }
+        AiroiCommand::ImportCard { card, name } => {
+            let new_contact = ContactCard::decode(card)?.into_contact(name.clone());
+            airoi_core::keys::contacts::add_contact(new_contact.clone())?;
+            println!("Contact '{}' added. Public key (ed25519): {}", new_contact.name, new_contact.public_key().ed25519_key());
+        }
         AiroiCommand::RemoveContact { name } => {
             if airoi_core::keys::contacts::remove_contact(name)? {
                 println!("Contact '{}' removed", name);
@@ -37,30 +74,264 @@ pub async fn execute_cli_command(cli: &Cli) -> anyhow::Result<()> {
         AiroiCommand::ListContacts => {
             list_contacts()?;
         }
-        AiroiCommand::Receive { addr } => {
+        AiroiCommand::SetPadding { name, policy } => {
+            if set_padding(name, *policy)? {
+                println!("Messages to '{}' are padded with '{}'", name, policy);
+            }
+            else {
+                println!("Contact '{}' not found", name);
+            }
+        }
+        AiroiCommand::SetCover { name, off, frame_size, bandwidth } => {
+            let cover = (!off).then_some(CoverTraffic { frame_size: *frame_size, bandwidth: *bandwidth });
+            if !set_cover(name, cover)? {
+                println!("Contact '{}' not found", name);
+            }
+            else if let Some(cover) = cover {
+                println!("Cover traffic for '{}': {} byte frames every {:?}", name, cover.frame_size, cover.interval());
+            }
+            else {
+                println!("Cover traffic for '{}' off", name);
+            }
+        }
+        AiroiCommand::SetRelay { name, address, off } => {
+            let relay = if *off { None } else { address.clone() };
+            if !set_relay(name, relay.clone())? {
+                println!("Contact '{}' not found", name);
+            }
+            else if let Some(relay) = relay {
+                println!("Messages to '{}' are left at {} while they are offline", name, relay);
+            }
+            else {
+                println!("Messages to '{}' are no longer left at a relay", name);
+            }
+        }
+        AiroiCommand::SetDisappearing { name, timer, off } => {
+            let timer = if *off { None } else { *timer };
+            if !set_disappearing(name, timer)? {
+                println!("Contact '{}' not found", name);
+            }
+            else if let Some(timer) = timer {
+                println!("Messages to '{}' disappear after {:?}", name, Duration::from_secs(timer as u64));
+            }
+            else {
+                println!("Messages to '{}' are kept", name);
+            }
+        }
+        AiroiCommand::SetRetention { days, off } => {
+            let retention = Retention { max_age_days: if *off { None } else { *days } };
+            set_retention(retention)?;
+            match retention.max_age_days {
+                Some(days) => println!("Messages older than {} days are deleted while `receive` or `chat` runs", days),
+                None => println!("All messages are kept"),
+            }
+        }
+        AiroiCommand::RepinContact { name } => {
+            let contacts = get_contacts()?;
+            let Some(contact) = contacts.into_iter().find(|c| &c.name == name) else {
+                bail!("Contact not found")
+            };
+            let Some(pending) = contact.pending_key() else {
+                bail!("'{}' has not presented a different key", name)
+            };
+            println!("    pinned fingerprint:    {}", contact.public_key().display_fingerprint());
+            println!("    presented fingerprint: {}", pending.display_fingerprint());
+            let confirm = Confirm::new("Trust the presented key from now on?")
+                .with_default(false)
+                .prompt()
+                .unwrap_or(false);
+            if confirm && repin_contact(name)? {
+                println!("Key of '{}' re-pinned", name);
+            }
+            else {
+                println!("Key of '{}' left unchanged", name);
+            }
+        }
+        AiroiCommand::Receive { addr, max_message_size, downloads_dir, idle_timeout, max_connections, handshake_timeout, max_frame_size } => {
             let (tx, mut rx) = tokio::sync::mpsc::channel(1);
 
             let addr = addr.clone();
+            let mut config = ReceiveConfig {
+                max_message_size: *max_message_size,
+                idle_timeout: Duration::from_secs(*idle_timeout),
+                limits: ReceiverLimits {
+                    max_connections: *max_connections,
+                    handshake_timeout: Duration::from_secs(*handshake_timeout),
+                    max_frame_size: *max_frame_size,
+                    ..ReceiverLimits::default()
+                },
+                ..ReceiveConfig::default()
+            };
+            if let Some(dir) = downloads_dir {
+                config.downloads_dir = dir.clone();
+            }
+            let registry = SessionRegistry::new(carrier(cli.transport), fetch_local_keypair()?, tx, config);
+            let mut events = registry.events();
             tokio::spawn(async move {
-                if let Err(e) = receive(addr, tx).await {
+                while let Ok(event) = events.recv().await {
+                    match event {
+                        SessionEvent::Opened(contact) => println!("Session with {} opened", contact.name),
+                        SessionEvent::Closed(contact, reason) => println!("Session with {} closed ({:?})", contact.name, reason),
+                    }
+                }
+            });
+            let history = Arc::new(History::open(registry.keys()));
+            let outbox = Arc::new(Outbox::open(registry.keys()));
+            tokio::spawn(run_outbox(registry.clone(), outbox, RetryPolicy::default()));
+            tokio::spawn(run_retention(history.clone(), Arc::new(SearchIndex::open(registry.keys()))));
+            tokio::spawn(async move {
+                if let Err(e) = receive(addr, registry).await {
                     eprintln!("receive error: {}", e);
                 }
             });
 
             while let Some(msg) = rx.recv().await {
-                println!("{}", msg);
+                match msg.kind {
+                    MessageKind::Text | MessageKind::GroupText => {
+                        warn_history(history.record_received(&msg));
+                        println!("{}", msg);
+                        msg.mark_read().await;
+                    }
+                    MessageKind::GroupControl => println!("{}", msg),
+                    MessageKind::FileOffer => {
+                        println!("{}", msg);
+                        // the prompt blocks on the terminal, keep it off the runtime's worker threads
+                        let accept = tokio::task::spawn_blocking(|| Confirm::new("Accept this file?").prompt().unwrap_or(false))
+                            .await
+                            .unwrap_or(false);
+                        if accept {
+                            msg.accept_file().await?;
+                        }
+                        else {
+                            msg.reject_file().await;
+                        }
+                    }
+                    MessageKind::FileComplete => println!("{}", msg),
+                    _ => {}
+                }
             }
         }
         AiroiCommand::Send { name, message } => {
             let contacts = get_contacts()?;
-            for c in &contacts {
-                if &c.name == name.as_str() {
-                    send(c.clone(), message.as_str()).await?;
+            let Some(contact) = contacts.into_iter().find(|c| &c.name == name) else {
+                bail!("Contact not found")
+            };
+            let keys = fetch_local_keypair()?;
+            let history = open_history(&keys)?;
+            let envelope = Envelope::text(message).expiring(contact.disappearing);
+            // the message may have arrived without a receipt, a retry with the same id is dropped
+            // as a duplicate by the receiver
+            let (outcome, reason) = match send_envelope_via(carrier(cli.transport).as_ref(), &keys, contact.clone(), envelope.clone()).await {
+                Ok(DeliveryStatus::TimedOut) => (format!("Delivery to '{}' unconfirmed", name), DeliveryStatus::TimedOut.to_string()),
+                Ok(status) => {
+                    warn_history(history.record_sent(&contact, &envelope, status.into()));
+                    println!("Message {}", status);
+                    if !status.is_delivered() {
+                        bail!("Delivery to '{}' not confirmed: {}", name, status);
+                    }
                     return Ok(());
                 }
+                Err(e) if is_transient(&e) => (format!("'{}' not reachable", name), e.to_string()),
+                Err(e) => {
+                    warn_history(history.record_sent(&contact, &envelope, DeliveryState::Failed));
+                    print_key_change_warning(&e);
+                    return Err(e.into());
+                }
+            };
+            if let Some(relay) = &contact.relay {
+                match deposit(carrier(cli.transport).as_ref(), &keys, &RatchetStore::open(&keys), relay, &contact, &envelope).await {
+                    Ok(_) => {
+                        warn_history(history.record_sent(&contact, &envelope, DeliveryState::Relayed));
+                        bail!("{} ({}), message left at relay {}", outcome, reason, relay);
+                    }
+                    Err(e) => eprintln!("Relay {} failed: {}", relay, e),
+                }
+            }
+            let queued = Outbox::open(&keys).push(&contact, &envelope, &reason, &RetryPolicy::default())?;
+            warn_history(history.record_sent(&contact, &envelope, DeliveryState::Queued));
+            bail!("{} ({}), message queued for retry as {}", outcome, reason, queued.id());
+        }
+        AiroiCommand::SendFile { name, path } => {
+            let contacts = get_contacts()?;
+            let Some(contact) = contacts.into_iter().find(|c| &c.name == name) else {
+                bail!("Contact not found")
+            };
+            let keys = fetch_local_keypair()?;
+            let status = send_file_via(carrier(cli.transport).as_ref(), &keys, contact, path).await
+                .inspect_err(print_key_change_warning)?;
+            println!("File {}", status);
+            if !status.is_delivered() {
+                bail!("Transfer of '{}' to '{}' not confirmed: {}", path.display(), name, status);
+            }
+        }
+        AiroiCommand::Chat { name, addr } => {
+            chat(carrier(cli.transport), name, addr.clone()).await?;
+        }
+        AiroiCommand::History { name, since, limit } => {
+            history(name, since.as_deref(), *limit)?;
+        }
+        AiroiCommand::Search { query, contact, since, until, direction, limit, rebuild } => {
+            let filter = SearchFilter {
+                conversation: contact.as_deref().map(conversation).transpose()?,
+                since: since.as_deref().map(parse_time).transpose()?,
+                until: until.as_deref().map(parse_time).transpose()?,
+                direction: *direction,
+            };
+            let keys = fetch_local_keypair()?;
+            let (history, index) = (open_history(&keys)?, SearchIndex::open(&keys));
+            if *rebuild {
+                println!("Indexed {} messages", index.rebuild(&history)?);
+            }
+            let found = index.search(&history, query, &filter, *limit)?;
+            println!("Messages matching '{}':", query);
+            if found.is_empty() {
+                println!("    No messages found");
+            }
+            for entry in found {
+                let sent = chrono::DateTime::from_timestamp_millis(entry.sent_at).unwrap_or_default().to_rfc3339();
+                match entry.direction {
+                    Direction::Sent => println!("    {} {}  you to {}: {}", entry.id(), sent, entry.contact, entry.text),
+                    Direction::Received => println!("    {} {}  {}: {}", entry.id(), sent, entry.contact, entry.text),
+                }
             }
-            bail!("Contact not found")
-
+        }
+        AiroiCommand::DeleteMessage { id } => {
+            let keys = fetch_local_keypair()?;
+            let history = open_history(&keys)?;
+            if history.delete(id)? {
+                // drop the deleted words from the index right away rather than at the next search
+                SearchIndex::open(&keys).sync(&history)?;
+                println!("Message {} deleted", id);
+            }
+            else {
+                println!("Message {} not found", id);
+            }
+        }
+        AiroiCommand::Fetch { relay } => {
+            let keys = fetch_local_keypair()?;
+            let messages = fetch(carrier(cli.transport).as_ref(), &keys, &RatchetStore::open(&keys), &PrekeyStore::open(&keys), relay, &get_contacts()?).await?;
+            if messages.is_empty() {
+                println!("No messages waiting at {}", relay);
+            }
+            let history = open_history(&keys)?;
+            for message in messages {
+                warn_history(history.record_received(&message));
+                println!("{}", message);
+            }
+        }
+        AiroiCommand::PublishPrekeys { relay, count } => {
+            if *count > MAX_ONE_TIME_PREKEYS {
+                bail!("Relays keep at most {} one-time prekeys", MAX_ONE_TIME_PREKEYS);
+            }
+            let keys = fetch_local_keypair()?;
+            let kept = publish_prekeys(carrier(cli.transport).as_ref(), &keys, &PrekeyStore::open(&keys), relay, *count).await?;
+            println!("Published prekeys with {} one-time prekeys at {}", kept, relay);
+        }
+        AiroiCommand::Group { command } => {
+            group(cli.transport, command).await?;
+        }
+        AiroiCommand::Outbox { command } => {
+            outbox(cli.transport, command).await?;
         }
         AiroiCommand::WhoAmI => {
             let current = fetch_local_keypair()?;
@@ -70,6 +341,245 @@ pub async fn execute_cli_command(cli: &Cli) -> anyhow::Result<()> {
     Ok(())
 }
 
+pub(crate) fn carrier(kind: Carrier) -> Arc<dyn Transport> {
+    match kind {
+        Carrier::Tor => Arc::new(TorTransport::new()),
+        Carrier::Tcp => Arc::new(TcpTransport),
+        #[cfg(unix)]
+        Carrier::Unix => Arc::new(airoi_core::transport::UnixTransport),
+    }
+}
+
+/// Warning shown when a contact presents a key other than the pinned one
+pub(crate) fn key_change_warning(e: &AiroiError) -> Option<String> {
+    let AiroiError::KeyMismatch { contact, pinned, presented } = e else {
+        return None;
+    };
+    Some(format!(
+        "WARNING: the key of '{contact}' has changed!\n\
+        Someone may be intercepting your messages, or the contact set up a new key.\n    \
+            pinned fingerprint:    {pinned}\n    \
+            presented fingerprint: {presented}\n\
+        Nothing was sent. Verify the new fingerprint with the contact, then run `repin-contact {contact}`."
+    ))
+}
+
+fn print_key_change_warning(e: &AiroiError) {
+    if let Some(warning) = key_change_warning(e) {
+        eprintln!("{}", warning);
+    }
+}
+
+/// Failing to record a message is reported but does not stop sending or receiving
+pub(crate) fn warn_history<T>(result: airoi_core::error::Result<T>) {
+    if let Err(e) = result {
+        eprintln!("history error: {}", e);
+    }
+}
+
+/// Unix milliseconds of a date (YYYY-MM-DD, midnight UTC) or an RFC 3339 time
+fn parse_time(time: &str) -> anyhow::Result<i64> {
+    if let Ok(parsed) = chrono::DateTime::parse_from_rfc3339(time) {
+        return Ok(parsed.timestamp_millis());
+    }
+    match chrono::NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        Ok(date) => Ok(date.and_time(chrono::NaiveTime::MIN).and_utc().timestamp_millis()),
+        Err(_) => bail!("'{}' is neither a date (YYYY-MM-DD) nor an RFC 3339 time", time),
+    }
+}
+
+/// Direct messages with the contact of this name, or else messages in the group
+fn conversation(name: &str) -> anyhow::Result<Conversation> {
+    match get_contacts()?.into_iter().find(|c| c.name == name) {
+        Some(contact) => Ok(Conversation::Contact(contact.fingerprint_x().to_string())),
+        None => match find_group(name)? {
+            Some(group) => Ok(Conversation::Group(group.id)),
+            None => bail!("No contact or group named '{}'", name),
+        },
+    }
+}
+
+fn history(name: &str, since: Option<&str>, limit: usize) -> anyhow::Result<()> {
+    let conversation = conversation(name)?;
+    let since = since.map(parse_time).transpose()?;
+    let keys = fetch_local_keypair()?;
+    let history = open_history(&keys)?;
+    let entries = history.conversation(&conversation, since, limit)?;
+    println!("History of {}:", name);
+    if entries.is_empty() {
+        println!("    No messages");
+    }
+    for entry in entries {
+        let sent = chrono::DateTime::from_timestamp_millis(entry.sent_at).unwrap_or_default().to_rfc3339();
+        match entry.direction {
+            Direction::Sent => println!("    {}  you: {} ({})", sent, entry.text, entry.state),
+            Direction::Received => println!("    {}  {}: {}", sent, entry.contact, entry.text),
+        }
+    }
+    Ok(())
+}
+
+async fn outbox(transport: Carrier, command: &OutboxCommand) -> anyhow::Result<()> {
+    let keys = fetch_local_keypair()?;
+    let outbox = Outbox::open(&keys);
+    match command {
+        OutboxCommand::List => {
+            let queued = outbox.list()?;
+            println!("Outbox:");
+            if queued.is_empty() {
+                println!("    No queued messages");
+            }
+            for message in queued {
+                let next = chrono::DateTime::from_timestamp_millis(message.next_attempt).unwrap_or_default();
+                println!("    {} to {}:", message.id(), message.contact);
+                println!("        message: {}", message.text);
+                println!("        attempts: {}, next attempt: {}", message.attempts, next.to_rfc3339());
+                if let Some(error) = &message.last_error {
+                    println!("        last error: {}", error);
+                }
+            }
+        }
+        OutboxCommand::Retry { id } => {
+            let due = id.clone().map(Due::Message).unwrap_or(Due::All);
+            let (tx, _rx) = tokio::sync::mpsc::channel(1);
+            let registry = SessionRegistry::new(carrier(transport), keys, tx, ReceiveConfig::default());
+            let report = deliver(&registry, &outbox, &RetryPolicy::default(), &get_contacts()?, &due).await?;
+            registry.close_all().await;
+            warn_history(open_history(registry.keys()).and_then(|history| report.record(&history)));
+            for message in &report.delivered {
+                println!("{} to {} delivered", message.id(), message.contact);
+            }
+            for message in &report.rejected {
+                println!("{} to {} rejected", message.id(), message.contact);
+            }
+            for message in &report.expired {
+                println!("{} to {} expired", message.id(), message.contact);
+            }
+            println!("{} message(s) still queued", report.pending);
+        }
+        OutboxCommand::Cancel { id } => {
+            match outbox.cancel(id)? {
+                Some(message) => println!("Message {} to {} cancelled", message.id(), message.contact),
+                None => println!("Message {} not queued", id),
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn group(transport: Carrier, command: &GroupCommand) -> anyhow::Result<()> {
+    if let GroupCommand::List = command {
+        return list_groups();
+    }
+    let keys = fetch_local_keypair()?;
+    let own = keys.public_key().ed25519_key().to_string();
+    let contacts = get_contacts()?;
+    let member_key = |name: &str| -> anyhow::Result<String> {
+        match contacts.iter().find(|c| c.name == name) {
+            Some(contact) if !contact.public_key().ed25519_key().is_empty() => Ok(contact.public_key().ed25519_key().to_string()),
+            Some(_) => bail!("'{}' has no proven ed25519 key yet, exchange a message first", name),
+            None => bail!("Contact '{}' not found", name),
+        }
+    };
+    let existing = |name: &str| -> anyhow::Result<Group> {
+        match find_group(name)? {
+            Some(group) => Ok(group),
+            None => bail!("Group '{}' not found", name),
+        }
+    };
+
+    let (tx, _rx) = tokio::sync::mpsc::channel(1);
+    let registry = SessionRegistry::new(carrier(transport), keys, tx, ReceiveConfig::default());
+    let report = match command {
+        GroupCommand::Create { name, members } => {
+            let members = members.iter().map(|m| member_key(m)).collect::<anyhow::Result<Vec<_>>>()?;
+            let group = Group::new(name, &own, members);
+            println!("Group '{}' created with id {}", group.name, group.id);
+            publish_update(&registry, group, None).await?
+        }
+        GroupCommand::Add { group, member } => {
+            let previous = existing(group)?;
+            let mut changed = previous.clone();
+            if !changed.add_member(&member_key(member)?) {
+                bail!("'{}' already is a member of '{}'", member, previous.name);
+            }
+            changed.epoch += 1;
+            publish_update(&registry, changed, Some(&previous)).await?
+        }
+        GroupCommand::Remove { group, member } => {
+            let previous = existing(group)?;
+            let mut changed = previous.clone();
+            if !changed.remove_member(&member_key(member)?) {
+                bail!("'{}' is not a member of '{}'", member, previous.name);
+            }
+            changed.epoch += 1;
+            publish_update(&registry, changed, Some(&previous)).await?
+        }
+        GroupCommand::Disappearing { group, timer, off } => {
+            let previous = existing(group)?;
+            let mut changed = previous.clone();
+            changed.disappearing = if *off { None } else { *timer };
+            if changed.disappearing == previous.disappearing {
+                bail!("'{}' already uses this timer", previous.name);
+            }
+            changed.epoch += 1;
+            publish_update(&registry, changed, Some(&previous)).await?
+        }
+        GroupCommand::Send { group, message } => {
+            let group = existing(group)?;
+            let report = send_group(&registry, &group, message).await?;
+            let state = if report.delivered.is_empty() { DeliveryState::Failed } else { DeliveryState::Delivered };
+            warn_history(open_history(registry.keys()).and_then(|history| history.record_group_sent(&group, message, state)));
+            report
+        }
+        GroupCommand::List => unreachable!(),
+    };
+    registry.close_all().await;
+    print_fan_out(&report);
+    Ok(())
+}
+
+fn print_fan_out(report: &FanOut) {
+    for contact in &report.delivered {
+        println!("    {}: delivered", contact.name);
+    }
+    for (contact, reason) in &report.failed {
+        println!("    {}: not delivered ({})", contact.name, reason);
+    }
+    for (contact, reason) in &report.queued {
+        println!("    {}: not delivered ({}), queued for retry", contact.name, reason);
+    }
+    for key in &report.unknown {
+        println!("    {}: not a contact", key);
+    }
+}
+
+fn list_groups() -> anyhow::Result<()> {
+    let groups = get_groups()?;
+    let contacts = get_contacts()?;
+    let own = fetch_local_keypair()?.public_key().ed25519_key().to_string();
+    println!("Groups:");
+    if groups.is_empty() {
+        println!("    No groups found");
+    }
+    for group in groups {
+        println!("    {} ({}):", group.name, group.id);
+        if let Some(timer) = group.disappearing {
+            println!("        disappearing after: {:?}", Duration::from_secs(timer as u64));
+        }
+        for member in &group.members {
+            let name = match contacts.iter().find(|c| c.public_key().ed25519_key() == member) {
+                _ if *member == own => "you".to_string(),
+                Some(contact) => contact.name.clone(),
+                None => member.clone(),
+            };
+            let admin = if group.is_admin(member) { " (admin)" } else { "" };
+            println!("        {}{}", name, admin);
+        }
+    }
+    Ok(())
+}
+
 fn output_fingerprint() -> anyhow::Result<()> {
     let current = fetch_local_keypair()?;
     let fingerprint = current.fingerprint_ed();
@@ -81,11 +591,22 @@ fn list_contacts() -> anyhow::Result<()> {
     let contacts = get_contacts()?;
     println!("Contacts:");
     if contacts.is_empty() {
-        return Ok(println!("    No contacts found"));
+        println!("    No contacts found");
+        return Ok(());
     }
     for contact in contacts {
         println!("    {}:", contact.name);
         println!("        fingerprint: {}", contact.fingerprint_ed());
+        println!("        padding: {}", contact.padding);
+        if let Some(relay) = &contact.relay {
+            println!("        relay: {}", relay);
+        }
+        if contact.prekeys.is_some() {
+            println!("        prekeys: from contact card");
+        }
+        if let Some(timer) = contact.disappearing {
+            println!("        disappearing after: {:?}", Duration::from_secs(timer as u64));
+        }
     }
     Ok(())
 }
\ No newline at end of file
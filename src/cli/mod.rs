@@ -1,2 +1,3 @@
 pub mod parser;
-pub mod execute;
\ No newline at end of file
+pub mod execute;
+pub mod chat;
\ No newline at end of file
@@ -1,4 +1,7 @@
+use std::path::PathBuf;
 use clap::{Parser, Subcommand};
+use airoi_core::message::history::Direction;
+use airoi_core::message::padding::PaddingPolicy;
 
 #[derive(Parser, Debug, Clone)]
 #[clap(
@@ -9,6 +12,17 @@ use clap::{Parser, Subcommand};
 pub struct Cli {
     #[clap(subcommand)]
     pub command: AiroiCommand,
+    /// How to reach contacts: tor, tcp (address host:port) or unix (address is a socket path)
+    #[clap(long, global = true, value_enum, default_value_t = Carrier::Tor)]
+    pub transport: Carrier,
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+pub enum Carrier {
+    Tor,
+    Tcp,
+    #[cfg(unix)]
+    Unix,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -26,6 +40,24 @@ pub enum AiroiCommand {
         /// Address of the contact
         address: String,
     },
+    /// Print a contact card with your key, address and prekeys for others to import
+    ExportCard {
+        /// Name others see you as
+        name: String,
+        /// Address others reach you at
+        address: String,
+        /// Relay you fetch offline messages from
+        #[clap(long)]
+        relay: Option<String>,
+    },
+    /// Add someone to your contacts from their contact card
+    ImportCard {
+        /// The card, as printed by `export-card`
+        card: String,
+        /// Name of the contact (defaults to the name on the card)
+        #[clap(long)]
+        name: Option<String>,
+    },
     /// Remove someone from your contacts
     RemoveContact {
         /// Name of the contact
@@ -33,10 +65,253 @@ pub enum AiroiCommand {
     },
     /// List all contacts
     ListContacts,
+    /// Choose how messages to a contact are padded to hide their length
+    SetPadding {
+        /// Name of the contact
+        name: String,
+        /// none, buckets (256/1024/4096/16384 bytes) or padme
+        policy: PaddingPolicy,
+    },
+    /// Send constant-rate cover traffic on sessions with a contact to hide when messages are sent.
+    /// The contact is asked to send cover traffic back at the same rate.
+    SetCover {
+        /// Name of the contact
+        name: String,
+        /// Turn cover traffic off again
+        #[clap(long)]
+        off: bool,
+        /// Size in bytes of every frame
+        #[clap(long, default_value_t = airoi_core::message::cover::DEFAULT_COVER_FRAME_SIZE)]
+        frame_size: usize,
+        /// Bytes per second the session may use
+        #[clap(long, default_value_t = airoi_core::message::cover::DEFAULT_COVER_BANDWIDTH)]
+        bandwidth: u64,
+    },
+    /// Leave messages for a contact at a relay while they are offline
+    SetRelay {
+        /// Name of the contact
+        name: String,
+        /// Address of the relay the contact fetches from
+        #[clap(required_unless_present = "off")]
+        address: Option<String>,
+        /// Stop using a relay for this contact
+        #[clap(long, conflicts_with = "address")]
+        off: bool,
+    },
+    /// Make messages to a contact disappear after a while, on both sides
+    SetDisappearing {
+        /// Name of the contact
+        name: String,
+        /// How long messages are kept: seconds, or a number with s, m, h or d
+        #[clap(value_parser = parse_timer, required_unless_present = "off")]
+        timer: Option<u32>,
+        /// Keep messages to this contact again
+        #[clap(long, conflicts_with = "timer")]
+        off: bool,
+    },
+    /// Delete messages from your history once they are older than a number of days
+    SetRetention {
+        /// Days messages are kept
+        #[clap(required_unless_present = "off")]
+        days: Option<u32>,
+        /// Keep all messages
+        #[clap(long, conflicts_with = "days")]
+        off: bool,
+    },
+    /// Accept the changed key of a contact. Only do this after verifying the new fingerprint out of band
+    RepinContact {
+        /// Name of the contact
+        name: String,
+    },
     
-    Receive { addr: Option<String> },
+    Receive {
+        addr: Option<String>,
+        /// Maximum size in bytes of a single reassembled message
+        #[clap(long, default_value_t = airoi_core::message::fragment::DEFAULT_MAX_MESSAGE_SIZE)]
+        max_message_size: usize,
+        /// Directory for received files (defaults to `downloads` in the airoi directory)
+        #[clap(long)]
+        downloads_dir: Option<PathBuf>,
+        /// Seconds without hearing from a contact before their session is closed
+        #[clap(long, default_value_t = airoi_core::message::receive::DEFAULT_IDLE_TIMEOUT.as_secs())]
+        idle_timeout: u64,
+        /// Maximum number of connections open at the same time
+        #[clap(long, default_value_t = airoi_core::message::limits::DEFAULT_MAX_CONNECTIONS)]
+        max_connections: usize,
+        /// Seconds a connection gets to complete the handshake
+        #[clap(long, default_value_t = airoi_core::message::limits::DEFAULT_HANDSHAKE_TIMEOUT.as_secs())]
+        handshake_timeout: u64,
+        /// Largest transport frame in bytes accepted from a contact. Contacts send frames of up to
+        /// the default, so a lower limit refuses their longer messages
+        #[clap(long, value_parser = parse_frame_size, default_value_t = airoi_core::message::fragment::NOISE_MAX_MSG_LEN)]
+        max_frame_size: usize,
+    },
     
     Send { name: String, message: String },
+    /// Send a file to a contact
+    SendFile {
+        /// Name of the contact
+        name: String,
+        /// Path of the file to send
+        path: PathBuf,
+    },
+    /// Chat with a contact in real time
+    Chat {
+        /// Name of the contact
+        name: String,
+        /// Address to accept the contact's connections on, must differ from a running receive
+        #[clap(long)]
+        addr: Option<String>,
+    },
+    /// Show the messages exchanged with a contact or in a group
+    History {
+        /// Name of the contact, or name or id of the group
+        name: String,
+        /// Only messages sent since this date (YYYY-MM-DD) or time (RFC 3339)
+        #[clap(long)]
+        since: Option<String>,
+        /// Number of most recent messages to show
+        #[clap(long, default_value_t = 50)]
+        limit: usize,
+    },
+    /// Search the message history
+    Search {
+        /// Words the messages have to contain; a word also matches longer words it starts
+        query: String,
+        /// Only messages with this contact, or in this group (name or id)
+        #[clap(long)]
+        contact: Option<String>,
+        /// Only messages sent since this date (YYYY-MM-DD) or time (RFC 3339)
+        #[clap(long)]
+        since: Option<String>,
+        /// Only messages sent before this date (YYYY-MM-DD) or time (RFC 3339)
+        #[clap(long)]
+        until: Option<String>,
+        /// Only sent or only received messages
+        #[clap(long)]
+        direction: Option<Direction>,
+        /// Number of most recent matches to show
+        #[clap(long, default_value_t = 50)]
+        limit: usize,
+        /// Rebuild the search index from the history first
+        #[clap(long)]
+        rebuild: bool,
+    },
+    /// Delete a message from the history, as shown by `search`
+    DeleteMessage {
+        /// Id of the message
+        id: String,
+    },
+    /// Fetch messages left for you at a relay
+    Fetch {
+        /// Address of the relay
+        relay: String,
+    },
+    /// Upload fresh prekeys to a relay, so contacts can start a conversation while you are offline
+    PublishPrekeys {
+        /// Address of the relay
+        relay: String,
+        /// Number of one-time prekeys to upload
+        #[clap(long, default_value_t = airoi_core::keys::prekeys::DEFAULT_ONE_TIME_PREKEYS)]
+        count: usize,
+    },
+    /// Group conversations, delivered to every member that is one of your contacts
+    Group {
+        #[clap(subcommand)]
+        command: GroupCommand,
+    },
+    /// Messages waiting for unreachable contacts. They are retried while `receive` or `chat` runs
+    Outbox {
+        #[clap(subcommand)]
+        command: OutboxCommand,
+    },
     #[clap(alias = "whoami")]
     WhoAmI,
 }
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum GroupCommand {
+    /// Create a group you administer and tell its members
+    Create {
+        /// Name of the group
+        name: String,
+        /// Names of the contacts to add
+        members: Vec<String>,
+    },
+    /// Add a contact to a group you administer
+    Add {
+        /// Name or id of the group
+        group: String,
+        /// Name of the contact
+        member: String,
+    },
+    /// Remove a contact from a group you administer
+    Remove {
+        /// Name or id of the group
+        group: String,
+        /// Name of the contact
+        member: String,
+    },
+    /// Make messages in a group you administer disappear after a while
+    Disappearing {
+        /// Name or id of the group
+        group: String,
+        /// How long messages are kept: seconds, or a number with s, m, h or d
+        #[clap(value_parser = parse_timer, required_unless_present = "off")]
+        timer: Option<u32>,
+        /// Keep messages in the group again
+        #[clap(long, conflicts_with = "timer")]
+        off: bool,
+    },
+    /// Send a message to every member of a group
+    Send {
+        /// Name or id of the group
+        group: String,
+        message: String,
+    },
+    /// List your groups
+    List,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum OutboxCommand {
+    /// List queued messages
+    List,
+    /// Try to deliver queued messages now, regardless of their backoff
+    Retry {
+        /// Id of a single message to retry (all if omitted)
+        id: Option<String>,
+    },
+    /// Drop a queued message without sending it
+    Cancel {
+        /// Id of the message
+        id: String,
+    },
+}
+
+/// Seconds of a timer such as `90`, `30s`, `5m`, `2h` or `7d`
+fn parse_timer(timer: &str) -> Result<u32, String> {
+    let (number, unit) = match timer.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => timer.split_at(index),
+        None => (timer, "s"),
+    };
+    let factor = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        other => return Err(format!("unknown unit '{}', expected s, m, h or d", other)),
+    };
+    number.parse::<u32>().ok()
+        .and_then(|n| n.checked_mul(factor))
+        .filter(|seconds| *seconds > 0)
+        .ok_or_else(|| format!("'{}' is not a positive timer", timer))
+}
+
+/// A frame size no larger than a Noise transport message
+fn parse_frame_size(size: &str) -> Result<usize, String> {
+    let max = airoi_core::message::fragment::NOISE_MAX_MSG_LEN;
+    size.parse::<usize>().ok()
+        .filter(|size| (1..=max).contains(size))
+        .ok_or_else(|| format!("'{}' is not a frame size between 1 and {}", size, max))
+}
@@ -43,6 +43,42 @@ pub enum AiroiError {
     
     #[error("Onion Error: {0}")]
     Onion(String),
+
+    #[error("Protocol Error: {0}")]
+    Protocol(String),
+
+    #[error("Unsupported protocol version: {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("Message too large: {size} bytes exceeds maximum of {max} bytes")]
+    MessageTooLarge { size: usize, max: usize },
+
+    #[error("Cover traffic queue full: {queued} frames waiting, {max} fit before the receipt deadline")]
+    CoverQueueFull { queued: usize, max: usize },
+
+    #[error("Session closed")]
+    SessionClosed,
+
+    #[error("Identity Error: {0}")]
+    Identity(String),
+
+    #[error("Frame too large: {size} bytes exceeds maximum of {max} bytes")]
+    FrameTooLarge { size: usize, max: usize },
+
+    #[error("Too many connections: limit of {max} reached")]
+    TooManyConnections { max: usize },
+
+    #[error("Handshake timed out after {0:?}")]
+    HandshakeTimeout(std::time::Duration),
+
+    #[error("Rate limited: {peer} opened more than {max} connections within {window:?}")]
+    RateLimited { peer: String, max: usize, window: std::time::Duration },
+
+    #[error("Relay Error: {0}")]
+    Relay(String),
+
+    #[error("Key Mismatch: {contact} presented key {presented}, pinned key is {pinned}")]
+    KeyMismatch { contact: String, pinned: String, presented: String },
 }
 
 pub type Result<T> = std::result::Result<T, AiroiError>;
\ No newline at end of file
@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
-use crate::error::Result;
+use crate::error::{AiroiError, Result};
 use crate::keys::Key;
 use crate::keys::key_gen::{ed25519_pk_to_x25519};
+use crate::keys::prekeys::PrekeyBundle;
+use crate::message::cover::CoverTraffic;
+use crate::message::padding::PaddingPolicy;
 use crate::util::get_airoi_dir;
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -10,6 +13,24 @@ pub struct Contact {
     pub public_key: Key,
     pub address: String,
     pub added_at: String,
+    /// Key presented by the contact that did not match the pinned one, kept until re-pinned
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pending_key: Option<Key>,
+    /// How messages to this contact are padded
+    #[serde(default)]
+    pub padding: PaddingPolicy,
+    /// Constant-rate cover traffic on sessions with this contact, off if `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cover: Option<CoverTraffic>,
+    /// Relay where the contact picks up messages sent while they are offline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+    /// Prekey bundle from the contact's card, for a first message while they are offline
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prekeys: Option<PrekeyBundle>,
+    /// Seconds after which messages to this contact disappear, kept if `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disappearing: Option<u32>,
 }
 
 
@@ -40,17 +61,83 @@ pub fn add_contact(contact: Contact) -> Result<()> {
     Ok(())
 }
 
+/// Replaces the stored contact with the same X25519 fingerprint
+pub fn update_contact(contact: &Contact) -> Result<()> {
+    let mut contacts = get_contacts()?;
+    if let Some(existing) = contacts.iter_mut().find(|c| c.fingerprint_x() == contact.fingerprint_x()) {
+        *existing = contact.clone();
+    }
+    store_contacts(contacts)?;
+    Ok(())
+}
+
+/// Replaces a contact's pinned key with the pending key it presented.
+/// Returns `false` if the contact does not exist or has no pending key.
+pub fn repin_contact(name: &str) -> Result<bool> {
+    let mut contacts = get_contacts()?;
+    let Some(contact) = contacts.iter_mut().find(|c| c.name == name) else {
+        return Ok(false);
+    };
+    let Some(key) = contact.pending_key.take() else {
+        return Ok(false);
+    };
+    contact.public_key = key;
+    store_contacts(contacts)?;
+    Ok(true)
+}
+
+/// Sets how messages to a contact are padded. Returns `false` if the contact does not exist.
+pub fn set_padding(name: &str, padding: PaddingPolicy) -> Result<bool> {
+    let mut contacts = get_contacts()?;
+    let Some(contact) = contacts.iter_mut().find(|c| c.name == name) else {
+        return Ok(false);
+    };
+    contact.padding = padding;
+    store_contacts(contacts)?;
+    Ok(true)
+}
+
+/// Turns cover traffic for a contact on (`Some`) or off. Returns `false` if the contact does not exist.
+pub fn set_cover(name: &str, cover: Option<CoverTraffic>) -> Result<bool> {
+    if let Some(cover) = &cover {
+        cover.validate()?;
+    }
+    let mut contacts = get_contacts()?;
+    let Some(contact) = contacts.iter_mut().find(|c| c.name == name) else {
+        return Ok(false);
+    };
+    contact.cover = cover;
+    store_contacts(contacts)?;
+    Ok(true)
+}
+
+/// Sets the relay a contact fetches offline messages from, or none. Returns `false` if the contact does not exist.
+pub fn set_relay(name: &str, relay: Option<String>) -> Result<bool> {
+    let mut contacts = get_contacts()?;
+    let Some(contact) = contacts.iter_mut().find(|c| c.name == name) else {
+        return Ok(false);
+    };
+    contact.relay = relay;
+    store_contacts(contacts)?;
+    Ok(true)
+}
+
+pub fn set_disappearing(name: &str, timer: Option<u32>) -> Result<bool> {
+    let mut contacts = get_contacts()?;
+    let Some(contact) = contacts.iter_mut().find(|c| c.name == name) else {
+        return Ok(false);
+    };
+    contact.disappearing = timer;
+    store_contacts(contacts)?;
+    Ok(true)
+}
+
 pub fn remove_contact(name: &str) -> Result<bool> {
     let mut contacts = get_contacts()?;
     let mut found = false;
-    let mut index = 0;
-    for contact in contacts.iter() {
-        if contact.name == name {
-            contacts.remove(index);
-            found = true;
-            break;
-        }
-        index += 1;
+    if let Some(index) = contacts.iter().position(|c| c.name == name) {
+        contacts.remove(index);
+        found = true;
     }
     store_contacts(contacts)?;
     Ok(found)
@@ -65,6 +152,12 @@ impl Contact {
             public_key,
             address: address.to_string(),
             added_at: chrono::Utc::now().to_rfc3339(),
+            pending_key: None,
+            padding: PaddingPolicy::default(),
+            cover: None,
+            relay: None,
+            prekeys: None,
+            disappearing: None,
         }
     }
     pub fn new_tofu(name: String, raw_remote_static: Vec<u8>, address: &str) -> Contact {
@@ -74,8 +167,27 @@ impl Contact {
             public_key,
             address: address.to_string(),
             added_at: chrono::Utc::now().to_rfc3339(),
+            pending_key: None,
+            padding: PaddingPolicy::default(),
+            cover: None,
+            relay: None,
+            prekeys: None,
+            disappearing: None,
         }
     }
+    /// Checks an Ed25519 key proven during the handshake against this contact. Contacts added
+    /// by TOFU have no Ed25519 key yet; they take the proven one. Returns whether the contact changed.
+    pub fn bind_identity(&mut self, raw_ed_public_key: &[u8]) -> Result<bool> {
+        if self.public_key.ed25519_key_raw().is_empty() {
+            let raw_x_public_key = self.public_key.x25519_key_raw().to_vec();
+            self.public_key = Key::new(raw_ed_public_key.to_vec(), raw_x_public_key);
+            return Ok(true);
+        }
+        if self.public_key.ed25519_key_raw() != raw_ed_public_key {
+            return Err(AiroiError::Identity(format!("{} presented a different ed25519 key", self.name)));
+        }
+        Ok(false)
+    }
     pub fn public_key(&self) -> &Key {
         &self.public_key
     }
@@ -91,4 +203,7 @@ impl Contact {
     pub fn fingerprint_x(&self) -> &str {
         self.public_key.fingerprint_x()
     }
+    pub fn pending_key(&self) -> Option<&Key> {
+        self.pending_key.as_ref()
+    }
 }
\ No newline at end of file
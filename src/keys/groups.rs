@@ -0,0 +1,122 @@
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::keys::contacts::Contact;
+use crate::util::get_airoi_dir;
+
+/// A group conversation. Members and admins are Ed25519 keys (base58), including our own.
+/// Only the admin may change the membership or the timer, and every change raises `epoch`. There
+/// is exactly one admin, the creator, so changes never race each other.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Group {
+    /// Base58 of 16 random bytes, chosen by the creator
+    pub id: String,
+    pub name: String,
+    pub members: Vec<String>,
+    pub admins: Vec<String>,
+    pub epoch: u64,
+    /// Seconds after which messages in the group disappear, kept if `None`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub disappearing: Option<u32>,
+}
+
+impl Group {
+    /// A new group administered by `creator`, with the creator as a member
+    pub fn new(name: &str, creator: &str, members: Vec<String>) -> Group {
+        let mut group = Group {
+            id: bs58::encode(rand::random::<[u8; 16]>()).into_string(),
+            name: name.to_string(),
+            members: vec![creator.to_string()],
+            admins: vec![creator.to_string()],
+            epoch: 1,
+            disappearing: None,
+        };
+        for member in members {
+            group.add_member(&member);
+        }
+        group
+    }
+
+    pub fn is_member(&self, key: &str) -> bool {
+        self.members.iter().any(|m| m == key)
+    }
+
+    pub fn is_admin(&self, key: &str) -> bool {
+        self.admins.iter().any(|a| a == key)
+    }
+
+    /// Returns `false` if the key already was a member
+    pub fn add_member(&mut self, key: &str) -> bool {
+        if self.is_member(key) {
+            return false;
+        }
+        self.members.push(key.to_string());
+        true
+    }
+
+    /// Removes a member, and their admin rights. Returns `false` if the key was no member.
+    pub fn remove_member(&mut self, key: &str) -> bool {
+        let before = self.members.len();
+        self.members.retain(|m| m != key);
+        self.admins.retain(|a| a != key);
+        self.members.len() != before
+    }
+
+    /// Contacts of the members other than `own`, and the members that are not our contacts
+    pub fn recipients<'a>(&'a self, contacts: &[Contact], own: &str) -> (Vec<Contact>, Vec<&'a str>) {
+        let mut found = vec![];
+        let mut unknown = vec![];
+        for member in self.members.iter().filter(|m| *m != own) {
+            match contacts.iter().find(|c| c.public_key().ed25519_key() == member) {
+                Some(contact) => found.push(contact.clone()),
+                None => unknown.push(member.as_str()),
+            }
+        }
+        (found, unknown)
+    }
+}
+
+pub fn get_groups() -> Result<Vec<Group>> {
+    let path = get_airoi_dir().join("groups.json");
+    if !path.exists() {
+        return Ok(vec![]);
+    }
+    let groups = serde_json::from_str::<Vec<Group>>(
+        &std::fs::read_to_string(&path)?
+    )?;
+    Ok(groups)
+}
+
+pub fn store_groups(groups: Vec<Group>) -> Result<()> {
+    let path = get_airoi_dir().join("groups.json");
+    let json = serde_json::to_string_pretty(&groups)?;
+    std::fs::write(&path, json)?;
+    Ok(())
+}
+
+/// The group with this id, or else the first one with this name
+pub fn find_group(name_or_id: &str) -> Result<Option<Group>> {
+    let groups = get_groups()?;
+    let by_id = groups.iter().position(|g| g.id == name_or_id);
+    let index = by_id.or_else(|| groups.iter().position(|g| g.name == name_or_id));
+    Ok(index.map(|i| groups[i].clone()))
+}
+
+/// Stores the group, replacing the one with the same id
+pub fn update_group(group: &Group) -> Result<()> {
+    let mut groups = get_groups()?;
+    match groups.iter_mut().find(|g| g.id == group.id) {
+        Some(existing) => *existing = group.clone(),
+        None => groups.push(group.clone()),
+    }
+    store_groups(groups)
+}
+
+/// Returns `false` if there is no group with this id
+pub fn remove_group(id: &str) -> Result<bool> {
+    let mut groups = get_groups()?;
+    let before = groups.len();
+    groups.retain(|g| g.id != id);
+    let found = groups.len() != before;
+    store_groups(groups)?;
+    Ok(found)
+}
@@ -1,11 +1,12 @@
 use chrono::Utc;
-use ed25519_dalek::{SigningKey, VerifyingKey};
+use ed25519_dalek::{Signer, SigningKey, VerifyingKey};
 use rand::rngs::OsRng;
 use rand::{TryRngCore};
 use sha2::{Digest, Sha512};
 use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
 use crate::keys::{Key, KeyPair};
-use crate::error::Result;
+use crate::keys::prekeys::{signed_prekey_payload, OneTimePrekey, SignedPrekey};
+use crate::error::{AiroiError, Result};
 
 pub fn generate_key_pair() -> Result<KeyPair> {
     let mut seed = [0u8; 32];
@@ -27,9 +28,30 @@ pub fn generate_key_pair() -> Result<KeyPair> {
     })
 }
 
+/// X25519 prekey signed by the identity key, returned with its secret
+pub fn generate_signed_prekey(keys: &KeyPair, id: u32) -> Result<(SignedPrekey, [u8; 32])> {
+    let seed: [u8; 32] = keys.private_key().ed25519_key_raw().try_into()
+        .map_err(|_| AiroiError::Identity("local ed25519 key is malformed".to_string()))?;
+    let (key, secret) = generate_x25519()?;
+    let signature = SigningKey::from_bytes(&seed).sign(&signed_prekey_payload(id, &key));
+    Ok((SignedPrekey { id, key, signature: signature.to_bytes().to_vec() }, secret))
+}
+
+/// Unsigned X25519 prekey for a single first contact, returned with its secret
+pub fn generate_one_time_prekey(id: u32) -> Result<(OneTimePrekey, [u8; 32])> {
+    let (key, secret) = generate_x25519()?;
+    Ok((OneTimePrekey { id, key }, secret))
+}
+
+fn generate_x25519() -> Result<([u8; 32], [u8; 32])> {
+    let mut secret = [0u8; 32];
+    OsRng.try_fill_bytes(&mut secret)?;
+    Ok((x25519(secret, X25519_BASEPOINT_BYTES), secret))
+}
+
 /// Ed25519 Secret -> X25519 Secret
 pub fn ed25519_sk_to_x25519(ed_bytes: &[u8]) -> [u8; 32] {
-    let hash = Sha512::digest(&ed_bytes);
+    let hash = Sha512::digest(ed_bytes);
     let mut x_sk = [0u8; 32];
     x_sk.copy_from_slice(&hash[0..32]);
 
@@ -41,14 +63,19 @@ pub fn ed25519_sk_to_x25519(ed_bytes: &[u8]) -> [u8; 32] {
 
 /// Ed25519 Public -> X25519 Public
 pub fn ed25519_pk_to_x25519(ed_pk: &[u8]) -> [u8; 32] {
+    try_ed25519_pk_to_x25519(ed_pk).expect("invalid ed pk")
+}
+
+/// Ed25519 Public -> X25519 Public, `None` if the bytes are not a valid Ed25519 point
+pub fn try_ed25519_pk_to_x25519(ed_pk: &[u8]) -> Option<[u8; 32]> {
     use curve25519_dalek::edwards::CompressedEdwardsY;
     use curve25519_dalek::montgomery::MontgomeryPoint;
 
-    let ed_pk: [u8; 32] = ed_pk.try_into().expect("invalid ed pk");
+    let ed_pk: [u8; 32] = ed_pk.try_into().ok()?;
     let compressed = CompressedEdwardsY(ed_pk);
-    let ed_point = compressed.decompress().expect("invalid ed point");
+    let ed_point = compressed.decompress()?;
     let mont_point: MontgomeryPoint = ed_point.to_montgomery();
-    mont_point.to_bytes()
+    Some(mont_point.to_bytes())
 }
 
 
@@ -57,8 +84,7 @@ pub fn get_fingerprint(key_bytes: &[u8]) -> String {
     hasher.update(key_bytes);
 
     let finger_print = hasher.finalize();
-    let fingerprint_bs58 = bs58::encode(finger_print).into_string();
-    fingerprint_bs58
+    bs58::encode(finger_print).into_string()
 }
 
 impl KeyPair {
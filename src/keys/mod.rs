@@ -3,6 +3,8 @@ use crate::keys::key_gen::get_fingerprint;
 
 pub mod key_gen;
 pub mod contacts;
+pub mod groups;
+pub mod prekeys;
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct KeyPair {
@@ -74,4 +76,12 @@ impl Key {
     pub fn fingerprint_x(&self) -> &str {
         &self.fingerprint_x
     }
+    /// Ed25519 fingerprint, or the X25519 one for keys without a proven Ed25519 identity
+    pub fn display_fingerprint(&self) -> &str {
+        if self.fingerprint_ed.is_empty() {
+            &self.fingerprint_x
+        } else {
+            &self.fingerprint_ed
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,442 @@
+//! Prekey bundles and X3DH, so the first message to a contact can be encrypted while they are
+//! offline and still get forward secrecy.
+//!
+//! A bundle holds a signed prekey (an X25519 key signed by the identity's Ed25519 key and
+//! rotated every `SIGNED_PREKEY_LIFETIME`) and one-time prekeys, which are used for a single
+//! first contact and then deleted. Bundles are shared in contact cards, which carry no one-time
+//! prekeys since a card may reach many people, or uploaded to a relay, which hands out each
+//! one-time prekey once.
+//!
+//! The initiator combines its identity and a fresh ephemeral key with the recipient's identity,
+//! signed prekey and one-time prekey (if any) into the secret the Double Ratchet starts from,
+//! with the signed prekey as the recipient's first ratchet key. The recipient learns the
+//! ephemeral key and prekey ids from the first ratchet message.
+use base64::Engine;
+use base64::engine::general_purpose;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::path::PathBuf;
+use std::time::Duration;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::Zeroize;
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::contacts::Contact;
+use crate::keys::key_gen::{generate_one_time_prekey, generate_signed_prekey, try_ed25519_pk_to_x25519};
+use crate::storage::sealed::{lock_sealed, read_sealed, storage_key, write_sealed};
+use crate::util::get_airoi_dir;
+
+pub const SIGNED_PREKEY_LIFETIME: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// One-time prekeys uploaded to a relay at once
+pub const DEFAULT_ONE_TIME_PREKEYS: usize = 100;
+/// Signed prekeys kept after rotation, for first messages still on their way. The signed prekey
+/// of the last exported contact card is kept on top of these.
+const RETIRED_SIGNED_PREKEYS: usize = 2;
+/// One-time prekeys kept locally at most; the oldest unused ones are dropped first
+const MAX_ONE_TIME_PREKEYS: usize = 1000;
+const PREKEY_CONTEXT: &[u8] = b"airoi prekey v1";
+const X3DH_INFO: &[u8] = b"airoi x3dh v1";
+/// Ephemeral key, signed prekey id and optional one-time prekey id
+pub(crate) const PREKEY_MESSAGE_LEN: usize = 32 + 4 + 1 + 4;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedPrekey {
+    pub id: u32,
+    pub key: [u8; 32],
+    /// Ed25519 signature of the identity over `PREKEY_CONTEXT || id || key`
+    pub signature: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OneTimePrekey {
+    pub id: u32,
+    pub key: [u8; 32],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PrekeyBundle {
+    /// Ed25519 key of the identity that signed the prekey
+    pub identity: Vec<u8>,
+    pub signed_prekey: SignedPrekey,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+pub(crate) fn signed_prekey_payload(id: u32, key: &[u8; 32]) -> Vec<u8> {
+    [PREKEY_CONTEXT, &id.to_be_bytes(), key].concat()
+}
+
+impl PrekeyBundle {
+    /// Checks the signature on the signed prekey
+    pub fn verify(&self) -> Result<()> {
+        let invalid = || AiroiError::Identity("prekey bundle has an invalid signature".to_string());
+        let identity: [u8; 32] = self.identity.as_slice().try_into().map_err(|_| invalid())?;
+        let verifying_key = VerifyingKey::from_bytes(&identity).map_err(|_| invalid())?;
+        let signature = Signature::from_slice(&self.signed_prekey.signature).map_err(|_| invalid())?;
+        let payload = signed_prekey_payload(self.signed_prekey.id, &self.signed_prekey.key);
+        verifying_key.verify(&payload, &signature).map_err(|_| invalid())
+    }
+
+    /// Checks the signature and that the bundle belongs to the contact
+    pub fn verify_for(&self, contact: &Contact) -> Result<()> {
+        if self.identity != contact.public_key().ed25519_key_raw() {
+            return Err(AiroiError::Identity(format!("prekey bundle does not belong to {}", contact.name)));
+        }
+        self.verify()
+    }
+}
+
+/// What a contact needs to reach us: identity, address and prekeys
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContactCard {
+    pub name: String,
+    pub address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub relay: Option<String>,
+    pub prekeys: PrekeyBundle,
+}
+
+impl ContactCard {
+    pub fn encode(&self) -> Result<String> {
+        Ok(general_purpose::STANDARD.encode(serde_json::to_vec(self)?))
+    }
+
+    /// Decodes a card and checks its prekey signature
+    pub fn decode(card: &str) -> Result<ContactCard> {
+        let card: ContactCard = serde_json::from_slice(&general_purpose::STANDARD.decode(card.trim())?)?;
+        card.prekeys.verify()?;
+        Ok(card)
+    }
+
+    pub fn into_contact(self, name: Option<String>) -> Contact {
+        let mut contact = Contact::new(name.unwrap_or(self.name), self.prekeys.identity.clone(), &self.address);
+        contact.relay = self.relay;
+        contact.prekeys = Some(self.prekeys);
+        contact
+    }
+}
+
+/// Sent with the first ratchet messages of an X3DH start
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct PrekeyMessage {
+    pub ephemeral: [u8; 32],
+    pub signed_prekey: u32,
+    pub one_time_prekey: Option<u32>,
+}
+
+impl PrekeyMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(PREKEY_MESSAGE_LEN);
+        out.extend_from_slice(&self.ephemeral);
+        out.extend_from_slice(&self.signed_prekey.to_be_bytes());
+        out.push(self.one_time_prekey.is_some() as u8);
+        out.extend_from_slice(&self.one_time_prekey.unwrap_or(0).to_be_bytes());
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<PrekeyMessage> {
+        if data.len() < PREKEY_MESSAGE_LEN {
+            return Err(AiroiError::Protocol("truncated prekey message".to_string()));
+        }
+        let one_time_prekey = u32::from_be_bytes(data[37..41].try_into().unwrap());
+        Ok(PrekeyMessage {
+            ephemeral: data[..32].try_into().unwrap(),
+            signed_prekey: u32::from_be_bytes(data[32..36].try_into().unwrap()),
+            one_time_prekey: (data[36] == 1).then_some(one_time_prekey),
+        })
+    }
+}
+
+fn kdf_x3dh(mut dh: Vec<u8>) -> [u8; 32] {
+    // 32 bytes of 0xFF first, as X3DH prescribes for X25519
+    let mut ikm = vec![0xFF; 32];
+    ikm.append(&mut dh);
+    let mut shared = [0u8; 32];
+    Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm)
+        .expand(X3DH_INFO, &mut shared)
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    ikm.zeroize();
+    shared
+}
+
+fn own_secret(keys: &KeyPair) -> Result<[u8; 32]> {
+    keys.private_key().x25519_key_raw().try_into()
+        .map_err(|_| AiroiError::Protocol("local x25519 key is malformed".to_string()))
+}
+
+/// Initiator side: verifies the bundle and derives the shared secret. Returns the secret, the
+/// message telling the recipient how to derive it, and the recipient's first ratchet key.
+pub(crate) fn x3dh_initiate(keys: &KeyPair, bundle: &PrekeyBundle) -> Result<([u8; 32], PrekeyMessage, [u8; 32])> {
+    bundle.verify()?;
+    let identity = try_ed25519_pk_to_x25519(&bundle.identity)
+        .ok_or_else(|| AiroiError::Identity("prekey bundle identity is not a valid key".to_string()))?;
+    let signed_prekey = bundle.signed_prekey.key;
+    let one_time_prekey = bundle.one_time_prekeys.first();
+
+    let mut ephemeral = [0u8; 32];
+    rand::TryRngCore::try_fill_bytes(&mut rand::rngs::OsRng, &mut ephemeral)?;
+    let mut dh = [
+        x25519(own_secret(keys)?, signed_prekey),
+        x25519(ephemeral, identity),
+        x25519(ephemeral, signed_prekey),
+    ].concat();
+    if let Some(one_time_prekey) = one_time_prekey {
+        dh.extend_from_slice(&x25519(ephemeral, one_time_prekey.key));
+    }
+    let message = PrekeyMessage {
+        ephemeral: x25519(ephemeral, X25519_BASEPOINT_BYTES),
+        signed_prekey: bundle.signed_prekey.id,
+        one_time_prekey: one_time_prekey.map(|k| k.id),
+    };
+    ephemeral.zeroize();
+    Ok((kdf_x3dh(dh), message, signed_prekey))
+}
+
+/// Recipient side: derives the shared secret from the initiator's static key and message.
+/// Returns the secret and the signed prekey secret, which is our first ratchet key.
+pub(crate) fn x3dh_respond(
+    keys: &KeyPair,
+    prekeys: &PrekeyStore,
+    sender: &[u8; 32],
+    message: &PrekeyMessage,
+) -> Result<([u8; 32], [u8; 32])> {
+    let (signed_prekey, one_time_prekey) = prekeys.secrets(message)?;
+    let mut dh = [
+        x25519(signed_prekey, *sender),
+        x25519(own_secret(keys)?, message.ephemeral),
+        x25519(signed_prekey, message.ephemeral),
+    ].concat();
+    if let Some(mut one_time_prekey) = one_time_prekey {
+        dh.extend_from_slice(&x25519(one_time_prekey, message.ephemeral));
+        one_time_prekey.zeroize();
+    }
+    Ok((kdf_x3dh(dh), signed_prekey))
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct StoredPrekey {
+    id: u32,
+    secret: [u8; 32],
+    public: [u8; 32],
+    /// Set for signed prekeys
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    signature: Vec<u8>,
+    /// Published in the last exported contact card, so kept through rotations
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    in_card: bool,
+    /// Unix milliseconds
+    created_at: i64,
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct PrekeySecrets {
+    /// Current signed prekey last
+    signed: Vec<StoredPrekey>,
+    one_time: Vec<StoredPrekey>,
+    next_id: u32,
+}
+
+impl Drop for PrekeySecrets {
+    fn drop(&mut self) {
+        for prekey in self.signed.iter_mut().chain(self.one_time.iter_mut()) {
+            prekey.secret.zeroize();
+        }
+    }
+}
+
+/// Our prekey secrets, persisted encrypted in `prekeys.json` in the airoi directory
+pub struct PrekeyStore {
+    path: PathBuf,
+    key: [u8; 32],
+}
+
+impl PrekeyStore {
+    pub fn open(keys: &KeyPair) -> PrekeyStore {
+        PrekeyStore::at(get_airoi_dir().join("prekeys.json"), keys)
+    }
+
+    pub fn at(path: PathBuf, keys: &KeyPair) -> PrekeyStore {
+        PrekeyStore { path, key: storage_key(keys, "prekeys") }
+    }
+
+    /// Our bundle with `one_time` fresh one-time prekeys, rotating the signed prekey if it is due
+    pub fn bundle(&self, keys: &KeyPair, one_time: usize) -> Result<PrekeyBundle> {
+        self.bundle_for(keys, one_time, false)
+    }
+
+    /// Our bundle for a contact card. A card may be used long after it was exported, so its
+    /// signed prekey is kept until the next card is exported.
+    pub fn card_bundle(&self, keys: &KeyPair) -> Result<PrekeyBundle> {
+        self.bundle_for(keys, 0, true)
+    }
+
+    fn bundle_for(&self, keys: &KeyPair, one_time: usize, card: bool) -> Result<PrekeyBundle> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut secrets = self.load()?;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let due = secrets.signed.last()
+            .is_none_or(|current| now - current.created_at > SIGNED_PREKEY_LIFETIME.as_millis() as i64);
+        if due {
+            let (signed, secret) = generate_signed_prekey(keys, secrets.next_id)?;
+            secrets.next_id += 1;
+            secrets.signed.push(StoredPrekey { id: signed.id, secret, public: signed.key, signature: signed.signature, in_card: false, created_at: now });
+            let retired = |signed: &[StoredPrekey]| signed[..signed.len() - 1].iter().filter(|p| !p.in_card).count();
+            while retired(&secrets.signed) > RETIRED_SIGNED_PREKEYS {
+                let oldest = secrets.signed.iter().position(|p| !p.in_card).unwrap();
+                secrets.signed.remove(oldest);
+            }
+        }
+        if card {
+            let current = secrets.signed.len() - 1;
+            for (i, prekey) in secrets.signed.iter_mut().enumerate() {
+                prekey.in_card = i == current;
+            }
+        }
+
+        let mut one_time_prekeys = Vec::with_capacity(one_time);
+        for _ in 0..one_time {
+            let (prekey, secret) = generate_one_time_prekey(secrets.next_id)?;
+            secrets.next_id += 1;
+            secrets.one_time.push(StoredPrekey { id: prekey.id, secret, public: prekey.key, signature: vec![], in_card: false, created_at: now });
+            one_time_prekeys.push(prekey);
+        }
+        while secrets.one_time.len() > MAX_ONE_TIME_PREKEYS {
+            secrets.one_time.remove(0);
+        }
+
+        let current = secrets.signed.last().unwrap();
+        let bundle = PrekeyBundle {
+            identity: keys.public_key().ed25519_key_raw().to_vec(),
+            signed_prekey: SignedPrekey { id: current.id, key: current.public, signature: current.signature.clone() },
+            one_time_prekeys,
+        };
+        self.store(&secrets)?;
+        Ok(bundle)
+    }
+
+    /// Deletes a one-time prekey once the first message using it was read
+    pub(crate) fn consume(&self, id: u32) -> Result<()> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut secrets = self.load()?;
+        secrets.one_time.retain(|prekey| prekey.id != id);
+        self.store(&secrets)
+    }
+
+    fn secrets(&self, message: &PrekeyMessage) -> Result<([u8; 32], Option<[u8; 32]>)> {
+        let _guard = lock_sealed(&self.path)?;
+        let secrets = self.load()?;
+        let unknown = |kind: &str, id: u32| AiroiError::Protocol(format!("unknown {} prekey {}", kind, id));
+        let signed = secrets.signed.iter()
+            .find(|prekey| prekey.id == message.signed_prekey)
+            .ok_or_else(|| unknown("signed", message.signed_prekey))?
+            .secret;
+        let one_time = match message.one_time_prekey {
+            Some(id) => Some(secrets.one_time.iter().find(|prekey| prekey.id == id).ok_or_else(|| unknown("one-time", id))?.secret),
+            None => None,
+        };
+        Ok((signed, one_time))
+    }
+
+    fn load(&self) -> Result<PrekeySecrets> {
+        match read_sealed(&self.path, &self.key)? {
+            Some(plain_text) => Ok(serde_json::from_slice(&plain_text)?),
+            None => Ok(PrekeySecrets::default()),
+        }
+    }
+
+    fn store(&self, secrets: &PrekeySecrets) -> Result<()> {
+        write_sealed(&self.path, &self.key, &serde_json::to_vec(secrets)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::key_gen::generate_key_pair;
+
+    fn store(name: &str, keys: &KeyPair) -> PrekeyStore {
+        let path = std::env::temp_dir().join(format!("airoi-prekeys-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        PrekeyStore::at(path, keys)
+    }
+
+    #[test]
+    fn test_x3dh_agrees() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let prekeys = store("agree", &bob);
+
+        for one_time in [0, 1] {
+            let bundle = prekeys.bundle(&bob, one_time).unwrap();
+            let (shared, message, ratchet_key) = x3dh_initiate(&alice, &bundle).unwrap();
+            assert_eq!(message.one_time_prekey.is_some(), one_time == 1);
+            assert_eq!(PrekeyMessage::decode(&message.encode()).unwrap(), message);
+
+            let sender = alice.public_key().x25519_key_raw().try_into().unwrap();
+            let (their_shared, secret) = x3dh_respond(&bob, &prekeys, &sender, &message).unwrap();
+            assert_eq!(shared, their_shared);
+            assert_eq!(x25519(secret, X25519_BASEPOINT_BYTES), ratchet_key);
+            if let Some(id) = message.one_time_prekey {
+                prekeys.consume(id).unwrap();
+                assert!(x3dh_respond(&bob, &prekeys, &sender, &message).is_err());
+            }
+        }
+        let _ = std::fs::remove_file(&prekeys.path);
+    }
+
+    /// Makes the signed prekey due for rotation on the next bundle
+    fn age(prekeys: &PrekeyStore) {
+        let mut secrets = prekeys.load().unwrap();
+        for prekey in &mut secrets.signed {
+            prekey.created_at = 0;
+        }
+        prekeys.store(&secrets).unwrap();
+    }
+
+    #[test]
+    fn test_card_prekey_outlives_rotation() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let prekeys = store("rotation", &bob);
+        let sender = alice.public_key().x25519_key_raw().try_into().unwrap();
+
+        let card = prekeys.card_bundle(&bob).unwrap();
+        for _ in 0..=RETIRED_SIGNED_PREKEYS + 1 {
+            age(&prekeys);
+            prekeys.bundle(&bob, 0).unwrap();
+        }
+        let (_, message, _) = x3dh_initiate(&alice, &card).unwrap();
+        assert!(x3dh_respond(&bob, &prekeys, &sender, &message).is_ok());
+
+        // exporting a new card lets the old card's prekey retire like any other
+        let new_card = prekeys.card_bundle(&bob).unwrap();
+        assert_ne!(new_card.signed_prekey.id, card.signed_prekey.id);
+        for _ in 0..=RETIRED_SIGNED_PREKEYS {
+            age(&prekeys);
+            prekeys.bundle(&bob, 0).unwrap();
+        }
+        assert!(x3dh_respond(&bob, &prekeys, &sender, &message).is_err());
+        let (_, message, _) = x3dh_initiate(&alice, &new_card).unwrap();
+        assert!(x3dh_respond(&bob, &prekeys, &sender, &message).is_ok());
+        let _ = std::fs::remove_file(&prekeys.path);
+    }
+
+    #[test]
+    fn test_card_round_trip_and_forged_bundle() {
+        let bob = generate_key_pair().unwrap();
+        let prekeys = store("card", &bob);
+        let card = ContactCard { name: "bob".to_string(), address: "bob.onion".to_string(), relay: None, prekeys: prekeys.card_bundle(&bob).unwrap() };
+        let decoded = ContactCard::decode(&card.encode().unwrap()).unwrap();
+        let contact = decoded.clone().into_contact(None);
+        assert_eq!(contact.public_key().ed25519_key_raw(), bob.public_key().ed25519_key_raw());
+        assert!(contact.prekeys.as_ref().unwrap().verify_for(&contact).is_ok());
+
+        let mut forged = decoded;
+        forged.prekeys.signed_prekey.key[0] ^= 1;
+        assert!(ContactCard::decode(&forged.encode().unwrap()).is_err());
+        let _ = std::fs::remove_file(&prekeys.path);
+    }
+}
@@ -3,4 +3,6 @@ pub mod keys;
 mod util;
 pub mod message;
 pub mod storage;
-mod tor;
\ No newline at end of file
+pub mod relay;
+mod tor;
+pub mod transport;
\ No newline at end of file
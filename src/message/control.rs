@@ -0,0 +1,92 @@
+//! Bodies of `Control` envelopes. The first byte selects the control type.
+use crate::error::{AiroiError, Result};
+use crate::message::cover::CoverTraffic;
+use crate::message::envelope::{Envelope, MessageKind};
+
+const CONTROL_IDENTITY: u8 = 0;
+const CONTROL_REKEY: u8 = 1;
+const CONTROL_CLOSE: u8 = 2;
+const CONTROL_COVER: u8 = 3;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Control {
+    /// Ed25519 identity key and its signature over the handshake hash
+    Identity { ed25519: [u8; 32], signature: [u8; 64] },
+    /// Everything after this message is encrypted under the next key
+    Rekey,
+    /// The sender ends the session, nothing follows
+    Close,
+    /// The sender sends cover traffic with these settings and asks for the same in return
+    Cover(CoverTraffic),
+}
+
+impl Control {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Control::Identity { ed25519, signature } => {
+                let mut out = vec![CONTROL_IDENTITY];
+                out.extend_from_slice(ed25519);
+                out.extend_from_slice(signature);
+                out
+            }
+            Control::Rekey => vec![CONTROL_REKEY],
+            Control::Close => vec![CONTROL_CLOSE],
+            Control::Cover(cover) => {
+                let mut out = vec![CONTROL_COVER];
+                out.extend_from_slice(&(cover.frame_size as u32).to_be_bytes());
+                out.extend_from_slice(&cover.bandwidth.to_be_bytes());
+                out
+            }
+        }
+    }
+
+    pub fn decode(body: &[u8]) -> Result<Control> {
+        let (kind, data) = body
+            .split_first()
+            .ok_or_else(|| AiroiError::Protocol("empty control message".to_string()))?;
+        match *kind {
+            CONTROL_IDENTITY => {
+                if data.len() != 32 + 64 {
+                    return Err(AiroiError::Protocol("malformed identity message".to_string()));
+                }
+                Ok(Control::Identity {
+                    ed25519: data[..32].try_into().unwrap(),
+                    signature: data[32..].try_into().unwrap(),
+                })
+            }
+            CONTROL_REKEY => Ok(Control::Rekey),
+            CONTROL_CLOSE => Ok(Control::Close),
+            CONTROL_COVER => {
+                if data.len() != 4 + 8 {
+                    return Err(AiroiError::Protocol("malformed cover message".to_string()));
+                }
+                Ok(Control::Cover(CoverTraffic {
+                    frame_size: u32::from_be_bytes(data[..4].try_into().unwrap()) as usize,
+                    bandwidth: u64::from_be_bytes(data[4..].try_into().unwrap()),
+                }))
+            }
+            other => Err(AiroiError::Protocol(format!("unknown control message {}", other))),
+        }
+    }
+
+    pub fn into_envelope(self) -> Envelope {
+        Envelope::new(MessageKind::Control, self.encode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identity_roundtrip() {
+        let control = Control::Identity { ed25519: [1u8; 32], signature: [2u8; 64] };
+        assert_eq!(Control::decode(&control.encode()).unwrap(), control);
+        assert!(Control::decode(&control.encode()[..40]).is_err());
+        assert!(Control::decode(&[]).is_err());
+        assert_eq!(Control::decode(&Control::Rekey.encode()).unwrap(), Control::Rekey);
+        let cover = Control::Cover(CoverTraffic { frame_size: 512, bandwidth: 2048 });
+        assert_eq!(Control::decode(&cover.encode()).unwrap(), cover);
+        assert!(Control::decode(&cover.encode()[..6]).is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Constant-rate cover traffic.
+//!
+//! With cover traffic enabled for a contact, an open session sends one frame of `frame_size`
+//! bytes every tick, whether or not there is anything to say. Outgoing payloads are split into
+//! chunks of exactly that size and queued; each tick sends the next queued chunk, or a dummy
+//! chunk the receiver discards if the queue is empty. An observer sees the same frame size at
+//! the same rate all the time, at the cost of `bandwidth` bytes per second for as long as the
+//! session is open. Large messages are delayed accordingly; the queue only holds what goes out
+//! before `ACK_TIMEOUT`, so a message that would miss its receipt deadline is refused instead.
+//! The contact is asked to send cover traffic with the same settings back (see `session`), so its
+//! receipts do not give away which frames carried data; a contact could also ignore the request.
+use std::collections::VecDeque;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use snow::TransportState;
+use tokio::io::AsyncWrite;
+use crate::error::{AiroiError, Result};
+use crate::message::fragment::{dummy_chunk, fragment_fixed, write_chunk, CHUNK_HEADER_LEN, MAX_CHUNK_LEN, NOISE_TAG_LEN};
+use crate::message::receipt::ACK_TIMEOUT;
+
+pub const DEFAULT_COVER_FRAME_SIZE: usize = 1024;
+/// 1 KiB/s, about one default frame per second
+pub const DEFAULT_COVER_BANDWIDTH: u64 = 1024;
+/// Frames never go out faster than this, however high the bandwidth
+pub const MIN_COVER_INTERVAL: Duration = Duration::from_millis(10);
+/// Bytes a frame takes on the wire besides its plaintext: length prefix and Noise tag
+const FRAME_OVERHEAD: usize = 2 + NOISE_TAG_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoverTraffic {
+    /// Plaintext size of every frame
+    pub frame_size: usize,
+    /// Bytes per second on the wire, frames included; caps what the session may use
+    pub bandwidth: u64,
+}
+
+impl Default for CoverTraffic {
+    fn default() -> Self {
+        CoverTraffic { frame_size: DEFAULT_COVER_FRAME_SIZE, bandwidth: DEFAULT_COVER_BANDWIDTH }
+    }
+}
+
+impl CoverTraffic {
+    pub fn validate(&self) -> Result<()> {
+        if self.frame_size <= CHUNK_HEADER_LEN || self.frame_size > MAX_CHUNK_LEN {
+            return Err(AiroiError::Protocol(format!(
+                "cover frame size must be between {} and {} bytes", CHUNK_HEADER_LEN + 1, MAX_CHUNK_LEN
+            )));
+        }
+        if self.bandwidth == 0 {
+            return Err(AiroiError::Protocol("cover bandwidth must be above zero".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Time between two frames so the wire rate stays within `bandwidth`
+    pub fn interval(&self) -> Duration {
+        let wire = (self.frame_size + FRAME_OVERHEAD) as f64;
+        Duration::from_secs_f64(wire / self.bandwidth.max(1) as f64).max(MIN_COVER_INTERVAL)
+    }
+
+    /// Frames that go out within `ACK_TIMEOUT`
+    pub fn max_queued(&self) -> usize {
+        ((ACK_TIMEOUT.as_secs_f64() / self.interval().as_secs_f64()) as usize).max(1)
+    }
+}
+
+/// Chunks waiting for their tick
+pub(crate) struct CoverQueue {
+    config: CoverTraffic,
+    /// Chunks with whether the sending key is replaced right after them
+    chunks: VecDeque<(Vec<u8>, bool)>,
+}
+
+impl CoverQueue {
+    pub fn new(config: CoverTraffic) -> CoverQueue {
+        CoverQueue { config, chunks: VecDeque::new() }
+    }
+
+    pub fn config(&self) -> CoverTraffic {
+        self.config
+    }
+
+    pub fn interval(&self) -> Duration {
+        self.config.interval()
+    }
+
+    /// Queues a payload. Refused with `MessageTooLarge` if it could never go out before its receipt
+    /// deadline, and with `CoverQueueFull` if it cannot while the queued chunks are ahead of it.
+    pub fn push(&mut self, payload: &[u8]) -> Result<()> {
+        let max = self.config.max_queued();
+        let chunks = fragment_fixed(payload, self.config.frame_size);
+        if chunks.len() > max {
+            let max_size = max * (self.config.frame_size - CHUNK_HEADER_LEN);
+            return Err(AiroiError::MessageTooLarge { size: payload.len(), max: max_size });
+        }
+        if self.chunks.len() + chunks.len() > max {
+            return Err(AiroiError::CoverQueueFull { queued: self.chunks.len(), max });
+        }
+        self.extend(chunks, false);
+        Ok(())
+    }
+
+    /// Queues a control payload after which the sending key is replaced. It is never refused,
+    /// a rekey only follows queued data.
+    pub fn push_rekey(&mut self, payload: &[u8]) {
+        let chunks = fragment_fixed(payload, self.config.frame_size);
+        self.extend(chunks, true);
+    }
+
+    fn extend(&mut self, chunks: Vec<Vec<u8>>, rekey_after: bool) {
+        let last = chunks.len() - 1;
+        self.chunks.extend(chunks.into_iter().enumerate().map(|(i, chunk)| (chunk, rekey_after && i == last)));
+    }
+
+    /// Sends the next queued chunk, or a dummy if there is none
+    pub async fn tick<W: AsyncWrite + Unpin>(&mut self, stream: &mut W, transport: &mut TransportState) -> Result<()> {
+        match self.chunks.pop_front() {
+            Some((chunk, rekey_after)) => {
+                write_chunk(stream, transport, &chunk).await?;
+                if rekey_after {
+                    transport.rekey_outgoing();
+                }
+            }
+            None => write_chunk(stream, transport, &dummy_chunk(self.config.frame_size)).await?,
+        }
+        Ok(())
+    }
+
+    /// Sends everything queued right away, e.g. before closing
+    pub async fn flush<W: AsyncWrite + Unpin>(&mut self, stream: &mut W, transport: &mut TransportState) -> Result<()> {
+        while !self.chunks.is_empty() {
+            self.tick(stream, transport).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_interval_respects_bandwidth() {
+        let cover = CoverTraffic { frame_size: 1006, bandwidth: 2048 };
+        assert_eq!(cover.interval(), Duration::from_millis(500));
+        let fast = CoverTraffic { frame_size: 100, bandwidth: u64::MAX };
+        assert_eq!(fast.interval(), MIN_COVER_INTERVAL);
+        assert!(CoverTraffic { frame_size: 3, bandwidth: 1 }.validate().is_err());
+        assert!(CoverTraffic { frame_size: 256, bandwidth: 0 }.validate().is_err());
+        assert!(CoverTraffic::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_queue_is_bounded_by_the_receipt_deadline() {
+        let cover = CoverTraffic { frame_size: 103, bandwidth: 121 };
+        assert_eq!(cover.max_queued(), 60);
+        let mut queue = CoverQueue::new(cover);
+        assert!(matches!(queue.push(&[0; 6001]), Err(AiroiError::MessageTooLarge { max: 6000, .. })));
+        queue.push(&[0; 5000]).unwrap();
+        assert!(matches!(queue.push(&[0; 1001]), Err(AiroiError::CoverQueueFull { queued: 50, max: 60 })));
+        queue.push(&[0; 1000]).unwrap();
+        queue.push_rekey(&[0; 10]);
+        assert_eq!(queue.chunks.len(), 61);
+    }
+}
@@ -0,0 +1,242 @@
+//! Versioned wire envelope carried inside every Noise transport message.
+//!
+//! Layout (integers are big-endian):
+//!
+//! ```text
+//! offset  size  field
+//! 0       1     protocol version
+//! 1       1     kind
+//! 2       16    message id
+//! 18      8     sender timestamp (unix millis, i64)
+//! 26      4     expiry (seconds, u32), 0 if the message does not disappear
+//! 30      4     body length (u32)
+//! 34      n     body
+//! ```
+//!
+//! A message with an expiry is deleted by the receiver that many seconds after it arrived, and
+//! by the sender that many seconds after sending it.
+//!
+//! The body of a `Text` envelope is UTF-8. `GroupText` and `GroupControl` bodies are JSON, see
+//! `message::group`.
+use chrono::{DateTime, Utc};
+use crate::error::{AiroiError, Result};
+
+pub const PROTOCOL_VERSION: u8 = 2;
+pub const HEADER_LEN: usize = 34;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MessageKind {
+    Text,
+    Ack,
+    Ping,
+    Control,
+    Read,
+    Reject,
+    FileOffer,
+    FileAccept,
+    FileChunk,
+    FileComplete,
+    GroupText,
+    GroupControl,
+}
+
+impl MessageKind {
+    pub fn as_byte(&self) -> u8 {
+        match self {
+            MessageKind::Text => 0,
+            MessageKind::Ack => 1,
+            MessageKind::Ping => 2,
+            MessageKind::Control => 3,
+            MessageKind::Read => 4,
+            MessageKind::Reject => 5,
+            MessageKind::FileOffer => 6,
+            MessageKind::FileAccept => 7,
+            MessageKind::FileChunk => 8,
+            MessageKind::FileComplete => 9,
+            MessageKind::GroupText => 10,
+            MessageKind::GroupControl => 11,
+        }
+    }
+}
+
+impl TryFrom<u8> for MessageKind {
+    type Error = AiroiError;
+
+    fn try_from(value: u8) -> Result<Self> {
+        match value {
+            0 => Ok(MessageKind::Text),
+            1 => Ok(MessageKind::Ack),
+            2 => Ok(MessageKind::Ping),
+            3 => Ok(MessageKind::Control),
+            4 => Ok(MessageKind::Read),
+            5 => Ok(MessageKind::Reject),
+            6 => Ok(MessageKind::FileOffer),
+            7 => Ok(MessageKind::FileAccept),
+            8 => Ok(MessageKind::FileChunk),
+            9 => Ok(MessageKind::FileComplete),
+            10 => Ok(MessageKind::GroupText),
+            11 => Ok(MessageKind::GroupControl),
+            other => Err(AiroiError::Protocol(format!("unknown message kind {}", other))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MessageId(pub [u8; 16]);
+
+impl MessageId {
+    pub fn random() -> MessageId {
+        MessageId(rand::random())
+    }
+}
+
+impl std::fmt::Display for MessageId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", bs58::encode(self.0).into_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Envelope {
+    pub version: u8,
+    pub kind: MessageKind,
+    pub id: MessageId,
+    pub timestamp: i64,
+    /// Seconds until the message disappears, 0 for never
+    pub expires_in: u32,
+    pub body: Vec<u8>,
+}
+
+impl Envelope {
+    pub fn new(kind: MessageKind, body: Vec<u8>) -> Envelope {
+        Envelope {
+            version: PROTOCOL_VERSION,
+            kind,
+            id: MessageId::random(),
+            timestamp: Utc::now().timestamp_millis(),
+            expires_in: 0,
+            body,
+        }
+    }
+
+    /// The envelope, disappearing after `timer` seconds if set
+    pub fn expiring(mut self, timer: Option<u32>) -> Envelope {
+        self.expires_in = timer.unwrap_or(0);
+        self
+    }
+
+    pub fn text(text: &str) -> Envelope {
+        Envelope::new(MessageKind::Text, text.as_bytes().to_vec())
+    }
+
+    pub fn ack(id: MessageId) -> Envelope {
+        Envelope::new(MessageKind::Ack, id.0.to_vec())
+    }
+
+    pub fn read_receipt(id: MessageId) -> Envelope {
+        Envelope::new(MessageKind::Read, id.0.to_vec())
+    }
+
+    pub fn reject(id: MessageId) -> Envelope {
+        Envelope::new(MessageKind::Reject, id.0.to_vec())
+    }
+
+    /// Id of the message an `Ack`, `Read` or `Reject` envelope refers to
+    pub fn referenced_id(&self) -> Result<MessageId> {
+        let id: [u8; 16] = self.body.as_slice().try_into()
+            .map_err(|_| AiroiError::Protocol("receipt body is not a message id".to_string()))?;
+        Ok(MessageId(id))
+    }
+
+    pub fn body_text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    /// Sender timestamp as RFC 3339, falling back to the current time if out of range
+    pub fn sent_at(&self) -> String {
+        DateTime::from_timestamp_millis(self.timestamp)
+            .unwrap_or_else(Utc::now)
+            .to_rfc3339()
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN + self.body.len());
+        out.push(self.version);
+        out.push(self.kind.as_byte());
+        out.extend_from_slice(&self.id.0);
+        out.extend_from_slice(&self.timestamp.to_be_bytes());
+        out.extend_from_slice(&self.expires_in.to_be_bytes());
+        out.extend_from_slice(&(self.body.len() as u32).to_be_bytes());
+        out.extend_from_slice(&self.body);
+        out
+    }
+
+    pub fn decode(data: &[u8]) -> Result<Envelope> {
+        if data.len() < HEADER_LEN {
+            return Err(AiroiError::Protocol("envelope shorter than header".to_string()));
+        }
+        let version = data[0];
+        if version != PROTOCOL_VERSION {
+            return Err(AiroiError::UnsupportedVersion(version));
+        }
+        let kind = MessageKind::try_from(data[1])?;
+        let mut id = [0u8; 16];
+        id.copy_from_slice(&data[2..18]);
+        let timestamp = i64::from_be_bytes(data[18..26].try_into().unwrap());
+        let expires_in = u32::from_be_bytes(data[26..30].try_into().unwrap());
+        let body_len = u32::from_be_bytes(data[30..34].try_into().unwrap()) as usize;
+        if data.len() - HEADER_LEN != body_len {
+            return Err(AiroiError::Protocol("envelope body length mismatch".to_string()));
+        }
+        Ok(Envelope {
+            version,
+            kind,
+            id: MessageId(id),
+            timestamp,
+            expires_in,
+            body: data[HEADER_LEN..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_envelope_roundtrip() {
+        let envelope = Envelope::text("hello there").expiring(Some(3600));
+        let decoded = Envelope::decode(&envelope.encode()).unwrap();
+        assert_eq!(envelope, decoded);
+        assert_eq!(decoded.body_text(), "hello there");
+    }
+
+    #[test]
+    fn test_receipt_references_message() {
+        let envelope = Envelope::text("hello");
+        let ack = Envelope::decode(&Envelope::ack(envelope.id).encode()).unwrap();
+        assert_eq!(ack.kind, MessageKind::Ack);
+        assert_eq!(ack.referenced_id().unwrap(), envelope.id);
+    }
+
+    #[test]
+    fn test_envelope_rejects_truncated() {
+        let encoded = Envelope::text("hello").encode();
+        assert!(Envelope::decode(&encoded[..HEADER_LEN - 1]).is_err());
+        assert!(Envelope::decode(&encoded[..encoded.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_envelope_rejects_unknown_version() {
+        let mut encoded = Envelope::text("hello").encode();
+        encoded[0] = PROTOCOL_VERSION + 1;
+        assert!(matches!(Envelope::decode(&encoded), Err(AiroiError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn test_envelope_rejects_unknown_kind() {
+        let mut encoded = Envelope::text("hello").encode();
+        encoded[1] = 0xff;
+        assert!(Envelope::decode(&encoded).is_err());
+    }
+}
@@ -0,0 +1,512 @@
+//! Chunked file transfer over a connection to a contact.
+//!
+//! The sender offers a file (`FileOffer`), the receiver answers with `FileAccept` carrying the
+//! offset it already holds, or `Reject`. The sender then streams `FileChunk`s from that offset and
+//! the receiver answers `FileComplete` once the SHA-256 of the whole file has been verified.
+//! If the connection drops, the sender reconnects and offers again; the receiver resumes from its
+//! partial file without asking the user a second time. Partial files are kept per contact, so the
+//! same file offered by someone else is asked about like any new offer. A chunk that cannot be
+//! written ends only its transfer, which the receiver then reports as failed.
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::error::{AiroiError, Result};
+use crate::keys::contacts::Contact;
+use crate::message::control::Control;
+use crate::message::envelope::{Envelope, MessageKind};
+use crate::message::fragment::{read_payload, Reassembler, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::message::receipt::{DeliveryStatus, ACK_TIMEOUT};
+use crate::message::rekey::{RekeyPolicy, Rekeyer};
+use crate::message::send::{connect, OutboundConnection};
+use crate::keys::KeyPair;
+use crate::storage::fetch_local_keypair;
+use crate::transport::{TorTransport, Transport};
+
+pub const FILE_CHUNK_SIZE: usize = 32 * 1024;
+pub const MAX_RESUME_ATTEMPTS: u32 = 5;
+/// How long the sender waits for the receiving user to accept or reject an offer
+pub const OFFER_TIMEOUT: Duration = Duration::from_secs(300);
+const RESUME_BACKOFF: Duration = Duration::from_secs(5);
+const PARTIAL_DIR: &str = ".partial";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOffer {
+    /// Base58 SHA-256 of the file content, identifies the transfer across reconnects
+    pub transfer: String,
+    pub name: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileAccept {
+    pub transfer: String,
+    pub offset: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileComplete {
+    pub transfer: String,
+    pub ok: bool,
+}
+
+/// Binary body of a `FileChunk` envelope: 32 byte file hash, u64 offset, data
+pub struct FileChunk {
+    pub hash: [u8; 32],
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+impl FileChunk {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(40 + self.data.len());
+        out.extend_from_slice(&self.hash);
+        out.extend_from_slice(&self.offset.to_be_bytes());
+        out.extend_from_slice(&self.data);
+        out
+    }
+
+    pub fn decode(body: &[u8]) -> Result<FileChunk> {
+        if body.len() < 40 {
+            return Err(AiroiError::Protocol("file chunk shorter than header".to_string()));
+        }
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&body[..32]);
+        let offset = u64::from_be_bytes(body[32..40].try_into().unwrap());
+        Ok(FileChunk { hash, offset, data: body[40..].to_vec() })
+    }
+}
+
+pub fn hash_file(path: &Path) -> Result<[u8; 32]> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; FILE_CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// `hash_file` on a blocking thread, so large files do not stall the runtime
+pub async fn hash_file_blocking(path: PathBuf) -> Result<[u8; 32]> {
+    tokio::task::spawn_blocking(move || hash_file(&path))
+        .await
+        .map_err(|e| AiroiError::Io(std::io::Error::other(e)))?
+}
+
+fn decode_transfer(transfer: &str) -> Result<[u8; 32]> {
+    bs58::decode(transfer).into_vec()?
+        .try_into()
+        .map_err(|_| AiroiError::Protocol("transfer id is not a sha256 hash".to_string()))
+}
+
+// ==================== Receiving ====================
+
+struct IncomingFile {
+    offer: FileOffer,
+    file: File,
+    written: u64,
+}
+
+/// How a transfer ended, and where the file was saved if its hash matched
+pub type Finished = (FileComplete, Option<PathBuf>);
+
+/// Receiver side state of all transfers running on one connection
+pub struct IncomingFiles {
+    downloads_dir: PathBuf,
+    /// Partial files of the contact on the other end of the connection
+    partial_dir: PathBuf,
+    active: HashMap<[u8; 32], IncomingFile>,
+    /// Transfers given up on, whose remaining chunks are dropped quietly
+    failed: HashSet<[u8; 32]>,
+}
+
+impl IncomingFiles {
+    /// Transfers from the contact with the given fingerprint
+    pub fn new(downloads_dir: PathBuf, contact: &str) -> IncomingFiles {
+        let partial_dir = downloads_dir.join(PARTIAL_DIR).join(contact);
+        IncomingFiles { downloads_dir, partial_dir, active: HashMap::new(), failed: HashSet::new() }
+    }
+
+    fn partial_path(&self, offer: &FileOffer) -> PathBuf {
+        self.partial_dir.join(format!("{}.part", offer.transfer))
+    }
+
+    /// Offset of a previously accepted, unfinished transfer of the same file
+    pub fn resume_offset(&self, offer: &FileOffer) -> Option<u64> {
+        std::fs::metadata(self.partial_path(offer))
+            .ok()
+            .map(|m| m.len().min(offer.size))
+    }
+
+    /// Starts or resumes a transfer. An empty file, or one whose partial file already holds every
+    /// byte, has no chunks left to come and is finished right away.
+    pub async fn accept(&mut self, offer: FileOffer) -> Result<(FileAccept, Option<Finished>)> {
+        let hash = decode_transfer(&offer.transfer)?;
+        let path = self.partial_path(&offer);
+        std::fs::create_dir_all(&self.partial_dir)?;
+        self.failed.remove(&hash);
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let written = file.metadata()?.len().min(offer.size);
+        let accept = FileAccept { transfer: offer.transfer.clone(), offset: written };
+        if written == offer.size {
+            let finished = self.finish(hash, IncomingFile { offer, file, written }).await?;
+            return Ok((accept, Some(finished)));
+        }
+        self.active.insert(hash, IncomingFile { offer, file, written });
+        Ok((accept, None))
+    }
+
+    /// Appends a chunk; once the file is complete its hash is verified and it is moved into
+    /// the downloads directory. If the chunk cannot be written the transfer ends as failed.
+    pub async fn write_chunk(&mut self, chunk: FileChunk) -> Result<Option<Finished>> {
+        if self.failed.contains(&chunk.hash) {
+            return Ok(None);
+        }
+        let incoming = self.active.get_mut(&chunk.hash)
+            .ok_or_else(|| AiroiError::Protocol("chunk for unknown transfer".to_string()))?;
+        if chunk.offset != incoming.written {
+            return Err(AiroiError::Protocol(format!(
+                "chunk at offset {} but {} bytes written", chunk.offset, incoming.written
+            )));
+        }
+        if incoming.written + chunk.data.len() as u64 > incoming.offer.size {
+            return Err(AiroiError::Protocol("chunk exceeds offered file size".to_string()));
+        }
+        if let Err(e) = incoming.file.write_all(&chunk.data) {
+            let name = incoming.offer.name.clone();
+            return Ok(Some(self.fail(chunk.hash, &name, e.into())));
+        }
+        incoming.written += chunk.data.len() as u64;
+        if incoming.written < incoming.offer.size {
+            return Ok(None);
+        }
+
+        let incoming = self.active.remove(&chunk.hash).unwrap();
+        let name = incoming.offer.name.clone();
+        match self.finish(chunk.hash, incoming).await {
+            Ok(finished) => Ok(Some(finished)),
+            Err(e) => Ok(Some(self.fail(chunk.hash, &name, e))),
+        }
+    }
+
+    /// Gives up on a transfer the disk could not take, e.g. when it is full
+    fn fail(&mut self, hash: [u8; 32], name: &str, e: AiroiError) -> Finished {
+        eprintln!("giving up on file '{}': {}", name, e);
+        self.active.remove(&hash);
+        self.failed.insert(hash);
+        (FileComplete { transfer: bs58::encode(hash).into_string(), ok: false }, None)
+    }
+
+    /// Verifies the hash of a fully written file and moves it into the downloads directory
+    async fn finish(&self, hash: [u8; 32], incoming: IncomingFile) -> Result<Finished> {
+        incoming.file.sync_all()?;
+        drop(incoming.file);
+        let partial = self.partial_path(&incoming.offer);
+        let ok = hash_file_blocking(partial.clone()).await? == hash;
+        let complete = FileComplete { transfer: incoming.offer.transfer.clone(), ok };
+        if !ok {
+            std::fs::remove_file(&partial)?;
+            return Ok((complete, None));
+        }
+        let target = self.target_path(&incoming.offer.name);
+        std::fs::rename(&partial, &target)?;
+        Ok((complete, Some(target)))
+    }
+
+    /// Free path in the downloads directory, never leaving it regardless of the offered name
+    fn target_path(&self, name: &str) -> PathBuf {
+        let name = Path::new(name)
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_else(|| "download".to_string());
+        let mut target = self.downloads_dir.join(&name);
+        let mut i = 1;
+        while target.exists() {
+            target = self.downloads_dir.join(format!("{}.{}", name, i));
+            i += 1;
+        }
+        target
+    }
+}
+
+// ==================== Sending ====================
+
+/// Sends a file to a contact over Tor with the local key pair, see `send_file_via`
+pub async fn send_file(contact: Contact, path: &Path) -> Result<DeliveryStatus> {
+    let keys = fetch_local_keypair()?;
+    send_file_via(&TorTransport::new(), &keys, contact, path).await
+}
+
+/// Sends a file to a contact, resuming after dropped connections
+pub async fn send_file_via(carrier: &dyn Transport, keys: &KeyPair, contact: Contact, path: &Path) -> Result<DeliveryStatus> {
+    let size = std::fs::metadata(path)?.len();
+    let hash = hash_file_blocking(path.to_path_buf()).await?;
+    let offer = FileOffer {
+        transfer: bs58::encode(hash).into_string(),
+        name: path.file_name().map(|n| n.to_string_lossy().to_string()).unwrap_or_default(),
+        size,
+    };
+
+    let mut attempt = 0;
+    loop {
+        match try_send_file(carrier, keys, &contact, path, &offer, hash).await {
+            Err(e @ (AiroiError::Io(_) | AiroiError::Onion(_))) if attempt < MAX_RESUME_ATTEMPTS => {
+                attempt += 1;
+                eprintln!("transfer interrupted ({}), resuming (attempt {}/{})", e, attempt, MAX_RESUME_ATTEMPTS);
+                tokio::time::sleep(RESUME_BACKOFF).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+async fn try_send_file(
+    carrier: &dyn Transport,
+    keys: &KeyPair,
+    contact: &Contact,
+    path: &Path,
+    offer: &FileOffer,
+    hash: [u8; 32],
+) -> Result<DeliveryStatus> {
+    let (mut conn, _) = connect(carrier, contact, keys, None).await?;
+    let mut reassembler = Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE);
+    let mut rekeyer = Rekeyer::new(RekeyPolicy::default(), contact.padding);
+
+    let offer_envelope = Envelope::new(MessageKind::FileOffer, serde_json::to_vec(offer)?);
+    rekeyer.write(&mut conn.stream, &mut conn.transport, &offer_envelope).await?;
+
+    let accept = tokio::time::timeout(OFFER_TIMEOUT, async {
+        loop {
+            let envelope = next_envelope(&mut conn, &mut reassembler, &mut rekeyer).await?;
+            match envelope.kind {
+                MessageKind::FileAccept => {
+                    let accept: FileAccept = serde_json::from_slice(&envelope.body)?;
+                    if accept.transfer == offer.transfer {
+                        return Ok(Some(accept));
+                    }
+                }
+                MessageKind::Reject if envelope.referenced_id()? == offer_envelope.id => return Ok(None),
+                _ => {}
+            }
+        }
+    }).await;
+    let accept = match accept {
+        Ok(Ok(Some(accept))) => accept,
+        Ok(Ok(None)) => return Ok(DeliveryStatus::Rejected),
+        Ok(Err(e)) => return Err(e),
+        Err(_) => return Ok(DeliveryStatus::TimedOut),
+    };
+
+    if accept.offset > 0 {
+        println!("Resuming '{}' at {} of {} bytes", offer.name, accept.offset, offer.size);
+    }
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(accept.offset))?;
+    let mut offset = accept.offset;
+    let mut buf = vec![0u8; FILE_CHUNK_SIZE];
+    while offset < offer.size {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            return Err(AiroiError::Protocol("file shrank while sending".to_string()));
+        }
+        let chunk = FileChunk { hash, offset, data: buf[..n].to_vec() };
+        let envelope = Envelope::new(MessageKind::FileChunk, chunk.encode());
+        rekeyer.write(&mut conn.stream, &mut conn.transport, &envelope).await?;
+        offset += n as u64;
+    }
+
+    let complete = tokio::time::timeout(ACK_TIMEOUT, async {
+        loop {
+            let envelope = next_envelope(&mut conn, &mut reassembler, &mut rekeyer).await?;
+            if envelope.kind == MessageKind::FileComplete {
+                let complete: FileComplete = serde_json::from_slice(&envelope.body)?;
+                if complete.transfer == offer.transfer {
+                    return Ok::<_, AiroiError>(complete);
+                }
+            }
+        }
+    }).await;
+    match complete {
+        Ok(Ok(complete)) if complete.ok => Ok(DeliveryStatus::Delivered),
+        Ok(Ok(_)) => Ok(DeliveryStatus::Rejected),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Ok(DeliveryStatus::TimedOut),
+    }
+}
+
+/// Next envelope from the receiver; its rekey messages are applied and its pings answered on the
+/// way, so its session does not end on the idle timeout while the user decides on the offer
+async fn next_envelope(conn: &mut OutboundConnection, reassembler: &mut Reassembler, rekeyer: &mut Rekeyer) -> Result<Envelope> {
+    loop {
+        let payload = read_payload(&mut conn.stream, &mut conn.transport, reassembler).await?;
+        let envelope = Envelope::decode(&payload)?;
+        match envelope.kind {
+            MessageKind::Control if Control::decode(&envelope.body)? == Control::Rekey => {
+                conn.transport.rekey_incoming();
+            }
+            MessageKind::Ping => {
+                rekeyer.write(&mut conn.stream, &mut conn.transport, &Envelope::ack(envelope.id)).await?;
+            }
+            _ => return Ok(envelope),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use crate::keys::key_gen::generate_key_pair;
+    use crate::message::receive::{handle_connection, ReceiveConfig};
+    use crate::transport::MemoryTransport;
+
+    fn temp_dir() -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("airoi-test-{}", bs58::encode(rand::random::<[u8; 8]>()).into_string()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn test_incoming_file_resumes_and_verifies() {
+        let dir = temp_dir();
+        let data: Vec<u8> = (0..1000u32).map(|i| i as u8).collect();
+        let hash: [u8; 32] = Sha256::digest(&data).into();
+        let offer = FileOffer { transfer: bs58::encode(hash).into_string(), name: "../evil.txt".to_string(), size: 1000 };
+
+        let mut incoming = IncomingFiles::new(dir.clone(), "alice");
+        assert!(incoming.resume_offset(&offer).is_none());
+        assert_eq!(incoming.accept(offer.clone()).await.unwrap().0.offset, 0);
+        let first = FileChunk { hash, offset: 0, data: data[..400].to_vec() };
+        assert!(incoming.write_chunk(first).await.unwrap().is_none());
+
+        // connection drops, a fresh connection resumes from the partial file; someone else
+        // offering the same file does not get to continue it
+        assert!(IncomingFiles::new(dir.clone(), "carol").resume_offset(&offer).is_none());
+        let mut incoming = IncomingFiles::new(dir.clone(), "alice");
+        assert_eq!(incoming.resume_offset(&offer), Some(400));
+        assert_eq!(incoming.accept(offer).await.unwrap().0.offset, 400);
+        let rest = FileChunk { hash, offset: 400, data: data[400..].to_vec() };
+        let (complete, saved) = incoming.write_chunk(rest).await.unwrap().unwrap();
+        assert!(complete.ok);
+        let saved = saved.unwrap();
+        assert_eq!(saved, dir.join("evil.txt"));
+        assert_eq!(std::fs::read(saved).unwrap(), data);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incoming_empty_file_completes_on_accept() {
+        let dir = temp_dir();
+        let hash: [u8; 32] = Sha256::digest([]).into();
+        let offer = FileOffer { transfer: bs58::encode(hash).into_string(), name: "empty".to_string(), size: 0 };
+
+        let mut incoming = IncomingFiles::new(dir.clone(), "alice");
+        let (accept, finished) = incoming.accept(offer).await.unwrap();
+        assert_eq!(accept.offset, 0);
+        let (complete, saved) = finished.unwrap();
+        assert!(complete.ok);
+        assert!(std::fs::read(saved.unwrap()).unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incoming_file_completes_when_resumed_fully_written() {
+        let dir = temp_dir();
+        let data = vec![7u8; 300];
+        let hash: [u8; 32] = Sha256::digest(&data).into();
+        let offer = FileOffer { transfer: bs58::encode(hash).into_string(), name: "full".to_string(), size: 300 };
+
+        // the last chunk was written but the connection dropped before the completion was sent
+        let mut incoming = IncomingFiles::new(dir.clone(), "alice");
+        incoming.accept(offer.clone()).await.unwrap();
+        incoming.active.get_mut(&hash).unwrap().file.write_all(&data).unwrap();
+
+        let mut incoming = IncomingFiles::new(dir.clone(), "alice");
+        assert_eq!(incoming.resume_offset(&offer), Some(300));
+        let (accept, finished) = incoming.accept(offer).await.unwrap();
+        assert_eq!(accept.offset, 300);
+        let (complete, saved) = finished.unwrap();
+        assert!(complete.ok);
+        assert_eq!(std::fs::read(saved.unwrap()).unwrap(), data);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_failed_write_ends_only_its_transfer() {
+        let dir = temp_dir();
+        let hash = [2u8; 32];
+        let offer = FileOffer { transfer: bs58::encode(hash).into_string(), name: "big".to_string(), size: 10 };
+        let mut incoming = IncomingFiles::new(dir.clone(), "alice");
+        incoming.accept(offer.clone()).await.unwrap();
+        incoming.active.get_mut(&hash).unwrap().file = OpenOptions::new().write(true).open("/dev/full").unwrap();
+
+        let (complete, saved) = incoming.write_chunk(FileChunk { hash, offset: 0, data: vec![0; 5] }).await.unwrap().unwrap();
+        assert!(!complete.ok && saved.is_none());
+        assert_eq!(complete.transfer, offer.transfer);
+        // the rest of the file is still on its way and dropped
+        assert!(incoming.write_chunk(FileChunk { hash, offset: 5, data: vec![0; 5] }).await.unwrap().is_none());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_offer_accepted_after_idle_timeout() {
+        let dir = temp_dir();
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let carrier = MemoryTransport::new();
+        let mut listener = carrier.listen("bob").await.unwrap();
+        let config = ReceiveConfig {
+            keepalive: Duration::from_millis(50),
+            idle_timeout: Duration::from_millis(300),
+            downloads_dir: dir.join("downloads"),
+            ..ReceiveConfig::default()
+        };
+        let (tx, mut rx) = mpsc::channel(1);
+        {
+            let bob = bob.clone();
+            let contacts = vec![Contact::new("alice".to_string(), alice.public_key().ed25519_key_raw().to_vec(), "alice")];
+            tokio::spawn(async move {
+                let (socket, peer_addr) = listener.accept().await.unwrap();
+                let _ = handle_connection(&bob, socket, &peer_addr, &contacts, tx, &config).await;
+            });
+        }
+
+        let path = dir.join("report.txt");
+        std::fs::write(&path, b"late but wanted").unwrap();
+        let contact = Contact::new("bob".to_string(), bob.public_key().ed25519_key_raw().to_vec(), "bob");
+        let sending = tokio::spawn(async move { send_file_via(&carrier, &alice, contact, &path).await });
+
+        // the user takes longer to decide than the receiver's idle timeout
+        let offer = rx.recv().await.unwrap();
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        offer.accept_file().await.unwrap();
+        assert_eq!(sending.await.unwrap().unwrap(), DeliveryStatus::Delivered);
+        assert_eq!(std::fs::read(dir.join("downloads").join("report.txt")).unwrap(), b"late but wanted");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incoming_file_rejects_wrong_offset() {
+        let dir = temp_dir();
+        let hash = [1u8; 32];
+        let offer = FileOffer { transfer: bs58::encode(hash).into_string(), name: "a".to_string(), size: 10 };
+        let mut incoming = IncomingFiles::new(dir.clone(), "alice");
+        incoming.accept(offer).await.unwrap();
+        assert!(incoming.write_chunk(FileChunk { hash, offset: 5, data: vec![0; 5] }).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
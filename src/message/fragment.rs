@@ -0,0 +1,213 @@
+//! Splits payloads of arbitrary length into chunks that each fit into a single
+//! Noise transport message, and reassembles them on the receiving side.
+//!
+//! Every chunk starts with a one byte flag: `FLAG_MORE` if further chunks of the
+//! same payload follow, `FLAG_FINAL` for the last one. The flag is followed by the
+//! length of the chunk data as a big-endian u16; anything after the data is padding.
+//! `FLAG_DUMMY` chunks carry no data and are dropped by the receiver (cover traffic).
+use snow::TransportState;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::error::{AiroiError, Result};
+use crate::message::{read_frame, write_frame};
+use crate::message::padding::PaddingPolicy;
+
+pub const NOISE_MAX_MSG_LEN: usize = 65535;
+pub const NOISE_TAG_LEN: usize = 16;
+pub const CHUNK_HEADER_LEN: usize = 3;
+/// Largest plaintext of a single chunk, padding included
+pub const MAX_CHUNK_LEN: usize = NOISE_MAX_MSG_LEN - NOISE_TAG_LEN;
+pub const MAX_CHUNK_DATA: usize = MAX_CHUNK_LEN - CHUNK_HEADER_LEN;
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 16 * 1024 * 1024;
+
+const FLAG_FINAL: u8 = 0;
+const FLAG_MORE: u8 = 1;
+const FLAG_DUMMY: u8 = 2;
+
+pub fn fragment(payload: &[u8], padding: PaddingPolicy) -> Vec<Vec<u8>> {
+    fragment_within(payload, padding, MAX_CHUNK_LEN)
+}
+
+/// Like `fragment` with chunks of at most `max_chunk_len` bytes, for chunks carried in something smaller than a transport message
+pub fn fragment_within(payload: &[u8], padding: PaddingPolicy, max_chunk_len: usize) -> Vec<Vec<u8>> {
+    split(payload, max_chunk_len - CHUNK_HEADER_LEN, |len| padding.padded_len(len, max_chunk_len))
+}
+
+/// Splits a payload into chunks of exactly `chunk_len` bytes
+pub fn fragment_fixed(payload: &[u8], chunk_len: usize) -> Vec<Vec<u8>> {
+    split(payload, chunk_len - CHUNK_HEADER_LEN, |_| chunk_len)
+}
+
+/// A chunk of `chunk_len` bytes that the receiver discards
+pub fn dummy_chunk(chunk_len: usize) -> Vec<u8> {
+    chunk(FLAG_DUMMY, &[], chunk_len)
+}
+
+fn split(payload: &[u8], max_data: usize, padded_len: impl Fn(usize) -> usize) -> Vec<Vec<u8>> {
+    if payload.is_empty() {
+        return vec![chunk(FLAG_FINAL, &[], padded_len(CHUNK_HEADER_LEN))];
+    }
+    let count = payload.len().div_ceil(max_data);
+    payload
+        .chunks(max_data)
+        .enumerate()
+        .map(|(i, data)| {
+            let flag = if i + 1 == count { FLAG_FINAL } else { FLAG_MORE };
+            chunk(flag, data, padded_len(CHUNK_HEADER_LEN + data.len()))
+        })
+        .collect()
+}
+
+fn chunk(flag: u8, data: &[u8], padded_len: usize) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(padded_len);
+    chunk.push(flag);
+    chunk.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    chunk.extend_from_slice(data);
+    chunk.resize(padded_len, 0);
+    chunk
+}
+
+pub struct Reassembler {
+    buf: Vec<u8>,
+    max_size: usize,
+}
+
+impl Reassembler {
+    pub fn new(max_size: usize) -> Reassembler {
+        Reassembler { buf: Vec::new(), max_size }
+    }
+
+    /// Feeds one decrypted chunk; returns the full payload once the final chunk arrived
+    pub fn push(&mut self, chunk: &[u8]) -> Result<Option<Vec<u8>>> {
+        if chunk.len() < CHUNK_HEADER_LEN {
+            return Err(AiroiError::Protocol("truncated chunk".to_string()));
+        }
+        let flag = &chunk[0];
+        if *flag == FLAG_DUMMY {
+            return Ok(None);
+        }
+        let len = u16::from_be_bytes([chunk[1], chunk[2]]) as usize;
+        let data = chunk[CHUNK_HEADER_LEN..]
+            .get(..len)
+            .ok_or_else(|| AiroiError::Protocol("chunk length exceeds chunk".to_string()))?;
+        let size = self.buf.len() + data.len();
+        if size > self.max_size {
+            self.buf.clear();
+            return Err(AiroiError::MessageTooLarge { size, max: self.max_size });
+        }
+        self.buf.extend_from_slice(data);
+        match *flag {
+            FLAG_FINAL => Ok(Some(std::mem::take(&mut self.buf))),
+            FLAG_MORE => Ok(None),
+            other => Err(AiroiError::Protocol(format!("unknown chunk flag {}", other))),
+        }
+    }
+}
+
+pub async fn write_payload<W: AsyncWrite + Unpin>(
+    stream: &mut W,
+    transport: &mut TransportState,
+    payload: &[u8],
+    padding: PaddingPolicy,
+) -> Result<()> {
+    for chunk in fragment(payload, padding) {
+        write_chunk(stream, transport, &chunk).await?;
+    }
+    Ok(())
+}
+
+/// Encrypts one chunk into a transport frame
+pub async fn write_chunk<W: AsyncWrite + Unpin>(stream: &mut W, transport: &mut TransportState, chunk: &[u8]) -> Result<()> {
+    let mut cipher = vec![0u8; chunk.len() + NOISE_TAG_LEN];
+    let len = transport.write_message(chunk, &mut cipher)?;
+    write_frame(stream, &cipher[..len]).await?;
+    Ok(())
+}
+
+pub async fn read_payload<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    transport: &mut TransportState,
+    reassembler: &mut Reassembler,
+) -> Result<Vec<u8>> {
+    loop {
+        let frame = read_frame(stream).await?;
+        if let Some(payload) = read_chunk(transport, reassembler, &frame)? {
+            return Ok(payload);
+        }
+    }
+}
+
+/// Decrypts one transport frame and feeds it to the reassembler
+pub fn read_chunk(
+    transport: &mut TransportState,
+    reassembler: &mut Reassembler,
+    frame: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    let mut plaintext = vec![0u8; frame.len()];
+    let len = transport.read_message(frame, &mut plaintext)?;
+    reassembler.push(&plaintext[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fragment_and_reassemble() {
+        let payload: Vec<u8> = (0..MAX_CHUNK_DATA * 3 + 17).map(|i| i as u8).collect();
+        let chunks = fragment(&payload, PaddingPolicy::None);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|c| c.len() <= MAX_CHUNK_LEN));
+
+        let mut reassembler = Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let mut result = None;
+        for chunk in &chunks {
+            result = reassembler.push(chunk).unwrap();
+        }
+        assert_eq!(result.unwrap(), payload);
+    }
+
+    #[test]
+    fn test_fragment_empty_payload() {
+        let chunks = fragment(&[], PaddingPolicy::None);
+        let mut reassembler = Reassembler::new(16);
+        assert_eq!(reassembler.push(&chunks[0]).unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn test_reassembler_enforces_max_size() {
+        let payload = vec![7u8; MAX_CHUNK_DATA + 1];
+        let chunks = fragment(&payload, PaddingPolicy::None);
+        let mut reassembler = Reassembler::new(MAX_CHUNK_DATA);
+        assert!(reassembler.push(&chunks[0]).unwrap().is_none());
+        assert!(matches!(reassembler.push(&chunks[1]), Err(AiroiError::MessageTooLarge { .. })));
+    }
+
+    #[test]
+    fn test_padding_is_stripped() {
+        let payload = b"hello".to_vec();
+        let chunks = fragment(&payload, PaddingPolicy::Buckets);
+        assert_eq!(chunks[0].len(), 256);
+        let mut reassembler = Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE);
+        assert_eq!(reassembler.push(&chunks[0]).unwrap(), Some(payload));
+
+        let mut chunk = chunks[0][..CHUNK_HEADER_LEN + 2].to_vec();
+        chunk[2] = 200;
+        assert!(reassembler.push(&chunk).is_err());
+    }
+
+    #[test]
+    fn test_fixed_chunks_and_dummies() {
+        let payload = vec![5u8; 1000];
+        let chunks = fragment_fixed(&payload, 256);
+        assert_eq!(chunks.len(), 4);
+        assert!(chunks.iter().all(|c| c.len() == 256));
+
+        let mut reassembler = Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let mut result = None;
+        for chunk in &chunks {
+            assert!(reassembler.push(&dummy_chunk(256)).unwrap().is_none());
+            result = reassembler.push(chunk).unwrap();
+        }
+        assert_eq!(result.unwrap(), payload);
+    }
+}
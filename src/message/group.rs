@@ -0,0 +1,309 @@
+//! Group conversations on top of the peer-to-peer sessions.
+//!
+//! There is no group server. A group message is encrypted once under the group's epoch keys (see
+//! `group_key`) and goes to every member over their own session (fan-out) as a `GroupText`
+//! envelope whose body names the group. Membership is changed by the admin, who signs the new
+//! state of the group and the commit that moves the group keys to the new epoch with their
+//! Ed25519 key, and sends it as `GroupControl` to the old and the new members. A member accepts a new state only if the
+//! contact that sent it also signed it, is an admin of the state the member knows, and raised the
+//! epoch. A group it does not know yet it joins if the signer administers it; a member that is
+//! no longer listed drops the group. A member that misses an update could not follow the group
+//! keys any more, so undelivered updates are queued in the outbox and resent in order.
+//!
+//! Members that are not our contacts cannot be reached and are reported as such.
+use std::sync::Arc;
+use base64::Engine;
+use base64::engine::general_purpose;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinSet;
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::contacts::{get_contacts, Contact};
+use crate::keys::groups::{get_groups, remove_group, update_group, Group};
+use crate::message::Message;
+use crate::message::envelope::{Envelope, MessageKind};
+use crate::message::group_key::{GroupCiphertext, GroupKeyStore, TreeCommit};
+use crate::message::outbox::{Outbox, RetryPolicy};
+use crate::message::session::SessionRegistry;
+
+const GROUP_CONTEXT: &[u8] = b"airoi group v1";
+
+/// Body of a `GroupText` envelope
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupText {
+    pub group: String,
+    pub message: GroupCiphertext,
+}
+
+/// Body of a `GroupControl` envelope: the new state of a group and its key commit, signed by an
+/// admin
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupUpdate {
+    pub group: Group,
+    pub commit: TreeCommit,
+    /// Ed25519 key (base58) of the admin that signed
+    pub signer: String,
+    /// Base64 signature over `GROUP_CONTEXT || JSON of group || JSON of commit`
+    pub signature: String,
+}
+
+fn signed_payload(group: &Group, commit: &TreeCommit) -> Result<Vec<u8>> {
+    Ok([GROUP_CONTEXT, &serde_json::to_vec(group)?, &serde_json::to_vec(commit)?].concat())
+}
+
+impl GroupUpdate {
+    pub fn sign(keys: &KeyPair, group: Group, commit: TreeCommit) -> Result<GroupUpdate> {
+        let seed: [u8; 32] = keys.private_key().ed25519_key_raw().try_into()
+            .map_err(|_| AiroiError::Identity("local ed25519 key is malformed".to_string()))?;
+        let signature = SigningKey::from_bytes(&seed).sign(&signed_payload(&group, &commit)?);
+        Ok(GroupUpdate {
+            group,
+            commit,
+            signer: keys.public_key().ed25519_key().to_string(),
+            signature: general_purpose::STANDARD.encode(signature.to_bytes()),
+        })
+    }
+
+    pub fn verify(&self) -> Result<()> {
+        let invalid = || AiroiError::Identity(format!("update of group {} has an invalid signature", self.group.name));
+        let signer: [u8; 32] = bs58::decode(&self.signer).into_vec().ok()
+            .and_then(|key| key.try_into().ok())
+            .ok_or_else(invalid)?;
+        let verifying_key = VerifyingKey::from_bytes(&signer).map_err(|_| invalid())?;
+        let signature = general_purpose::STANDARD.decode(&self.signature).ok()
+            .and_then(|signature| Signature::from_slice(&signature).ok())
+            .ok_or_else(invalid)?;
+        verifying_key.verify(&signed_payload(&self.group, &self.commit)?, &signature).map_err(|_| invalid())
+    }
+
+    /// Checks the update, sent by the contact with Ed25519 key `sender`, against the state we know
+    pub fn check(&self, current: Option<&Group>, sender: &str) -> Result<()> {
+        self.verify()?;
+        let refused = |reason: &str| Err(AiroiError::Identity(format!("update of group {} refused: {}", self.group.name, reason)));
+        if self.signer != sender {
+            return refused("not signed by its sender");
+        }
+        // with two admins committing from the same epoch the group keys would split in two
+        if self.group.admins.len() != 1 {
+            return refused("a group has exactly one admin");
+        }
+        match current {
+            Some(current) if !current.is_admin(sender) => refused("sender is no admin"),
+            Some(current) if self.group.epoch <= current.epoch => refused("outdated epoch"),
+            None if !self.group.is_admin(sender) => refused("sender is no admin"),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn into_envelope(&self) -> Result<Envelope> {
+        Ok(Envelope::new(MessageKind::GroupControl, serde_json::to_vec(self)?))
+    }
+}
+
+/// Which members got a fan-out
+#[derive(Debug, Default)]
+pub struct FanOut {
+    pub delivered: Vec<Contact>,
+    /// Contacts the envelope was not delivered to, with the reason
+    pub failed: Vec<(Contact, String)>,
+    /// Contacts a group update was not delivered to and is queued for, with the reason
+    pub queued: Vec<(Contact, String)>,
+    /// Members that are not our contacts
+    pub unknown: Vec<String>,
+}
+
+/// Sends the envelope to every contact at once over the registry's sessions
+async fn fan_out(registry: &Arc<SessionRegistry>, recipients: Vec<Contact>, envelope: &Envelope) -> FanOut {
+    let mut sends = JoinSet::new();
+    for contact in recipients {
+        let registry = registry.clone();
+        let envelope = envelope.clone();
+        sends.spawn(async move {
+            let result = registry.send(&contact, envelope).await;
+            (contact, result)
+        });
+    }
+    let mut report = FanOut::default();
+    while let Some(joined) = sends.join_next().await {
+        match joined {
+            Ok((contact, Ok(status))) if status.is_delivered() => report.delivered.push(contact),
+            Ok((contact, Ok(status))) => report.failed.push((contact, status.to_string())),
+            Ok((contact, Err(e))) => report.failed.push((contact, e.to_string())),
+            Err(e) => eprintln!("group send task failed: {}", e),
+        }
+    }
+    report
+}
+
+/// Encrypts a text under the group's epoch and sends it to every other member, disappearing
+/// with the group's timer
+pub async fn send_group(registry: &Arc<SessionRegistry>, group: &Group, text: &str) -> Result<FanOut> {
+    let own = registry.keys().public_key().ed25519_key().to_string();
+    if !group.is_member(&own) {
+        return Err(AiroiError::Identity(format!("not a member of group {}", group.name)));
+    }
+    let (recipients, unknown) = group.recipients(&get_contacts()?, &own);
+    let unknown = unknown.into_iter().map(str::to_string).collect();
+    let message = GroupKeyStore::open(registry.keys()).encrypt(&group.id, text.as_bytes())?;
+    let body = GroupText { group: group.id.clone(), message };
+    let envelope = Envelope::new(MessageKind::GroupText, serde_json::to_vec(&body)?).expiring(group.disappearing);
+    Ok(FanOut { unknown, ..fan_out(registry, recipients, &envelope).await })
+}
+
+/// Commits the group keys to the new state of a group we administer, signs and stores it, and
+/// sends it to the members of the new and of the `previous` state, so removed members learn about
+/// it as well. Members it does not reach get it from the outbox later. We cannot remove ourselves,
+/// as the committer has to know the new epoch.
+pub async fn publish_update(registry: &Arc<SessionRegistry>, group: Group, previous: Option<&Group>) -> Result<FanOut> {
+    let own = registry.keys().public_key().ed25519_key().to_string();
+    if previous.is_some_and(|previous| !previous.is_admin(&own)) {
+        return Err(AiroiError::Identity(format!("only admins may change group {}", group.name)));
+    }
+    if !group.is_member(&own) {
+        return Err(AiroiError::Identity(format!("cannot remove ourselves from group {}", group.name)));
+    }
+    if group.admins.len() != 1 {
+        return Err(AiroiError::Identity(format!("group {} must have exactly one admin", group.name)));
+    }
+    let commit = GroupKeyStore::open(registry.keys()).commit(registry.keys(), &group)?;
+    let update = GroupUpdate::sign(registry.keys(), group, commit)?;
+    let mut reach = update.group.clone();
+    for member in previous.iter().flat_map(|p| p.members.iter()) {
+        reach.add_member(member);
+    }
+    update_group(&update.group)?;
+    let (recipients, unknown) = reach.recipients(&get_contacts()?, &own);
+    let unknown = unknown.into_iter().map(str::to_string).collect();
+    let envelope = update.into_envelope()?;
+    let mut report = fan_out(registry, recipients, &envelope).await;
+    let outbox = Outbox::open(registry.keys());
+    for (contact, reason) in std::mem::take(&mut report.failed) {
+        match outbox.push(&contact, &envelope, &reason, &RetryPolicy::default()) {
+            Ok(_) => report.queued.push((contact, reason)),
+            Err(e) => report.failed.push((contact, format!("{}, queueing failed: {}", reason, e))),
+        }
+    }
+    Ok(FanOut { unknown, ..report })
+}
+
+/// Name of the contact with this Ed25519 key, or the start of the key
+fn member_name(contacts: &[Contact], key: &str) -> String {
+    contacts.iter()
+        .find(|c| c.public_key().ed25519_key() == key)
+        .map(|c| c.name.clone())
+        .unwrap_or_else(|| key.chars().take(8).collect())
+}
+
+/// Turns a `GroupText` or `GroupControl` envelope from the contact into a message, decrypting
+/// texts and applying membership changes and their commits. Fails if the envelope is not
+/// acceptable from this contact.
+pub(crate) fn receive_group(contact: &Contact, keys: &KeyPair, envelope: &Envelope) -> Result<Message> {
+    let sender = contact.public_key().ed25519_key();
+    let identity = keys.public_key().ed25519_key();
+    let store = GroupKeyStore::open(keys);
+    let groups = get_groups()?;
+    let mut message = Message::from_envelope(contact.clone(), envelope);
+    match envelope.kind {
+        MessageKind::GroupText => {
+            let body: GroupText = serde_json::from_slice(&envelope.body)?;
+            let group = groups.into_iter()
+                .find(|g| g.id == body.group && g.is_member(sender))
+                .ok_or_else(|| AiroiError::Identity(format!("{} is not in group {}", contact.name, body.group)))?;
+            let text = store.decrypt(&group.id, sender, &body.message)?;
+            message.message = String::from_utf8(text)
+                .map_err(|_| AiroiError::Protocol("group message is not valid UTF-8".to_string()))?;
+            message.group = Some(group);
+        }
+        MessageKind::GroupControl => {
+            let update: GroupUpdate = serde_json::from_slice(&envelope.body)?;
+            let current = groups.iter().find(|g| g.id == update.group.id);
+            update.check(current, sender)?;
+            if update.group.is_member(identity) {
+                store.process(keys, &update.group, sender, &update.commit)?;
+            }
+            let after = update.group;
+            let contacts = get_contacts()?;
+            message.message = match current {
+                _ if !after.is_member(identity) => {
+                    remove_group(&after.id)?;
+                    store.forget(&after.id)?;
+                    "removed you from the group".to_string()
+                }
+                None => format!("added you to the group with {} members", after.members.len()),
+                Some(before) => {
+                    let added = after.members.iter().filter(|m| !before.is_member(m));
+                    let removed = before.members.iter().filter(|m| !after.is_member(m));
+                    let timer = match after.disappearing {
+                        _ if after.disappearing == before.disappearing => None,
+                        Some(timer) => Some(format!("set disappearing messages to {:?}", std::time::Duration::from_secs(timer as u64))),
+                        None => Some("turned disappearing messages off".to_string()),
+                    };
+                    let changes: Vec<String> = added.map(|m| format!("added {}", member_name(&contacts, m)))
+                        .chain(removed.map(|m| format!("removed {}", member_name(&contacts, m))))
+                        .chain(timer)
+                        .collect();
+                    if changes.is_empty() { "updated the group".to_string() } else { changes.join(", ") }
+                }
+            };
+            if after.is_member(identity) {
+                update_group(&after)?;
+            }
+            message.group = Some(after);
+        }
+        other => return Err(AiroiError::Protocol(format!("{:?} is not a group message", other))),
+    }
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::key_gen::generate_key_pair;
+
+    fn store(keys: &KeyPair) -> GroupKeyStore {
+        let path = std::env::temp_dir().join(format!("airoi-group-keys-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        GroupKeyStore::at(path, keys)
+    }
+
+    #[test]
+    fn test_update_checks() {
+        let admin = generate_key_pair().unwrap();
+        let member = generate_key_pair().unwrap();
+        let (admin_key, member_key) = (admin.public_key().ed25519_key(), member.public_key().ed25519_key());
+        let group = Group::new("friends", admin_key, vec![member_key.to_string()]);
+        let keys = store(&admin);
+
+        let commit = keys.commit(&admin, &group).unwrap();
+        let created = GroupUpdate::sign(&admin, group.clone(), commit.clone()).unwrap();
+        assert!(created.check(None, admin_key).is_ok());
+        // forwarded by someone else, or signed by a non-admin
+        assert!(created.check(None, member_key).is_err());
+        let by_member = GroupUpdate::sign(&member, group.clone(), commit).unwrap();
+        assert!(by_member.check(None, member_key).is_err());
+        assert!(by_member.check(Some(&group), member_key).is_err());
+
+        // replaying the creation after a change is refused, the change itself is accepted
+        let mut changed = group.clone();
+        changed.remove_member(member_key);
+        changed.epoch += 1;
+        let removal = GroupUpdate::sign(&admin, changed.clone(), keys.commit(&admin, &changed).unwrap()).unwrap();
+        assert!(removal.check(Some(&group), admin_key).is_ok());
+        assert!(created.check(Some(&changed), admin_key).is_err());
+
+        let mut forged = removal.clone();
+        forged.group.members.push(member_key.to_string());
+        assert!(forged.check(Some(&group), admin_key).is_err());
+        let mut forged = removal.clone();
+        forged.commit.epoch += 1;
+        assert!(forged.check(Some(&group), admin_key).is_err());
+
+        // a second admin could commit concurrently, so it is refused
+        let mut shared = changed.clone();
+        shared.admins.push(member_key.to_string());
+        shared.epoch += 1;
+        let sharing = GroupUpdate::sign(&admin, shared.clone(), keys.commit(&admin, &shared).unwrap()).unwrap();
+        assert!(sharing.check(Some(&changed), admin_key).is_err());
+    }
+}
@@ -0,0 +1,756 @@
+//! Group key agreement after MLS (RFC 9420), in a reduced form, so a group message is encrypted
+//! once for all members and every message key is deleted after use.
+//!
+//! Members sit at the leaves of a left-balanced binary tree whose nodes hold X25519 keys; every
+//! member knows the secret keys on the path from its leaf to the root. An admin changing the
+//! membership sends a commit: it replaces its own leaf and path with keys derived from a fresh
+//! chain of path secrets (TreeKEM), and encrypts each path secret to the resolution of the
+//! sibling subtree, so every other member can decrypt exactly the part of the path it shares.
+//! The secret above the root is the commit secret. Removed members' leaves and paths are blanked,
+//! so nothing is encrypted to them any more. Added members start with their identity X25519 key
+//! as leaf key, with their path blanked, and get the joiner secret in a `Welcome`.
+//!
+//! Each epoch chains the previous one's init secret with the commit secret and the group context
+//! (group id, epoch and tree hash). From the epoch secret come the next init secret, a
+//! confirmation tag binding the commit to the derived state, and the application secret, which
+//! gives every sender its own hash ratchet: each message key is used once and then deleted.
+//! Messages of the previous epoch still open until the next change.
+//!
+//! Commits are not merged: two committing from the same epoch would split the group into halves
+//! that cannot read each other. A group therefore has exactly one admin, who commits in order,
+//! and a member that missed a commit gets it resent from the admin's outbox.
+//!
+//! Only the admin commits, and a commit refreshes only the admin's leaf and path. Every other
+//! member keeps its identity X25519 key as leaf key, and welcomes are sealed to that key too.
+//! What is delivered is therefore limited: message keys of an epoch are gone once used, and an
+//! epoch's keys are gone one change later, but stealing a member's identity key opens every
+//! later commit (no post-compromise security for members that never commit) and any recorded
+//! welcome to that member (no forward secrecy for the epoch it joined in).
+//! State is stored per group in `group_keys.json` in the airoi directory, encrypted under a key
+//! derived from the local key pair.
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use base64::Engine;
+use base64::engine::general_purpose;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use chacha20poly1305::aead::{Aead, Payload};
+use hkdf::Hkdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::Zeroize;
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::groups::Group;
+use crate::keys::key_gen::try_ed25519_pk_to_x25519;
+use crate::message::ratchet::{generate_secret, kdf_chain, message_cipher};
+use crate::storage::sealed::{lock_sealed, read_sealed, storage_key, write_sealed};
+use crate::util::get_airoi_dir;
+
+/// Message keys derived ahead within one sender's chain at most
+pub const MAX_GENERATION_SKIP: u32 = 1000;
+/// Skipped message keys kept per epoch, the oldest are dropped first
+const MAX_SKIPPED_KEYS: usize = 2000;
+
+const SEAL_INFO: &[u8] = b"airoi group seal v1";
+const PATH_INFO: &[u8] = b"airoi group path v1";
+const NODE_INFO: &[u8] = b"airoi group node v1";
+const JOINER_INFO: &[u8] = b"airoi group joiner v1";
+const EPOCH_INFO: &[u8] = b"airoi group epoch v1";
+const INIT_INFO: &[u8] = b"airoi group init v1";
+const APPLICATION_INFO: &[u8] = b"airoi group application v1";
+const CONFIRM_INFO: &[u8] = b"airoi group confirm v1";
+const SENDER_INFO: &[u8] = b"airoi group sender v1";
+
+fn kdf(salt: Option<&[u8]>, ikm: &[u8], info: &[&[u8]]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    Hkdf::<Sha256>::new(salt, ikm)
+        .expand(&info.concat(), &mut out)
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    out
+}
+
+fn crypto_error(e: impl std::fmt::Display) -> AiroiError {
+    AiroiError::Protocol(format!("group message could not be decrypted: {}", e))
+}
+
+// Tree math on the array representation: leaf `i` is node `2i`, parents sit in between, and the
+// width (number of leaves) is a power of two, so the root is node `width - 1`.
+
+fn level(x: usize) -> u32 {
+    x.trailing_ones()
+}
+
+fn left(x: usize) -> usize {
+    x ^ (1 << (level(x) - 1))
+}
+
+fn right(x: usize) -> usize {
+    x ^ (3 << (level(x) - 1))
+}
+
+fn parent(x: usize) -> usize {
+    let k = level(x);
+    let b = (x >> (k + 1)) & 1;
+    (x | (1 << k)) ^ (b << (k + 1))
+}
+
+fn sibling(x: usize) -> usize {
+    let p = parent(x);
+    if x < p { right(p) } else { left(p) }
+}
+
+/// Nodes from the parent of `x` up to the root
+fn direct_path(x: usize, width: usize) -> Vec<usize> {
+    let mut path = vec![];
+    let mut x = x;
+    while x != width - 1 {
+        x = parent(x);
+        path.push(x);
+    }
+    path
+}
+
+fn public_key(secret: &[u8; 32]) -> [u8; 32] {
+    x25519(*secret, X25519_BASEPOINT_BYTES)
+}
+
+/// Public state of the tree, the same for every member
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PublicTree {
+    /// Public key of every node, leaves at even indices; `None` for blank nodes
+    pub nodes: Vec<Option<[u8; 32]>>,
+    /// Ed25519 key (base58) of the member at every leaf
+    pub leaves: Vec<Option<String>>,
+}
+
+impl PublicTree {
+    fn new() -> PublicTree {
+        PublicTree { nodes: vec![None], leaves: vec![None] }
+    }
+
+    fn width(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn leaf_of(&self, member: &str) -> Option<usize> {
+        self.leaves.iter().position(|leaf| leaf.as_deref() == Some(member))
+    }
+
+    /// Non-blank nodes covering the subtree under `x`
+    fn resolution(&self, x: usize) -> Vec<usize> {
+        match self.nodes[x] {
+            Some(_) => vec![x],
+            None if level(x) == 0 => vec![],
+            None => [self.resolution(left(x)), self.resolution(right(x))].concat(),
+        }
+    }
+
+    fn blank_path(&mut self, leaf: usize) {
+        for node in direct_path(2 * leaf, self.width()) {
+            self.nodes[node] = None;
+        }
+    }
+
+    /// Puts a member at the first free leaf, doubling the tree if there is none
+    fn add(&mut self, member: &str, key: [u8; 32]) -> usize {
+        let leaf = match self.leaves.iter().position(Option::is_none) {
+            Some(leaf) => leaf,
+            None => {
+                // the old tree becomes the left subtree of the new root, indices stay the same
+                let width = self.width();
+                self.nodes.resize(4 * width - 1, None);
+                self.leaves.resize(2 * width, None);
+                width
+            }
+        };
+        self.leaves[leaf] = Some(member.to_string());
+        self.nodes[2 * leaf] = Some(key);
+        self.blank_path(leaf);
+        leaf
+    }
+
+    fn remove(&mut self, leaf: usize) {
+        self.leaves[leaf] = None;
+        self.nodes[2 * leaf] = None;
+        self.blank_path(leaf);
+    }
+
+    fn hash(&self) -> Result<[u8; 32]> {
+        Ok(Sha256::digest(serde_json::to_vec(self)?).into())
+    }
+}
+
+/// A secret encrypted to an X25519 key with an ephemeral DH, HKDF and ChaCha20-Poly1305
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sealed {
+    pub ephemeral: [u8; 32],
+    /// Base64
+    pub ciphertext: String,
+}
+
+fn seal_cipher(dh: [u8; 32], ephemeral: &[u8; 32], recipient: &[u8; 32]) -> ChaCha20Poly1305 {
+    let mut key = kdf(None, &dh, &[SEAL_INFO, ephemeral, recipient]);
+    let cipher = ChaCha20Poly1305::new_from_slice(&key).expect("32 byte key");
+    key.zeroize();
+    cipher
+}
+
+fn seal_to(recipient: &[u8; 32], plaintext: &[u8], aad: &[u8]) -> Result<Sealed> {
+    let mut secret = generate_secret()?;
+    let ephemeral = public_key(&secret);
+    let cipher = seal_cipher(x25519(secret, *recipient), &ephemeral, recipient);
+    secret.zeroize();
+    // every key encrypts a single message, so a zero nonce is fine
+    let ciphertext = cipher.encrypt(&[0u8; 12].into(), Payload { msg: plaintext, aad })
+        .map_err(|e| AiroiError::Protocol(format!("group encryption failed: {}", e)))?;
+    Ok(Sealed { ephemeral, ciphertext: general_purpose::STANDARD.encode(ciphertext) })
+}
+
+fn open_sealed(secret: &[u8; 32], sealed: &Sealed, aad: &[u8]) -> Result<[u8; 32]> {
+    let cipher = seal_cipher(x25519(*secret, sealed.ephemeral), &sealed.ephemeral, &public_key(secret));
+    let ciphertext = general_purpose::STANDARD.decode(&sealed.ciphertext)?;
+    let plaintext = cipher.decrypt(&[0u8; 12].into(), Payload { msg: &ciphertext, aad }).map_err(crypto_error)?;
+    plaintext.as_slice().try_into().map_err(|_| crypto_error("sealed secret has the wrong length"))
+}
+
+/// A path secret of the committer, encrypted to a node in the resolution of its copath
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PathSecret {
+    /// Node on the committer's direct path the secret belongs to
+    pub node: u32,
+    /// Node whose key it is encrypted to
+    pub recipient: u32,
+    pub sealed: Sealed,
+}
+
+/// The joiner secret of an epoch, encrypted to an added member's identity key
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Welcome {
+    pub member: String,
+    pub sealed: Sealed,
+}
+
+/// Moves a group to its next epoch; sent inside the signed `GroupUpdate`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TreeCommit {
+    pub epoch: u64,
+    /// Leaf of the committer
+    pub committer: u32,
+    /// The tree after the commit
+    pub tree: PublicTree,
+    pub path: Vec<PathSecret>,
+    pub welcomes: Vec<Welcome>,
+    /// Proves the committer derived the same epoch secret
+    pub confirmation: [u8; 32],
+}
+
+/// A message encrypted under a group's epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GroupCiphertext {
+    pub epoch: u64,
+    /// Leaf of the sender
+    pub leaf: u32,
+    pub generation: u32,
+    /// Base64
+    pub ciphertext: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    leaf: u32,
+    generation: u32,
+    key: [u8; 32],
+}
+
+/// Message keys of one epoch
+#[derive(Clone, Serialize, Deserialize)]
+struct EpochKeys {
+    epoch: u64,
+    application: [u8; 32],
+    /// Next generation and chain key of every sender that has used its chain
+    chains: HashMap<u32, (u32, [u8; 32])>,
+    skipped: VecDeque<SkippedKey>,
+}
+
+impl Drop for EpochKeys {
+    fn drop(&mut self) {
+        self.application.zeroize();
+        for (_, chain) in self.chains.values_mut() {
+            chain.zeroize();
+        }
+        for skipped in self.skipped.iter_mut() {
+            skipped.key.zeroize();
+        }
+    }
+}
+
+impl EpochKeys {
+    fn new(epoch: u64, epoch_secret: &[u8; 32]) -> EpochKeys {
+        EpochKeys {
+            epoch,
+            application: kdf(None, epoch_secret, &[APPLICATION_INFO]),
+            chains: HashMap::new(),
+            skipped: VecDeque::new(),
+        }
+    }
+
+    fn chain(&self, leaf: u32) -> (u32, [u8; 32]) {
+        self.chains.get(&leaf).copied()
+            .unwrap_or_else(|| (0, kdf(None, &self.application, &[SENDER_INFO, &leaf.to_be_bytes()])))
+    }
+
+    /// Key of the sender's next message
+    fn next_key(&mut self, leaf: u32) -> (u32, [u8; 32]) {
+        let (generation, chain) = self.chain(leaf);
+        let (next, key) = kdf_chain(&chain);
+        self.chains.insert(leaf, (generation + 1, next));
+        (generation, key)
+    }
+
+    /// Key of a received message, each key is handed out once
+    fn key(&mut self, leaf: u32, generation: u32) -> Result<[u8; 32]> {
+        if let Some(index) = self.skipped.iter().position(|s| s.leaf == leaf && s.generation == generation) {
+            return Ok(self.skipped.remove(index).unwrap().key);
+        }
+        let (next, _) = self.chain(leaf);
+        if generation < next {
+            return Err(crypto_error("message key already used"));
+        }
+        if generation - next > MAX_GENERATION_SKIP {
+            return Err(crypto_error(format!("more than {} skipped messages", MAX_GENERATION_SKIP)));
+        }
+        loop {
+            let (current, key) = self.next_key(leaf);
+            if current == generation {
+                break Ok(key);
+            }
+            self.skipped.push_back(SkippedKey { leaf, generation: current, key });
+            if self.skipped.len() > MAX_SKIPPED_KEYS {
+                self.skipped.pop_front();
+            }
+        }
+    }
+}
+
+fn message_aad(group_id: &str, epoch: u64, leaf: u32, generation: u32) -> Vec<u8> {
+    [group_id.as_bytes(), &epoch.to_be_bytes(), &leaf.to_be_bytes(), &generation.to_be_bytes()].concat()
+}
+
+/// Group id, epoch and tree hash; every epoch secret is bound to it
+fn group_context(group_id: &str, epoch: u64, tree: &PublicTree) -> Result<Vec<u8>> {
+    Ok([group_id.as_bytes(), &epoch.to_be_bytes(), &tree.hash()?].concat())
+}
+
+/// What protects secrets sealed within a commit to that commit
+fn commit_aad(group_id: &str, epoch: u64) -> Vec<u8> {
+    [group_id.as_bytes(), &epoch.to_be_bytes()].concat()
+}
+
+/// Our key state in one group
+#[derive(Clone, Serialize, Deserialize)]
+struct GroupKeyState {
+    leaf: u32,
+    tree: PublicTree,
+    /// Secret keys of the nodes on our path we know
+    secrets: HashMap<u32, [u8; 32]>,
+    init_secret: [u8; 32],
+    current: EpochKeys,
+    /// Messages of the previous epoch may still be on their way
+    previous: Option<EpochKeys>,
+}
+
+impl Drop for GroupKeyState {
+    fn drop(&mut self) {
+        self.init_secret.zeroize();
+        for secret in self.secrets.values_mut() {
+            secret.zeroize();
+        }
+    }
+}
+
+/// Epoch keys and state following a joiner secret; returns the state and confirmation tag
+fn enter_epoch(
+    joiner: [u8; 32],
+    context: &[u8],
+    epoch: u64,
+    leaf: usize,
+    tree: PublicTree,
+    secrets: HashMap<u32, [u8; 32]>,
+    previous: Option<EpochKeys>,
+) -> (GroupKeyState, [u8; 32]) {
+    let mut epoch_secret = kdf(None, &joiner, &[EPOCH_INFO, context]);
+    let confirmation = kdf(None, &epoch_secret, &[CONFIRM_INFO, context]);
+    let state = GroupKeyState {
+        leaf: leaf as u32,
+        tree,
+        secrets,
+        init_secret: kdf(None, &epoch_secret, &[INIT_INFO]),
+        current: EpochKeys::new(epoch, &epoch_secret),
+        previous,
+    };
+    epoch_secret.zeroize();
+    (state, confirmation)
+}
+
+fn member_key(member: &str) -> Result<[u8; 32]> {
+    bs58::decode(member).into_vec().ok()
+        .and_then(|key| try_ed25519_pk_to_x25519(&key))
+        .ok_or_else(|| AiroiError::Identity(format!("group member {} has an invalid key", member)))
+}
+
+fn own_identity_secret(keys: &KeyPair) -> Result<[u8; 32]> {
+    keys.private_key().x25519_key_raw().try_into()
+        .map_err(|_| AiroiError::Protocol("local x25519 key is malformed".to_string()))
+}
+
+/// Creates the commit that moves the group to the membership and epoch of `group`. Without a
+/// previous state the tree is built anew and every other member is welcomed.
+fn commit(keys: &KeyPair, state: Option<&GroupKeyState>, group: &Group) -> Result<(TreeCommit, GroupKeyState)> {
+    let own = keys.public_key().ed25519_key();
+    if !group.is_member(own) {
+        return Err(AiroiError::Identity(format!("the committer must stay in group {}", group.name)));
+    }
+    let (mut tree, mut init_secret) = match state {
+        Some(state) => (state.tree.clone(), state.init_secret),
+        None => (PublicTree::new(), generate_secret()?),
+    };
+    for leaf in 0..tree.width() {
+        if tree.leaves[leaf].as_deref().is_some_and(|member| !group.is_member(member)) {
+            tree.remove(leaf);
+        }
+    }
+    let mut added = vec![];
+    for member in std::iter::once(own).chain(group.members.iter().map(String::as_str)) {
+        if tree.leaf_of(member).is_none() {
+            let key = member_key(member)?;
+            tree.add(member, key);
+            if member != own {
+                added.push((member.to_string(), key));
+            }
+        }
+    }
+    let leaf = tree.leaf_of(own).expect("committer was added");
+    let width = tree.width();
+
+    // fresh leaf and path, each path secret derived from the one below
+    let mut path_secret = generate_secret()?;
+    let mut secrets = HashMap::new();
+    let mut path_secrets = vec![];
+    let mut node_secret = kdf(None, &path_secret, &[NODE_INFO]);
+    tree.nodes[2 * leaf] = Some(public_key(&node_secret));
+    secrets.insert(2 * leaf as u32, node_secret);
+    for node in direct_path(2 * leaf, width) {
+        path_secret = kdf(None, &path_secret, &[PATH_INFO]);
+        node_secret = kdf(None, &path_secret, &[NODE_INFO]);
+        tree.nodes[node] = Some(public_key(&node_secret));
+        secrets.insert(node as u32, node_secret);
+        path_secrets.push((node, path_secret));
+    }
+    let commit_secret = kdf(None, &path_secret, &[PATH_INFO]);
+    path_secret.zeroize();
+
+    let aad = commit_aad(&group.id, group.epoch);
+    let mut path = vec![];
+    let mut below = 2 * leaf;
+    for (node, secret) in &mut path_secrets {
+        for recipient in tree.resolution(sibling(below)) {
+            let key = tree.nodes[recipient].expect("resolution holds non-blank nodes");
+            path.push(PathSecret { node: *node as u32, recipient: recipient as u32, sealed: seal_to(&key, secret, &aad)? });
+        }
+        secret.zeroize();
+        below = *node;
+    }
+
+    let mut joiner = kdf(Some(&init_secret), &commit_secret, &[JOINER_INFO]);
+    init_secret.zeroize();
+    let mut welcomes = vec![];
+    for (member, key) in added {
+        welcomes.push(Welcome { member, sealed: seal_to(&key, &joiner, &aad)? });
+    }
+    let context = group_context(&group.id, group.epoch, &tree)?;
+    let previous = state.map(|s| s.current.clone());
+    let (state, confirmation) = enter_epoch(joiner, &context, group.epoch, leaf, tree.clone(), secrets, previous);
+    joiner.zeroize();
+    Ok((TreeCommit { epoch: group.epoch, committer: leaf as u32, tree, path, welcomes, confirmation }, state))
+}
+
+/// Applies a commit by the member `committer` (Ed25519, base58) that moves us into `group`
+fn process(keys: &KeyPair, state: Option<&GroupKeyState>, group: &Group, committer: &str, commit: &TreeCommit) -> Result<GroupKeyState> {
+    let invalid = |reason: &str| Err(AiroiError::Protocol(format!("commit for group {} refused: {}", group.name, reason)));
+    let tree = &commit.tree;
+    let width = tree.width();
+    if commit.epoch != group.epoch || !width.is_power_of_two() || tree.nodes.len() != 2 * width - 1 {
+        return invalid("malformed tree");
+    }
+    let mut leaves: Vec<&str> = tree.leaves.iter().flatten().map(String::as_str).collect();
+    let mut members: Vec<&str> = group.members.iter().map(String::as_str).collect();
+    leaves.sort();
+    members.sort();
+    if leaves != members {
+        return invalid("tree does not match the members");
+    }
+    if tree.leaves.get(commit.committer as usize).and_then(Option::as_deref) != Some(committer) {
+        return invalid("not sent by the committer");
+    }
+    let own = keys.public_key().ed25519_key();
+    let Some(leaf) = tree.leaf_of(own) else {
+        return invalid("we are not a member");
+    };
+    if leaf == commit.committer as usize {
+        return invalid("we did not commit this");
+    }
+
+    // secrets we hold for nodes that still carry the same key, our identity key among them
+    let mut known: HashMap<u32, [u8; 32]> = state.map(|s| s.secrets.clone()).unwrap_or_default();
+    known.insert(2 * leaf as u32, own_identity_secret(keys)?);
+    known.retain(|node, secret| tree.nodes.get(*node as usize).copied().flatten() == Some(public_key(secret)));
+
+    let aad = commit_aad(&group.id, group.epoch);
+    let committer_path = direct_path(2 * commit.committer as usize, width);
+    let mut secrets = known.clone();
+    let mut commit_secret = None;
+    if let Some(encrypted) = commit.path.iter().find(|p| known.contains_key(&p.recipient) && committer_path.contains(&(p.node as usize))) {
+        let mut path_secret = open_sealed(&known[&encrypted.recipient], &encrypted.sealed, &aad)?;
+        let start = committer_path.iter().position(|n| *n == encrypted.node as usize).unwrap();
+        for node in &committer_path[start..] {
+            let node_secret = kdf(None, &path_secret, &[NODE_INFO]);
+            if tree.nodes[*node] != Some(public_key(&node_secret)) {
+                return invalid("path secret does not match the tree");
+            }
+            secrets.insert(*node as u32, node_secret);
+            path_secret = kdf(None, &path_secret, &[PATH_INFO]);
+        }
+        commit_secret = Some(path_secret);
+    }
+
+    let joiner = match commit.welcomes.iter().find(|w| w.member == own) {
+        Some(welcome) => open_sealed(&own_identity_secret(keys)?, &welcome.sealed, &aad)?,
+        None => match (state, commit_secret) {
+            (Some(state), Some(commit_secret)) => kdf(Some(&state.init_secret), &commit_secret, &[JOINER_INFO]),
+            (None, _) => return invalid("no welcome for us"),
+            (_, None) => return invalid("no path secret for us"),
+        },
+    };
+    let context = group_context(&group.id, group.epoch, tree)?;
+    let previous = state.map(|s| s.current.clone());
+    let (state, confirmation) = enter_epoch(joiner, &context, group.epoch, leaf, tree.clone(), secrets, previous);
+    if confirmation != commit.confirmation {
+        return invalid("confirmation tag mismatch");
+    }
+    Ok(state)
+}
+
+impl GroupKeyState {
+    fn encrypt(&mut self, group_id: &str, plaintext: &[u8]) -> Result<GroupCiphertext> {
+        let (generation, mut key) = self.current.next_key(self.leaf);
+        let (cipher, nonce) = message_cipher(&key);
+        key.zeroize();
+        let aad = message_aad(group_id, self.current.epoch, self.leaf, generation);
+        let ciphertext = cipher.encrypt(&nonce.into(), Payload { msg: plaintext, aad: &aad })
+            .map_err(|e| AiroiError::Protocol(format!("group encryption failed: {}", e)))?;
+        Ok(GroupCiphertext {
+            epoch: self.current.epoch,
+            leaf: self.leaf,
+            generation,
+            ciphertext: general_purpose::STANDARD.encode(ciphertext),
+        })
+    }
+
+    /// Decrypts a message of the current or previous epoch from the member `sender`
+    fn decrypt(&mut self, group_id: &str, sender: &str, message: &GroupCiphertext) -> Result<Vec<u8>> {
+        let epoch = match &mut self.previous {
+            Some(previous) if previous.epoch == message.epoch => previous,
+            _ if self.current.epoch == message.epoch => &mut self.current,
+            _ => return Err(crypto_error(format!("unknown epoch {}", message.epoch))),
+        };
+        // leaves are only freed by removals, which advance the epoch, so the current tree
+        // still names the sender of a previous epoch's message unless they were removed
+        if self.tree.leaves.get(message.leaf as usize).and_then(Option::as_deref) != Some(sender) {
+            return Err(crypto_error("sender does not sit at that leaf"));
+        }
+        let mut key = epoch.key(message.leaf, message.generation)?;
+        let (cipher, nonce) = message_cipher(&key);
+        key.zeroize();
+        let aad = message_aad(group_id, message.epoch, message.leaf, message.generation);
+        let ciphertext = general_purpose::STANDARD.decode(&message.ciphertext)?;
+        cipher.decrypt(&nonce.into(), Payload { msg: &ciphertext, aad: &aad }).map_err(crypto_error)
+    }
+}
+
+/// Key state of every group we are in, persisted encrypted
+pub struct GroupKeyStore {
+    path: PathBuf,
+    key: [u8; 32],
+}
+
+impl GroupKeyStore {
+    pub fn open(keys: &KeyPair) -> GroupKeyStore {
+        GroupKeyStore::at(get_airoi_dir().join("group_keys.json"), keys)
+    }
+
+    pub fn at(path: PathBuf, keys: &KeyPair) -> GroupKeyStore {
+        GroupKeyStore { path, key: storage_key(keys, "group keys") }
+    }
+
+    /// Our epoch of the group, `None` if we hold no keys for it
+    pub fn epoch(&self, group_id: &str) -> Result<Option<u64>> {
+        let _guard = lock_sealed(&self.path)?;
+        Ok(self.load()?.get(group_id).map(|state| state.current.epoch))
+    }
+
+    /// Commits the membership and epoch of `group` and moves our state along
+    pub fn commit(&self, keys: &KeyPair, group: &Group) -> Result<TreeCommit> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut states = self.load()?;
+        let (commit, state) = commit(keys, states.get(&group.id), group)?;
+        states.insert(group.id.clone(), state);
+        self.store(&states)?;
+        Ok(commit)
+    }
+
+    /// Applies a commit the member `committer` sent for `group`
+    pub fn process(&self, keys: &KeyPair, group: &Group, committer: &str, commit: &TreeCommit) -> Result<()> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut states = self.load()?;
+        let state = process(keys, states.get(&group.id), group, committer, commit)?;
+        states.insert(group.id.clone(), state);
+        self.store(&states)
+    }
+
+    /// Deletes our keys of a group we left or were removed from
+    pub fn forget(&self, group_id: &str) -> Result<()> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut states = self.load()?;
+        states.remove(group_id);
+        self.store(&states)
+    }
+
+    pub fn encrypt(&self, group_id: &str, plaintext: &[u8]) -> Result<GroupCiphertext> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut states = self.load()?;
+        let state = states.get_mut(group_id).ok_or_else(|| no_keys(group_id))?;
+        let message = state.encrypt(group_id, plaintext)?;
+        self.store(&states)?;
+        Ok(message)
+    }
+
+    /// Decrypts a message from the member `sender`. The state only changes if it is authentic.
+    pub fn decrypt(&self, group_id: &str, sender: &str, message: &GroupCiphertext) -> Result<Vec<u8>> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut states = self.load()?;
+        let state = states.get_mut(group_id).ok_or_else(|| no_keys(group_id))?;
+        let mut next = state.clone();
+        let plaintext = next.decrypt(group_id, sender, message)?;
+        *state = next;
+        self.store(&states)?;
+        Ok(plaintext)
+    }
+
+    fn load(&self) -> Result<HashMap<String, GroupKeyState>> {
+        match read_sealed(&self.path, &self.key)? {
+            Some(plain_text) => Ok(serde_json::from_slice(&plain_text)?),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn store(&self, states: &HashMap<String, GroupKeyState>) -> Result<()> {
+        write_sealed(&self.path, &self.key, &serde_json::to_vec(states)?)
+    }
+}
+
+fn no_keys(group_id: &str) -> AiroiError {
+    AiroiError::Protocol(format!("no keys for group {}, ask an admin to add you again", group_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::key_gen::generate_key_pair;
+
+    fn key(keys: &KeyPair) -> String {
+        keys.public_key().ed25519_key().to_string()
+    }
+
+    #[test]
+    fn test_tree_math() {
+        assert_eq!(direct_path(0, 4), vec![1, 3]);
+        assert_eq!(direct_path(6, 4), vec![5, 3]);
+        assert_eq!((sibling(0), sibling(1), sibling(5)), (2, 5, 1));
+        assert!(direct_path(0, 1).is_empty());
+    }
+
+    #[test]
+    fn test_members_agree_across_changes() {
+        let members: Vec<KeyPair> = (0..4).map(|_| generate_key_pair().unwrap()).collect();
+        let [admin, bob, carol, dave] = [&members[0], &members[1], &members[2], &members[3]];
+        let mut group = Group::new("friends", &key(admin), vec![key(bob), key(carol)]);
+
+        let (created, mut admin_state) = commit(admin, None, &group).unwrap();
+        let mut bob_state = process(bob, None, &group, &key(admin), &created).unwrap();
+        let mut carol_state = process(carol, None, &group, &key(admin), &created).unwrap();
+
+        let message = bob_state.encrypt(&group.id, b"hello").unwrap();
+        assert_eq!(carol_state.decrypt(&group.id, &key(bob), &message).unwrap(), b"hello");
+        assert_eq!(admin_state.decrypt(&group.id, &key(bob), &message).unwrap(), b"hello");
+        // a message key is used once, and only the member at the leaf may use its chain
+        assert!(admin_state.decrypt(&group.id, &key(bob), &message).is_err());
+        let late = carol_state.encrypt(&group.id, b"late").unwrap();
+        assert!(admin_state.decrypt(&group.id, &key(bob), &late).is_err());
+
+        let delayed = bob_state.encrypt(&group.id, b"delayed").unwrap();
+
+        // dave joins, carol leaves
+        group.add_member(&key(dave));
+        group.remove_member(&key(carol));
+        group.epoch += 1;
+        let (changed, next_admin) = commit(admin, Some(&admin_state), &group).unwrap();
+        admin_state = next_admin;
+        bob_state = process(bob, Some(&bob_state), &group, &key(admin), &changed).unwrap();
+        let mut dave_state = process(dave, None, &group, &key(admin), &changed).unwrap();
+        assert!(process(carol, Some(&carol_state), &group, &key(admin), &changed).is_err());
+
+        let message = dave_state.encrypt(&group.id, b"hi all").unwrap();
+        assert_eq!(bob_state.decrypt(&group.id, &key(dave), &message).unwrap(), b"hi all");
+        assert_eq!(admin_state.decrypt(&group.id, &key(dave), &message).unwrap(), b"hi all");
+        assert!(carol_state.decrypt(&group.id, &key(dave), &message).is_err());
+        // messages of the previous epoch still open, unless their sender was removed
+        assert_eq!(admin_state.decrypt(&group.id, &key(bob), &delayed).unwrap(), b"delayed");
+        assert!(bob_state.decrypt(&group.id, &key(carol), &late).is_err());
+
+        // a commit with a forged tree is refused
+        let mut forged = changed.clone();
+        forged.tree.nodes[0] = Some([9; 32]);
+        assert!(process(bob, Some(&bob_state), &group, &key(admin), &forged).is_err());
+    }
+
+    #[test]
+    fn test_member_catches_up_on_missed_commits_in_order() {
+        let members: Vec<KeyPair> = (0..4).map(|_| generate_key_pair().unwrap()).collect();
+        let [admin, bob, carol, dave] = [&members[0], &members[1], &members[2], &members[3]];
+        let mut group = Group::new("friends", &key(admin), vec![key(bob), key(carol)]);
+        let (created, mut admin_state) = commit(admin, None, &group).unwrap();
+        let mut carol_state = process(carol, None, &group, &key(admin), &created).unwrap();
+
+        // carol is offline while dave joins and bob leaves, the commits wait in the admin's outbox
+        let mut missed = vec![];
+        group.add_member(&key(dave));
+        group.epoch += 1;
+        let (added, next) = commit(admin, Some(&admin_state), &group).unwrap();
+        missed.push((group.clone(), added));
+        group.remove_member(&key(bob));
+        group.epoch += 1;
+        let (removed, next) = commit(admin, Some(&next), &group).unwrap();
+        missed.push((group.clone(), removed));
+        admin_state = next;
+        let message = admin_state.encrypt(&group.id, b"where is carol?").unwrap();
+
+        // the latest commit alone does not help, the queued ones in order do
+        let (latest_group, latest) = &missed[1];
+        assert!(process(carol, Some(&carol_state), latest_group, &key(admin), latest).is_err());
+        for (state, changed) in &missed {
+            carol_state = process(carol, Some(&carol_state), state, &key(admin), changed).unwrap();
+        }
+        assert_eq!(carol_state.decrypt(&group.id, &key(admin), &message).unwrap(), b"where is carol?");
+    }
+}
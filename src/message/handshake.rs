@@ -0,0 +1,315 @@
+//! Noise handshakes for both sides of a connection.
+//!
+//! The first frame of every connection starts with one byte selecting the pattern:
+//! `PATTERN_XX` for contacts whose static key is unknown, `PATTERN_IK` when the initiator
+//! already knows the responder's static key. IK finishes in a single round trip and lets the
+//! initiator put its first envelope into the handshake payload. That payload is encrypted to
+//! the responder's static key but does not get forward secrecy from the initiator's ephemeral
+//! key alone, which is the usual IK trade-off.
+//!
+//! After the handshake both sides send a `Control::Identity` with their Ed25519 key and a
+//! signature over the handshake hash. The peer checks the signature and that the Noise static
+//! key is the X25519 form of that Ed25519 key, which binds the long-term identity to the session.
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use snow::{Builder, HandshakeState, TransportState};
+use snow::params::NoiseParams;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::key_gen::try_ed25519_pk_to_x25519;
+use crate::message::{read_frame, write_frame};
+use crate::message::control::Control;
+use crate::message::envelope::{Envelope, MessageKind};
+use crate::message::fragment::{read_payload, write_payload, Reassembler, NOISE_MAX_MSG_LEN};
+use crate::message::padding::PaddingPolicy;
+
+pub const NOISE_XX: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+pub const NOISE_IK: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+
+pub const PATTERN_XX: u8 = 0x01;
+pub const PATTERN_IK: u8 = 0x02;
+
+const IDENTITY_CONTEXT: &[u8] = b"airoi identity v1";
+const ROLE_INITIATOR: u8 = b'I';
+const ROLE_RESPONDER: u8 = b'R';
+
+/// Overhead of the first IK message: pattern byte, ephemeral key, encrypted static key and payload tag
+const IK_MSG1_OVERHEAD: usize = 1 + 32 + 32 + 16 + 16;
+
+pub struct Handshake {
+    pub transport: TransportState,
+    pub remote_static: Vec<u8>,
+    pub handshake_hash: Vec<u8>,
+    /// Payload the initiator put into the first IK message, if any
+    pub early_payload: Option<Vec<u8>>,
+}
+
+/// Whether a payload fits into the first IK handshake message
+pub fn fits_early(payload: &[u8]) -> bool {
+    payload.len() + IK_MSG1_OVERHEAD <= NOISE_MAX_MSG_LEN
+}
+
+pub(crate) fn build(pattern: &str, local_priv: &[u8], remote_static: Option<&[u8]>, initiator: bool) -> Result<HandshakeState> {
+    let params: NoiseParams = pattern.parse()?;
+    let mut builder = Builder::new(params).local_private_key(local_priv)?;
+    if let Some(remote_static) = remote_static {
+        builder = builder.remote_public_key(remote_static)?;
+    }
+    let noise = if initiator { builder.build_initiator()? } else { builder.build_responder()? };
+    Ok(noise)
+}
+
+fn finish(noise: HandshakeState, early_payload: Option<Vec<u8>>) -> Result<Handshake> {
+    let remote_static = noise
+        .get_remote_static()
+        .ok_or_else(|| AiroiError::RemoteStatic("handshake did not reveal remote static key".to_string()))?
+        .to_vec();
+    let handshake_hash = noise.get_handshake_hash().to_vec();
+    Ok(Handshake {
+        transport: noise.into_transport_mode()?,
+        remote_static,
+        handshake_hash,
+        early_payload,
+    })
+}
+
+fn identity_message(role: u8, handshake_hash: &[u8]) -> Vec<u8> {
+    let mut message = IDENTITY_CONTEXT.to_vec();
+    message.push(role);
+    message.extend_from_slice(handshake_hash);
+    message
+}
+
+/// Sends our identity proof and verifies the peer's. Returns the peer's proven Ed25519 key.
+pub async fn exchange_identity<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    handshake: &mut Handshake,
+    keys: &KeyPair,
+    initiator: bool,
+) -> Result<[u8; 32]> {
+    let (own_role, peer_role) = if initiator {
+        (ROLE_INITIATOR, ROLE_RESPONDER)
+    } else {
+        (ROLE_RESPONDER, ROLE_INITIATOR)
+    };
+
+    let seed: [u8; 32] = keys.private_key().ed25519_key_raw().try_into()
+        .map_err(|_| AiroiError::Identity("local ed25519 key is malformed".to_string()))?;
+    let signing_key = SigningKey::from_bytes(&seed);
+    let proof = Control::Identity {
+        ed25519: signing_key.verifying_key().to_bytes(),
+        signature: signing_key.sign(&identity_message(own_role, &handshake.handshake_hash)).to_bytes(),
+    };
+    write_payload(stream, &mut handshake.transport, &proof.into_envelope().encode(), PaddingPolicy::default()).await?;
+
+    let mut reassembler = Reassembler::new(NOISE_MAX_MSG_LEN);
+    let payload = read_payload(stream, &mut handshake.transport, &mut reassembler).await?;
+    let envelope = Envelope::decode(&payload)?;
+    if envelope.kind != MessageKind::Control {
+        return Err(AiroiError::Identity("peer did not prove its identity".to_string()));
+    }
+    let Control::Identity { ed25519, signature } = Control::decode(&envelope.body)? else {
+        return Err(AiroiError::Identity("peer did not prove its identity".to_string()));
+    };
+    verify_identity(&ed25519, &signature, &handshake.remote_static, peer_role, &handshake.handshake_hash)?;
+    Ok(ed25519)
+}
+
+fn verify_identity(
+    ed25519: &[u8; 32],
+    signature: &[u8; 64],
+    remote_static: &[u8],
+    role: u8,
+    handshake_hash: &[u8],
+) -> Result<()> {
+    let verifying_key = VerifyingKey::from_bytes(ed25519)
+        .map_err(|_| AiroiError::Identity("peer sent an invalid ed25519 key".to_string()))?;
+    verifying_key
+        .verify(&identity_message(role, handshake_hash), &Signature::from_bytes(signature))
+        .map_err(|_| AiroiError::Identity("identity signature does not verify".to_string()))?;
+    if try_ed25519_pk_to_x25519(ed25519).as_ref().map(|x| x.as_slice()) != Some(remote_static) {
+        return Err(AiroiError::Identity("ed25519 key does not match the noise static key".to_string()));
+    }
+    Ok(())
+}
+
+/// IK handshake to a responder whose static key we know, optionally carrying a first payload
+pub async fn initiate_ik<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: &mut S,
+    local_priv: &[u8],
+    remote_static: &[u8],
+    early_payload: Option<&[u8]>,
+) -> Result<Handshake> {
+    let mut noise = build(NOISE_IK, local_priv, Some(remote_static), true)?;
+    let mut buf = vec![0u8; NOISE_MAX_MSG_LEN];
+
+    // msg1: -> e, es, s, ss (+ early payload)
+    let mut msg1 = vec![PATTERN_IK];
+    let len1 = noise.write_message(early_payload.unwrap_or(&[]), &mut buf)?;
+    msg1.extend_from_slice(&buf[..len1]);
+    write_frame(stream, &msg1).await?;
+
+    // msg2: <- e, ee, se
+    let msg2 = read_frame(stream).await?;
+    noise.read_message(&msg2, &mut buf)?;
+
+    finish(noise, None)
+}
+
+/// XX handshake, used when the responder's static key is unknown or IK failed
+pub async fn initiate_xx<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, local_priv: &[u8]) -> Result<Handshake> {
+    let mut noise = build(NOISE_XX, local_priv, None, true)?;
+    let mut buf = vec![0u8; NOISE_MAX_MSG_LEN];
+
+    // msg1
+    let mut msg1 = vec![PATTERN_XX];
+    let len1 = noise.write_message(&[], &mut buf)?;
+    msg1.extend_from_slice(&buf[..len1]);
+    write_frame(stream, &msg1).await?;
+
+    // msg2
+    let msg2 = read_frame(stream).await?;
+    noise.read_message(&msg2, &mut buf)?;
+
+    // msg3
+    let len3 = noise.write_message(&[], &mut buf)?;
+    write_frame(stream, &buf[..len3]).await?;
+
+    finish(noise, None)
+}
+
+/// Responder side, accepting either pattern depending on the leading byte
+pub async fn respond<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut S, local_priv: &[u8]) -> Result<Handshake> {
+    let msg1 = read_frame(stream).await?;
+    let (pattern, msg1) = msg1
+        .split_first()
+        .ok_or_else(|| AiroiError::Protocol("empty handshake message".to_string()))?;
+    let mut buf = vec![0u8; NOISE_MAX_MSG_LEN];
+
+    match *pattern {
+        PATTERN_IK => {
+            let mut noise = build(NOISE_IK, local_priv, None, false)?;
+            let len = noise.read_message(msg1, &mut buf)?;
+            let early_payload = (len > 0).then(|| buf[..len].to_vec());
+
+            let len2 = noise.write_message(&[], &mut buf)?;
+            write_frame(stream, &buf[..len2]).await?;
+
+            finish(noise, early_payload)
+        }
+        PATTERN_XX => {
+            let mut noise = build(NOISE_XX, local_priv, None, false)?;
+            noise.read_message(msg1, &mut buf)?;
+
+            let len2 = noise.write_message(&[], &mut buf)?;
+            write_frame(stream, &buf[..len2]).await?;
+
+            let msg3 = read_frame(stream).await?;
+            noise.read_message(&msg3, &mut buf)?;
+
+            finish(noise, None)
+        }
+        other => Err(AiroiError::Protocol(format!("unknown handshake pattern {}", other))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::key_gen::generate_key_pair;
+
+    #[tokio::test]
+    async fn test_ik_handshake_carries_early_payload() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(NOISE_MAX_MSG_LEN * 2);
+
+        let bob_priv = bob.private_key().x25519_key_raw().to_vec();
+        let responder = tokio::spawn(async move { respond(&mut b, &bob_priv).await.unwrap() });
+
+        let initiator = initiate_ik(
+            &mut a,
+            alice.private_key().x25519_key_raw(),
+            bob.public_key().x25519_key_raw(),
+            Some(b"first"),
+        ).await.unwrap();
+        let responder = responder.await.unwrap();
+
+        assert_eq!(responder.early_payload.as_deref(), Some(&b"first"[..]));
+        assert_eq!(responder.remote_static, alice.public_key().x25519_key_raw());
+        assert_eq!(initiator.remote_static, bob.public_key().x25519_key_raw());
+    }
+
+    #[tokio::test]
+    async fn test_ik_handshake_fails_for_wrong_static_key() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let mallory = generate_key_pair().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(NOISE_MAX_MSG_LEN * 2);
+
+        let bob_priv = bob.private_key().x25519_key_raw().to_vec();
+        let responder = tokio::spawn(async move { respond(&mut b, &bob_priv).await });
+
+        let initiator = initiate_ik(
+            &mut a,
+            alice.private_key().x25519_key_raw(),
+            mallory.public_key().x25519_key_raw(),
+            None,
+        ).await;
+        assert!(responder.await.unwrap().is_err());
+        assert!(initiator.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_identity_exchange_proves_ed25519_key() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(NOISE_MAX_MSG_LEN * 2);
+
+        let bob_clone = bob.clone();
+        let responder = tokio::spawn(async move {
+            let mut handshake = respond(&mut b, bob_clone.private_key().x25519_key_raw()).await.unwrap();
+            exchange_identity(&mut b, &mut handshake, &bob_clone, false).await.unwrap()
+        });
+        let mut handshake = initiate_xx(&mut a, alice.private_key().x25519_key_raw()).await.unwrap();
+        let bob_ed = exchange_identity(&mut a, &mut handshake, &alice, true).await.unwrap();
+
+        assert_eq!(bob_ed, bob.public_key().ed25519_key_raw());
+        assert_eq!(responder.await.unwrap(), alice.public_key().ed25519_key_raw());
+    }
+
+    #[test]
+    fn test_identity_rejects_foreign_ed25519_key() {
+        let alice = generate_key_pair().unwrap();
+        let mallory = generate_key_pair().unwrap();
+        let hash = [9u8; 32];
+
+        // mallory signs correctly with her own key but claims alice's noise static key
+        let seed: [u8; 32] = mallory.private_key().ed25519_key_raw().try_into().unwrap();
+        let signing_key = SigningKey::from_bytes(&seed);
+        let signature = signing_key.sign(&identity_message(ROLE_INITIATOR, &hash)).to_bytes();
+        let ed25519 = signing_key.verifying_key().to_bytes();
+
+        let result = verify_identity(&ed25519, &signature, alice.public_key().x25519_key_raw(), ROLE_INITIATOR, &hash);
+        assert!(matches!(result, Err(AiroiError::Identity(_))));
+        // a signature for the other role must not be accepted either
+        let result = verify_identity(&ed25519, &signature, mallory.public_key().x25519_key_raw(), ROLE_RESPONDER, &hash);
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_xx_handshake() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(NOISE_MAX_MSG_LEN * 2);
+
+        let bob_priv = bob.private_key().x25519_key_raw().to_vec();
+        let responder = tokio::spawn(async move { respond(&mut b, &bob_priv).await.unwrap() });
+        let initiator = initiate_xx(&mut a, alice.private_key().x25519_key_raw()).await.unwrap();
+        let responder = responder.await.unwrap();
+
+        assert!(responder.early_payload.is_none());
+        assert_eq!(responder.remote_static, alice.public_key().x25519_key_raw());
+        assert_eq!(initiator.remote_static, bob.public_key().x25519_key_raw());
+    }
+}
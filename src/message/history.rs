@@ -0,0 +1,356 @@
+//! Local archive of sent and received text messages.
+//!
+//! Every text and group text is kept in `history.json` in the airoi directory, encrypted under a
+//! key derived from the local key pair, together with its timestamps and how far its delivery
+//! got. Messages are recorded where they are shown or sent, and their state follows receipts and
+//! the outbox. Disappearing messages are hidden once they expire and deleted by the next `prune`,
+//! which also drops messages older than the local retention (see `retention`).
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::keys::KeyPair;
+use crate::keys::contacts::Contact;
+use crate::keys::groups::Group;
+use crate::message::Message;
+use crate::message::envelope::{Envelope, MessageId};
+use crate::message::receipt::DeliveryStatus;
+use crate::storage::sealed::{lock_sealed, read_sealed, storage_key, write_sealed};
+use crate::util::get_airoi_dir;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+impl std::str::FromStr for Direction {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "sent" => Ok(Direction::Sent),
+            "received" => Ok(Direction::Received),
+            other => Err(format!("unknown direction '{}', expected sent or received", other)),
+        }
+    }
+}
+
+/// How far a message got
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryState {
+    Received,
+    Delivered,
+    Read,
+    /// Waiting in the outbox
+    Queued,
+    /// Left at the contact's relay
+    Relayed,
+    /// Sent but not acknowledged in time
+    Unconfirmed,
+    /// Refused by the receiver, dropped from the outbox or never sent
+    Failed,
+}
+
+impl From<DeliveryStatus> for DeliveryState {
+    fn from(status: DeliveryStatus) -> Self {
+        match status {
+            DeliveryStatus::Delivered => DeliveryState::Delivered,
+            DeliveryStatus::Read => DeliveryState::Read,
+            DeliveryStatus::TimedOut => DeliveryState::Unconfirmed,
+            DeliveryStatus::Rejected => DeliveryState::Failed,
+        }
+    }
+}
+
+impl std::fmt::Display for DeliveryState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DeliveryState::Received => "received",
+            DeliveryState::Delivered => "delivered",
+            DeliveryState::Read => "read",
+            DeliveryState::Queued => "queued",
+            DeliveryState::Relayed => "left at relay",
+            DeliveryState::Unconfirmed => "unconfirmed",
+            DeliveryState::Failed => "failed",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    id: [u8; 16],
+    pub direction: Direction,
+    /// Name of the contact at the time, or of the group for our own group messages
+    pub contact: String,
+    /// X25519 fingerprint of the contact, empty for our own group messages
+    pub fingerprint: String,
+    /// Id of the group the message went to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub text: String,
+    /// Unix milliseconds, the sender's timestamp
+    pub sent_at: i64,
+    /// Unix milliseconds, set for received messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub received_at: Option<i64>,
+    pub state: DeliveryState,
+    /// Unix milliseconds, when a disappearing message is deleted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<i64>,
+}
+
+impl HistoryEntry {
+    pub fn id(&self) -> MessageId {
+        MessageId(self.id)
+    }
+
+    fn is_expired(&self, now: i64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// When a message with the timer disappears, counting from `start` (Unix milliseconds)
+fn expiry(start: i64, expires_in: u32) -> Option<i64> {
+    (expires_in > 0).then(|| start + expires_in as i64 * 1000)
+}
+
+/// Whose messages to look at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conversation {
+    /// Direct messages with the contact with this X25519 fingerprint
+    Contact(String),
+    /// Messages in the group with this id
+    Group(String),
+}
+
+impl Conversation {
+    pub(crate) fn includes(&self, entry: &HistoryEntry) -> bool {
+        match self {
+            Conversation::Contact(fingerprint) => entry.group.is_none() && &entry.fingerprint == fingerprint,
+            Conversation::Group(id) => entry.group.as_ref() == Some(id),
+        }
+    }
+}
+
+pub struct History {
+    path: PathBuf,
+    key: [u8; 32],
+}
+
+impl History {
+    /// The history in the airoi directory
+    pub fn open(keys: &KeyPair) -> History {
+        History::at(get_airoi_dir().join("history.json"), keys)
+    }
+
+    pub fn at(path: PathBuf, keys: &KeyPair) -> History {
+        History { path, key: storage_key(keys, "history") }
+    }
+
+    /// Records a text envelope we sent to the contact
+    pub fn record_sent(&self, contact: &Contact, envelope: &Envelope, state: DeliveryState) -> Result<()> {
+        self.push(HistoryEntry {
+            id: envelope.id.0,
+            direction: Direction::Sent,
+            contact: contact.name.clone(),
+            fingerprint: contact.fingerprint_x().to_string(),
+            group: None,
+            text: envelope.body_text(),
+            sent_at: envelope.timestamp,
+            received_at: None,
+            state,
+            expires_at: expiry(envelope.timestamp, envelope.expires_in),
+        })
+    }
+
+    /// Records a text we sent to a group, disappearing with the group's timer
+    pub fn record_group_sent(&self, group: &Group, text: &str, state: DeliveryState) -> Result<()> {
+        let now = chrono::Utc::now().timestamp_millis();
+        self.push(HistoryEntry {
+            id: MessageId::random().0,
+            direction: Direction::Sent,
+            contact: group.name.clone(),
+            fingerprint: String::new(),
+            group: Some(group.id.clone()),
+            text: text.to_string(),
+            sent_at: now,
+            received_at: None,
+            state,
+            expires_at: expiry(now, group.disappearing.unwrap_or(0)),
+        })
+    }
+
+    /// Records a received text or group text, disappearing the message's timer after it arrived.
+    /// A message already recorded, such as a duplicate fetched from a relay, is kept once.
+    pub fn record_received(&self, message: &Message) -> Result<()> {
+        let millis = |rfc3339: &str| chrono::DateTime::parse_from_rfc3339(rfc3339)
+            .map(|time| time.timestamp_millis())
+            .unwrap_or_else(|_| chrono::Utc::now().timestamp_millis());
+        self.push(HistoryEntry {
+            id: message.id.0,
+            direction: Direction::Received,
+            contact: message.sender.name.clone(),
+            fingerprint: message.sender.fingerprint_x().to_string(),
+            group: message.group.as_ref().map(|group| group.id.clone()),
+            text: message.message.clone(),
+            sent_at: millis(&message.sent),
+            received_at: Some(millis(&message.received)),
+            state: DeliveryState::Received,
+            expires_at: expiry(millis(&message.received), message.expires_in),
+        })
+    }
+
+    /// Updates the state of a sent message. Returns `false` if it is not recorded.
+    pub fn set_state(&self, id: MessageId, state: DeliveryState) -> Result<bool> {
+        self.modify(|entries| {
+            let entry = entries.iter_mut().find(|e| e.direction == Direction::Sent && e.id() == id);
+            entry.map(|entry| entry.state = state).is_some()
+        })
+    }
+
+    /// Deletes the messages with this id. Returns `false` if there were none.
+    pub fn delete(&self, id: &str) -> Result<bool> {
+        self.modify(|entries| {
+            let before = entries.len();
+            entries.retain(|e| e.id().to_string() != id);
+            entries.len() != before
+        })
+    }
+
+    /// Every recorded message that has not expired, in the order they were recorded
+    pub fn entries(&self) -> Result<Vec<HistoryEntry>> {
+        let _guard = lock_sealed(&self.path)?;
+        let now = chrono::Utc::now().timestamp_millis();
+        Ok(self.load()?.into_iter().filter(|e| !e.is_expired(now)).collect())
+    }
+
+    /// Deletes expired messages, and messages sent before `oldest` (Unix milliseconds) if set.
+    /// Returns the number of messages deleted.
+    pub fn prune(&self, oldest: Option<i64>) -> Result<usize> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut deleted = 0;
+        self.modify(|entries| {
+            let before = entries.len();
+            entries.retain(|e| !e.is_expired(now) && oldest.is_none_or(|oldest| e.sent_at >= oldest));
+            deleted = before - entries.len();
+            deleted > 0
+        })?;
+        Ok(deleted)
+    }
+
+    /// When the next disappearing message expires, in Unix milliseconds
+    pub fn next_expiry(&self) -> Result<Option<i64>> {
+        let _guard = lock_sealed(&self.path)?;
+        Ok(self.load()?.iter().filter_map(|e| e.expires_at).min())
+    }
+
+    /// The last `limit` messages of the conversation sent at or after `since` (Unix milliseconds),
+    /// oldest first
+    pub fn conversation(&self, with: &Conversation, since: Option<i64>, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let _guard = lock_sealed(&self.path)?;
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut entries: Vec<HistoryEntry> = self.load()?.into_iter()
+            .filter(|e| !e.is_expired(now) && with.includes(e) && since.is_none_or(|since| e.sent_at >= since))
+            .collect();
+        entries.sort_by_key(|e| e.sent_at);
+        let skip = entries.len().saturating_sub(limit);
+        Ok(entries.split_off(skip))
+    }
+
+    fn push(&self, entry: HistoryEntry) -> Result<()> {
+        self.modify(|entries| {
+            let recorded = entries.iter().any(|e| e.id == entry.id && e.direction == entry.direction);
+            if !recorded {
+                entries.push(entry);
+            }
+            !recorded
+        })?;
+        Ok(())
+    }
+
+    /// Applies a change to the entries, which are only written back if `f` reports a change
+    fn modify(&self, f: impl FnOnce(&mut Vec<HistoryEntry>) -> bool) -> Result<bool> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut entries = self.load()?;
+        let changed = f(&mut entries);
+        if changed {
+            write_sealed(&self.path, &self.key, &serde_json::to_vec(&entries)?)?;
+        }
+        Ok(changed)
+    }
+
+    fn load(&self) -> Result<Vec<HistoryEntry>> {
+        match read_sealed(&self.path, &self.key)? {
+            Some(plain_text) => Ok(serde_json::from_slice(&plain_text)?),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::key_gen::generate_key_pair;
+
+    fn contact(name: &str) -> Contact {
+        Contact::new(name.to_string(), generate_key_pair().unwrap().public_key().ed25519_key_raw().to_vec(), name)
+    }
+
+    #[test]
+    fn test_history_is_encrypted_and_filtered() {
+        let keys = generate_key_pair().unwrap();
+        let path = std::env::temp_dir().join(format!("airoi-history-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let history = History::at(path.clone(), &keys);
+        let (bob, carol) = (contact("bob"), contact("carol"));
+
+        let mut first = Envelope::text("meet at noon");
+        first.timestamp -= 60_000;
+        history.record_sent(&bob, &first, DeliveryState::Queued).unwrap();
+        let reply = Message::from_envelope(bob.clone(), &Envelope::text("see you there"));
+        history.record_received(&reply).unwrap();
+        history.record_received(&reply).unwrap();
+        history.record_sent(&carol, &Envelope::text("hi carol"), DeliveryState::Delivered).unwrap();
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("noon"));
+        assert!(History::at(path.clone(), &generate_key_pair().unwrap()).conversation(&Conversation::Contact(String::new()), None, 10).is_err());
+
+        assert!(history.set_state(first.id, DeliveryState::Read).unwrap());
+        assert!(!history.set_state(reply.id, DeliveryState::Read).unwrap());
+        let with_bob = Conversation::Contact(bob.fingerprint_x().to_string());
+        let entries = history.conversation(&with_bob, None, 10).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id(), entries[0].state), (first.id, DeliveryState::Read));
+        assert_eq!((entries[1].direction, entries[1].text.as_str()), (Direction::Received, "see you there"));
+
+        let latest = history.conversation(&with_bob, None, 1).unwrap();
+        assert_eq!(latest[0].id(), reply.id);
+        assert_eq!(history.conversation(&with_bob, Some(first.timestamp + 1), 10).unwrap().len(), 1);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn test_concurrent_writers_keep_every_entry() {
+        let keys = generate_key_pair().unwrap();
+        let path = std::env::temp_dir().join(format!("airoi-history-concurrent-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bob = contact("bob");
+
+        // separate handles on the same file, like two airoi processes
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                let history = History::at(path.clone(), &keys);
+                let bob = &bob;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        history.record_sent(bob, &Envelope::text("hi"), DeliveryState::Delivered).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(History::at(path.clone(), &keys).entries().unwrap().len(), 40);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,115 @@
+//! Resource limits for inbound connections, so a hostile peer cannot exhaust memory or tasks.
+//!
+//! Over Tor every inbound connection comes from the local Tor daemon, so peers are told apart
+//! by their Noise static key rather than their address. The per-peer rate limit therefore
+//! applies right after the handshake, before any prompt or session is started. Before the
+//! handshake connections are limited by address, which over Tor bounds all peers together;
+//! the connection count and handshake deadline cover peers that never finish a handshake.
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::error::{AiroiError, Result};
+use crate::message::fragment::NOISE_MAX_MSG_LEN;
+
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+pub const DEFAULT_PEER_RATE: usize = 20;
+pub const DEFAULT_ADDRESS_RATE: usize = 120;
+pub const DEFAULT_PEER_RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone)]
+pub struct ReceiverLimits {
+    /// Connections open at the same time, including ones still handshaking
+    pub max_connections: usize,
+    /// Time a connection gets to complete the handshake and identity exchange
+    pub handshake_timeout: Duration,
+    /// Largest transport frame accepted, checked before its buffer is allocated
+    pub max_frame_size: usize,
+    /// Connections a single peer may open within `peer_rate_window`
+    pub peer_rate: usize,
+    /// Connections a single address may open within `peer_rate_window`, checked before the
+    /// handshake. Over Tor all peers share one address, so it is well above `peer_rate`.
+    pub address_rate: usize,
+    pub peer_rate_window: Duration,
+}
+
+impl Default for ReceiverLimits {
+    fn default() -> Self {
+        ReceiverLimits {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            handshake_timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+            max_frame_size: NOISE_MAX_MSG_LEN,
+            peer_rate: DEFAULT_PEER_RATE,
+            address_rate: DEFAULT_ADDRESS_RATE,
+            peer_rate_window: DEFAULT_PEER_RATE_WINDOW,
+        }
+    }
+}
+
+/// Recent connection times per peer, shared by everything using the same config
+#[derive(Debug, Clone, Default)]
+pub struct PeerHistory(Arc<Mutex<HashMap<String, VecDeque<Instant>>>>);
+
+impl PeerHistory {
+    /// Records a connection from `peer`, failing if it exceeds the peer's rate
+    pub(crate) fn check(&self, peer: &str, limits: &ReceiverLimits) -> Result<()> {
+        self.record(peer, limits.peer_rate, limits.peer_rate_window)
+    }
+
+    /// Records a connection from a transport address, failing if it exceeds `address_rate`.
+    /// TCP ports are left out since every connection gets a new one.
+    pub(crate) fn check_address(&self, address: &str, limits: &ReceiverLimits) -> Result<()> {
+        let host = address
+            .parse::<SocketAddr>()
+            .map(|address| address.ip().to_string())
+            .unwrap_or_else(|_| address.to_string());
+        self.record(&format!("address {}", host), limits.address_rate, limits.peer_rate_window)
+    }
+
+    fn record(&self, peer: &str, rate: usize, window: Duration) -> Result<()> {
+        let now = Instant::now();
+        let mut history = self.0.lock().unwrap();
+        history.retain(|_, times| {
+            while times.front().is_some_and(|t| now.duration_since(*t) >= window) {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+        let times = history.entry(peer.to_string()).or_default();
+        if times.len() >= rate {
+            return Err(AiroiError::RateLimited { peer: peer.to_string(), max: rate, window });
+        }
+        times.push_back(now);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_rate_limit() {
+        let limits = ReceiverLimits { peer_rate: 2, peer_rate_window: Duration::from_millis(100), ..ReceiverLimits::default() };
+        let history = PeerHistory::default();
+        history.check("a", &limits).unwrap();
+        history.check("a", &limits).unwrap();
+        assert!(matches!(history.check("a", &limits), Err(AiroiError::RateLimited { max: 2, .. })));
+        history.check("b", &limits).unwrap();
+
+        std::thread::sleep(Duration::from_millis(120));
+        history.check("a", &limits).unwrap();
+    }
+
+    #[test]
+    fn test_address_rate_limit_ignores_ports() {
+        let limits = ReceiverLimits { address_rate: 2, ..ReceiverLimits::default() };
+        let history = PeerHistory::default();
+        history.check_address("10.0.0.1:5001", &limits).unwrap();
+        history.check_address("10.0.0.1:5002", &limits).unwrap();
+        assert!(matches!(history.check_address("10.0.0.1:5003", &limits), Err(AiroiError::RateLimited { max: 2, .. })));
+        history.check_address("10.0.0.2:5001", &limits).unwrap();
+        history.check_address("memory:bob", &limits).unwrap();
+    }
+}
@@ -1,31 +1,120 @@
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
 use crate::keys::contacts::Contact;
+use crate::keys::groups::Group;
+use crate::message::envelope::{Envelope, MessageId, MessageKind};
+use crate::message::file::{FileAccept, FileOffer};
 
+pub mod control;
+pub mod cover;
+pub mod envelope;
+pub mod file;
+pub mod fragment;
+pub mod group;
+pub mod group_key;
+pub mod handshake;
+pub mod history;
+pub mod limits;
+pub mod outbox;
+pub mod padding;
+pub mod ratchet;
+pub mod receipt;
+pub mod rekey;
+pub mod replay;
+pub mod retention;
 pub mod receive;
+pub mod search;
 pub mod send;
+pub mod session;
 
 pub struct Message {
     pub sender: Contact,
+    pub id: MessageId,
+    pub kind: MessageKind,
     pub message: String,
+    pub sent: String,
     pub received: String,
+    /// Outbound queue of the connection this message arrived on, used for receipts
+    pub responder: Option<mpsc::Sender<Envelope>>,
+    /// Set for `FileOffer` messages
+    pub file: Option<FileOffer>,
+    /// Set for `GroupText` and `GroupControl` messages, the group as it is after the message
+    pub group: Option<Group>,
+    /// Seconds after receipt the message disappears, 0 for never
+    pub expires_in: u32,
+}
+
+impl Message {
+    pub fn from_envelope(sender: Contact, envelope: &Envelope) -> Message {
+        Message {
+            sender,
+            id: envelope.id,
+            kind: envelope.kind,
+            message: envelope.body_text(),
+            sent: envelope.sent_at(),
+            received: chrono::Utc::now().to_rfc3339(),
+            responder: None,
+            file: None,
+            group: None,
+            expires_in: envelope.expires_in,
+        }
+    }
+
+    /// Tells the sender that the message has been viewed, if the connection is still open
+    pub async fn mark_read(&self) {
+        if let Some(responder) = &self.responder {
+            let _ = responder.send(Envelope::read_receipt(self.id)).await;
+        }
+    }
+
+    /// Accepts an offered file; it is written to the downloads directory as chunks arrive
+    pub async fn accept_file(&self) -> crate::error::Result<()> {
+        if let (Some(responder), Some(offer)) = (&self.responder, &self.file) {
+            let accept = FileAccept { transfer: offer.transfer.clone(), offset: 0 };
+            let envelope = Envelope::new(MessageKind::FileAccept, serde_json::to_vec(&accept)?);
+            let _ = responder.send(envelope).await;
+        }
+        Ok(())
+    }
+
+    pub async fn reject_file(&self) {
+        if let Some(responder) = &self.responder {
+            let _ = responder.send(Envelope::reject(self.id)).await;
+        }
+    }
 }
 
 impl std::fmt::Display for Message {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}:  {}: {}", self.received, self.sender.name, self.message)
+        match &self.group {
+            Some(group) => write!(f, "{}:  [{}] {}: {}", self.received, group.name, self.sender.name, self.message)?,
+            None => write!(f, "{}:  {}: {}", self.received, self.sender.name, self.message)?,
+        }
+        if self.expires_in > 0 {
+            write!(f, "  (disappears in {:?})", std::time::Duration::from_secs(self.expires_in as u64))?;
+        }
+        Ok(())
     }
 }
 
-async fn read_frame(stream: &mut tokio::net::TcpStream) -> std::io::Result<Vec<u8>> {
+async fn read_frame<R: AsyncRead + Unpin>(stream: &mut R) -> crate::error::Result<Vec<u8>> {
+    read_frame_max(stream, u16::MAX as usize).await
+}
+
+/// Reads a frame, refusing lengths above `max` before anything is allocated
+async fn read_frame_max<R: AsyncRead + Unpin>(stream: &mut R, max: usize) -> crate::error::Result<Vec<u8>> {
     let mut len_buf = [0u8; 2];
     stream.read_exact(&mut len_buf).await?;
     let len = u16::from_be_bytes(len_buf) as usize;
+    if len > max {
+        return Err(crate::error::AiroiError::FrameTooLarge { size: len, max });
+    }
     let mut buf = vec![0u8; len];
     stream.read_exact(&mut buf).await?;
     Ok(buf)
 }
 
-async fn write_frame(stream: &mut tokio::net::TcpStream, data: &[u8]) -> std::io::Result<()> {
+async fn write_frame<W: AsyncWrite + Unpin>(stream: &mut W, data: &[u8]) -> std::io::Result<()> {
     let len = data.len();
     if len > u16::MAX as usize {
         return Err(std::io::Error::new(
@@ -0,0 +1,444 @@
+//! Persistent outbox for contacts that cannot be reached.
+//!
+//! A text message or group update that could not be delivered is kept in `outbox.json` in the
+//! airoi directory, encrypted under a key derived from the local key pair. `run_outbox` retries queued messages
+//! with exponential backoff and drops them once they expire. When a session with a contact opens,
+//! in either direction, their queue is flushed right away regardless of the backoff.
+//! Messages are resent under their original id and timestamp: if an earlier attempt arrived but
+//! its acknowledgment got lost, the receiver's replay window recognises the copy and only
+//! acknowledges it again.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::contacts::{get_contacts, Contact};
+use crate::message::envelope::{Envelope, MessageId, MessageKind};
+use crate::message::history::{DeliveryState, History};
+use crate::message::receipt::DeliveryStatus;
+use crate::message::session::{SessionEvent, SessionRegistry};
+use crate::storage::sealed::{lock_sealed, read_sealed, storage_key, write_sealed};
+use crate::util::get_airoi_dir;
+
+pub const DEFAULT_RETRY_DELAY: Duration = Duration::from_secs(30);
+pub const DEFAULT_MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Kept below the receiver's replay window, older messages would be refused as stale anyway
+pub const DEFAULT_OUTBOX_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+/// The retry loop looks at the outbox at least this often, to pick up messages queued elsewhere
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy)]
+pub struct RetryPolicy {
+    /// Delay after the first failed attempt, doubled with every further one
+    pub initial: Duration,
+    pub max: Duration,
+    /// Messages queued longer than this are dropped
+    pub expiry: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy { initial: DEFAULT_RETRY_DELAY, max: DEFAULT_MAX_RETRY_DELAY, expiry: DEFAULT_OUTBOX_EXPIRY }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the next attempt after `attempts` failed ones
+    pub fn delay(&self, attempts: u32) -> Duration {
+        let factor = 1u32.checked_shl(attempts.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct QueuedMessage {
+    id: [u8; 16],
+    /// Name of the recipient when the message was queued
+    pub contact: String,
+    /// X25519 fingerprint of the recipient, used to find them again
+    pub fingerprint: String,
+    pub text: String,
+    /// Base64 body of a queued `GroupControl` envelope, whose `text` only describes it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    group_update: Option<String>,
+    /// Seconds the message disappears after, 0 for never
+    #[serde(default)]
+    pub expires_in: u32,
+    /// Unix milliseconds, also the timestamp of the envelope
+    pub queued_at: i64,
+    pub attempts: u32,
+    /// Unix milliseconds
+    pub next_attempt: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<String>,
+}
+
+impl QueuedMessage {
+    pub fn id(&self) -> MessageId {
+        MessageId(self.id)
+    }
+
+    /// The envelope as it was first sent
+    pub fn envelope(&self) -> Envelope {
+        let mut envelope = match self.group_update.as_ref().map(|body| general_purpose::STANDARD.decode(body)) {
+            Some(Ok(body)) => Envelope::new(MessageKind::GroupControl, body),
+            _ => Envelope::text(&self.text),
+        };
+        envelope.id = self.id();
+        envelope.expires_in = self.expires_in;
+        envelope.timestamp = self.queued_at;
+        envelope
+    }
+
+    fn is_expired(&self, policy: &RetryPolicy, now: i64) -> bool {
+        now - self.queued_at > policy.expiry.as_millis() as i64
+    }
+
+    fn failed(&mut self, policy: &RetryPolicy, error: String, now: i64) {
+        self.attempts += 1;
+        self.next_attempt = now + policy.delay(self.attempts).as_millis() as i64;
+        self.last_error = Some(error);
+    }
+}
+
+/// Which queued messages `deliver` attempts besides dropping expired ones
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Due {
+    /// Those whose backoff has run out
+    Scheduled,
+    /// Everything to the contact with this X25519 fingerprint
+    Contact(String),
+    /// The message with this id
+    Message(String),
+    All,
+}
+
+impl Due {
+    fn includes(&self, message: &QueuedMessage, now: i64) -> bool {
+        match self {
+            Due::Scheduled => message.next_attempt <= now,
+            Due::Contact(fingerprint) => &message.fingerprint == fingerprint,
+            Due::Message(id) => &message.id().to_string() == id,
+            Due::All => true,
+        }
+    }
+}
+
+/// Outcome of one pass over the outbox
+#[derive(Debug, Default)]
+pub struct FlushReport {
+    pub delivered: Vec<QueuedMessage>,
+    /// Refused by the receiver, retrying would not help
+    pub rejected: Vec<QueuedMessage>,
+    pub expired: Vec<QueuedMessage>,
+    /// Still queued
+    pub pending: usize,
+}
+
+impl FlushReport {
+    /// Updates the state of the messages that left the outbox in the history
+    pub fn record(&self, history: &History) -> Result<()> {
+        for message in &self.delivered {
+            history.set_state(message.id(), DeliveryState::Delivered)?;
+        }
+        for message in self.rejected.iter().chain(&self.expired) {
+            history.set_state(message.id(), DeliveryState::Failed)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct Outbox {
+    path: PathBuf,
+    key: [u8; 32],
+}
+
+impl Outbox {
+    /// The outbox in the airoi directory
+    pub fn open(keys: &KeyPair) -> Outbox {
+        Outbox::at(get_airoi_dir().join("outbox.json"), keys)
+    }
+
+    pub fn at(path: PathBuf, keys: &KeyPair) -> Outbox {
+        Outbox { path, key: storage_key(keys, "outbox") }
+    }
+
+    /// Queued messages, oldest first
+    pub fn list(&self) -> Result<Vec<QueuedMessage>> {
+        let _guard = lock_sealed(&self.path)?;
+        self.load()
+    }
+
+    /// Queues a text or group update envelope for the contact, due for its first retry after
+    /// `RetryPolicy::initial`
+    pub fn push(&self, contact: &Contact, envelope: &Envelope, error: &str, policy: &RetryPolicy) -> Result<QueuedMessage> {
+        let (text, group_update) = match envelope.kind {
+            MessageKind::Text => (envelope.body_text(), None),
+            MessageKind::GroupControl => ("group update".to_string(), Some(general_purpose::STANDARD.encode(&envelope.body))),
+            _ => return Err(AiroiError::Protocol("only text messages and group updates can be queued".to_string())),
+        };
+        let mut message = QueuedMessage {
+            id: envelope.id.0,
+            contact: contact.name.clone(),
+            fingerprint: contact.fingerprint_x().to_string(),
+            text,
+            group_update,
+            expires_in: envelope.expires_in,
+            queued_at: envelope.timestamp,
+            attempts: 0,
+            next_attempt: 0,
+            last_error: None,
+        };
+        message.failed(policy, error.to_string(), chrono::Utc::now().timestamp_millis());
+        self.modify(|queued| queued.push(message.clone()))?;
+        Ok(message)
+    }
+
+    /// Removes a queued message by id, returning it if it was queued
+    pub fn cancel(&self, id: &str) -> Result<Option<QueuedMessage>> {
+        self.modify(|queued| {
+            let index = queued.iter().position(|m| m.id().to_string() == id)?;
+            Some(queued.remove(index))
+        })
+    }
+
+    /// When the earliest queued message is due, in Unix milliseconds
+    pub fn next_attempt(&self) -> Result<Option<i64>> {
+        Ok(self.list()?.iter().map(|m| m.next_attempt).min())
+    }
+
+    fn remove(&self, id: MessageId) -> Result<()> {
+        self.modify(|queued| queued.retain(|m| m.id() != id))
+    }
+
+    fn replace(&self, message: &QueuedMessage) -> Result<()> {
+        self.modify(|queued| {
+            if let Some(existing) = queued.iter_mut().find(|m| m.id == message.id) {
+                *existing = message.clone();
+            }
+        })
+    }
+
+    fn modify<R>(&self, f: impl FnOnce(&mut Vec<QueuedMessage>) -> R) -> Result<R> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut queued = self.load()?;
+        let result = f(&mut queued);
+        write_sealed(&self.path, &self.key, &serde_json::to_vec(&queued)?)?;
+        Ok(result)
+    }
+
+    fn load(&self) -> Result<Vec<QueuedMessage>> {
+        match read_sealed(&self.path, &self.key)? {
+            Some(plain_text) => Ok(serde_json::from_slice(&plain_text)?),
+            None => Ok(vec![]),
+        }
+    }
+}
+
+/// Whether a failed send is worth queueing. Key changes and refusals are not retried.
+pub fn is_transient(e: &AiroiError) -> bool {
+    matches!(e, AiroiError::Io(_) | AiroiError::Onion(_) | AiroiError::SessionClosed | AiroiError::HandshakeTimeout(_) | AiroiError::CoverQueueFull { .. })
+}
+
+/// Sends the queued messages selected by `due` over the registry's sessions, oldest first. Once a
+/// message to a contact was not confirmed, their remaining messages are rescheduled without
+/// another attempt.
+pub async fn deliver(
+    registry: &SessionRegistry,
+    outbox: &Outbox,
+    policy: &RetryPolicy,
+    contacts: &[Contact],
+    due: &Due,
+) -> Result<FlushReport> {
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut report = FlushReport::default();
+    let mut unreachable: HashMap<String, String> = HashMap::new();
+
+    for mut message in outbox.list()? {
+        if message.is_expired(policy, now) {
+            outbox.remove(message.id())?;
+            report.expired.push(message);
+            continue;
+        }
+        if !due.includes(&message, now) {
+            report.pending += 1;
+            continue;
+        }
+        let contact = contacts.iter().find(|c| c.fingerprint_x() == message.fingerprint);
+        let error = match (contact, unreachable.get(&message.fingerprint)) {
+            (None, _) => "contact no longer exists".to_string(),
+            (Some(_), Some(error)) => error.clone(),
+            (Some(contact), None) => match registry.send(contact, message.envelope()).await {
+                Ok(status) if status.is_delivered() => {
+                    outbox.remove(message.id())?;
+                    report.delivered.push(message);
+                    continue;
+                }
+                Ok(DeliveryStatus::Rejected) => {
+                    outbox.remove(message.id())?;
+                    report.rejected.push(message);
+                    continue;
+                }
+                // later messages wait as well, group updates only apply in order
+                Ok(status) => {
+                    unreachable.insert(message.fingerprint.clone(), status.to_string());
+                    status.to_string()
+                }
+                Err(e) => {
+                    unreachable.insert(message.fingerprint.clone(), e.to_string());
+                    e.to_string()
+                }
+            },
+        };
+        message.failed(policy, error, chrono::Utc::now().timestamp_millis());
+        outbox.replace(&message)?;
+        report.pending += 1;
+    }
+    Ok(report)
+}
+
+/// Retries queued messages for as long as the registry lives. A contact's messages are sent as
+/// soon as a session with them opens.
+pub async fn run_outbox(registry: Arc<SessionRegistry>, outbox: Arc<Outbox>, policy: RetryPolicy) {
+    let mut events = registry.events();
+    let history = History::open(registry.keys());
+    let mut due = Due::Scheduled;
+    loop {
+        let result = match get_contacts() {
+            Ok(contacts) => deliver(&registry, &outbox, &policy, &contacts, &due).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(report) => {
+                for message in &report.delivered {
+                    println!("Queued message {} to {} delivered", message.id(), message.contact);
+                }
+                for message in &report.rejected {
+                    println!("Queued message {} to {} rejected", message.id(), message.contact);
+                }
+                for message in &report.expired {
+                    println!("Queued message {} to {} expired", message.id(), message.contact);
+                }
+                if let Err(e) = report.record(&history) {
+                    eprintln!("history error: {}", e);
+                }
+            }
+            Err(e) => eprintln!("outbox error: {}", e),
+        }
+
+        let now = chrono::Utc::now().timestamp_millis();
+        let wait = match outbox.next_attempt() {
+            Ok(Some(next)) => Duration::from_millis((next - now).max(0) as u64).min(POLL_INTERVAL),
+            _ => POLL_INTERVAL,
+        };
+        due = tokio::select! {
+            _ = tokio::time::sleep(wait) => Due::Scheduled,
+            event = events.recv() => match event {
+                Ok(SessionEvent::Opened(contact)) => Due::Contact(contact.fingerprint_x().to_string()),
+                Ok(SessionEvent::Closed(..)) | Err(RecvError::Lagged(_)) => Due::Scheduled,
+                Err(RecvError::Closed) => return,
+            },
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc;
+    use crate::keys::key_gen::generate_key_pair;
+    use crate::message::receive::{handle_connection, ReceiveConfig};
+    use crate::transport::{MemoryTransport, Transport};
+
+    fn outbox_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("airoi-outbox-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy { initial: Duration::from_secs(10), max: Duration::from_secs(60), expiry: DEFAULT_OUTBOX_EXPIRY };
+        assert_eq!(policy.delay(1), Duration::from_secs(10));
+        assert_eq!(policy.delay(2), Duration::from_secs(20));
+        assert_eq!(policy.delay(3), Duration::from_secs(40));
+        assert_eq!(policy.delay(4), Duration::from_secs(60));
+        assert_eq!(policy.delay(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_outbox_is_encrypted_and_persistent() {
+        let keys = generate_key_pair().unwrap();
+        let path = outbox_path("encrypted");
+        let bob = Contact::new("bob".to_string(), generate_key_pair().unwrap().public_key().ed25519_key_raw().to_vec(), "bob");
+        let envelope = Envelope::text("meet at noon");
+        let queued = Outbox::at(path.clone(), &keys).push(&bob, &envelope, "offline", &RetryPolicy::default()).unwrap();
+        assert_eq!(queued.attempts, 1);
+        assert!(!std::fs::read_to_string(&path).unwrap().contains("noon"));
+
+        let outbox = Outbox::at(path.clone(), &keys);
+        let listed = outbox.list().unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].envelope(), envelope);
+        assert!(Outbox::at(path.clone(), &generate_key_pair().unwrap()).list().is_err());
+
+        assert!(outbox.cancel("unknown").unwrap().is_none());
+        assert!(outbox.cancel(&envelope.id.to_string()).unwrap().is_some());
+        assert!(outbox.list().unwrap().is_empty());
+
+        // group updates are queued as they were sent, other kinds are refused
+        let update = Envelope::new(MessageKind::GroupControl, br#"{"group":"friends"}"#.to_vec());
+        outbox.push(&bob, &update, "offline", &RetryPolicy::default()).unwrap();
+        assert_eq!(outbox.list().unwrap()[0].envelope(), update);
+        assert!(outbox.push(&bob, &Envelope::new(MessageKind::Ping, vec![]), "offline", &RetryPolicy::default()).is_err());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[tokio::test]
+    async fn test_queued_message_delivered_when_reachable() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let carrier = MemoryTransport::new();
+        let (inbound, _inbound_rx) = mpsc::channel(1);
+        let registry = SessionRegistry::new(Arc::new(carrier.clone()), alice.clone(), inbound, ReceiveConfig::default());
+        let path = outbox_path("deliver");
+        let outbox = Outbox::at(path.clone(), &alice);
+        let policy = RetryPolicy::default();
+
+        let contacts = vec![Contact::new("bob".to_string(), bob.public_key().ed25519_key_raw().to_vec(), "bob")];
+        let envelope = Envelope::text("are you there?");
+        outbox.push(&contacts[0], &envelope, "offline", &policy).unwrap();
+
+        // not due yet, then due but bob is still offline
+        let report = deliver(&registry, &outbox, &policy, &contacts, &Due::Scheduled).await.unwrap();
+        assert_eq!((report.pending, outbox.list().unwrap()[0].attempts), (1, 1));
+        let report = deliver(&registry, &outbox, &policy, &contacts, &Due::All).await.unwrap();
+        assert_eq!((report.pending, outbox.list().unwrap()[0].attempts), (1, 2));
+
+        let mut listener = carrier.listen("bob").await.unwrap();
+        let (tx, mut rx) = mpsc::channel(1);
+        {
+            let bob = bob.clone();
+            let senders = vec![Contact::new("alice".to_string(), alice.public_key().ed25519_key_raw().to_vec(), "alice")];
+            tokio::spawn(async move {
+                let (socket, peer_addr) = listener.accept().await.unwrap();
+                let _ = handle_connection(&bob, socket, &peer_addr, &senders, tx, &ReceiveConfig::default()).await;
+            });
+        }
+
+        let due = Due::Contact(contacts[0].fingerprint_x().to_string());
+        let report = deliver(&registry, &outbox, &policy, &contacts, &due).await.unwrap();
+        assert_eq!((report.delivered.len(), report.pending), (1, 0));
+        assert!(outbox.list().unwrap().is_empty());
+
+        let received = rx.recv().await.unwrap();
+        assert_eq!((received.id, received.message.as_str()), (envelope.id, "are you there?"));
+        registry.close_all().await;
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,100 @@
+//! Length-hiding padding of transport chunks.
+//!
+//! A network observer sees the length of every Noise message. Chunks are padded with zeros
+//! inside the encrypted payload, so only the padded length is visible; the chunk header carries
+//! the real data length and the receiver drops the rest. Padding is chosen by the sender, the
+//! receiver strips it whatever the policy was.
+use serde::{Deserialize, Serialize};
+
+/// Padded chunk sizes for `PaddingPolicy::Buckets`; larger chunks are padded to the maximum
+pub const PADDING_BUCKETS: [usize; 4] = [256, 1024, 4096, 16384];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PaddingPolicy {
+    /// Chunks are sent as they are
+    None,
+    /// Chunks are padded to the next of `PADDING_BUCKETS`
+    #[default]
+    Buckets,
+    /// Padmé: at most 12% overhead, leaks only O(log log n) bits of the length
+    Padme,
+}
+
+impl PaddingPolicy {
+    /// Length a chunk of `len` bytes is padded to, never more than `max`
+    pub fn padded_len(&self, len: usize, max: usize) -> usize {
+        let padded = match self {
+            PaddingPolicy::None => len,
+            PaddingPolicy::Buckets => PADDING_BUCKETS
+                .iter()
+                .copied()
+                .find(|bucket| *bucket >= len)
+                .unwrap_or(max),
+            PaddingPolicy::Padme => padme(len),
+        };
+        padded.clamp(len, max.max(len))
+    }
+}
+
+impl std::str::FromStr for PaddingPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(PaddingPolicy::None),
+            "buckets" => Ok(PaddingPolicy::Buckets),
+            "padme" => Ok(PaddingPolicy::Padme),
+            other => Err(format!("unknown padding policy '{}', expected none, buckets or padme", other)),
+        }
+    }
+}
+
+impl std::fmt::Display for PaddingPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            PaddingPolicy::None => "none",
+            PaddingPolicy::Buckets => "buckets",
+            PaddingPolicy::Padme => "padme",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+    let e = usize::BITS - 1 - len.leading_zeros();
+    let s = u32::BITS - e.leading_zeros();
+    let last_bits = e - s;
+    let mask = (1usize << last_bits) - 1;
+    (len + mask) & !mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        let policy = PaddingPolicy::Buckets;
+        assert_eq!(policy.padded_len(1, 65519), 256);
+        assert_eq!(policy.padded_len(256, 65519), 256);
+        assert_eq!(policy.padded_len(257, 65519), 1024);
+        assert_eq!(policy.padded_len(20000, 65519), 65519);
+        assert_eq!(PaddingPolicy::None.padded_len(257, 65519), 257);
+    }
+
+    #[test]
+    fn test_padme() {
+        let policy = PaddingPolicy::Padme;
+        assert_eq!(policy.padded_len(1000, 65519), 1024);
+        assert_eq!(policy.padded_len(9, 65519), 10);
+        for len in 2..5000 {
+            let padded = policy.padded_len(len, 65519);
+            assert!(padded >= len && padded as f64 <= len as f64 * 1.12 + 1.0);
+        }
+        assert_eq!(policy.padded_len(65000, 65519), 65519);
+    }
+}
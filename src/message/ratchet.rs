@@ -0,0 +1,611 @@
+//! Double Ratchet for messages that do not travel over a live session.
+//!
+//! A live session gets forward secrecy from its ephemeral Noise keys, but a message left at a
+//! relay is sealed to the recipient's long-term key. Such messages are first encrypted with a
+//! per-contact Double Ratchet (as specified by Signal, with X25519, HKDF-SHA256, HMAC-SHA256
+//! and ChaCha20-Poly1305): every message gets its own key, and every reply turns the root key
+//! over with a fresh DH, so a compromise of the current state reveals neither past messages
+//! nor, after the next round trip, future ones.
+//!
+//! With a prekey bundle of the recipient, the ratchet is bootstrapped by X3DH (see
+//! `keys::prekeys`) with the signed prekey as the recipient's first ratchet key, and its first
+//! messages carry the X3DH prekey message (`FLAG_PREKEY`). Without one, it falls back to the
+//! static-static DH of both identities, with the recipient's static key as its first ratchet
+//! key, which only gets forward secrecy once the recipient replies. Whoever writes first starts
+//! the ratchet and marks its messages `FLAG_INITIAL` until it hears back. If both sides started
+//! one at the same time, the one started by the smaller static key wins; the other side adopts it.
+//!
+//! Message keys of skipped messages are kept (up to `MAX_SKIP` per chain and
+//! `MAX_SKIPPED_KEYS` overall) so messages can arrive out of order. State is stored per contact
+//! in `ratchets.json` in the airoi directory, encrypted under a key derived from the local key pair.
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use chacha20poly1305::{ChaCha20Poly1305, KeyInit};
+use chacha20poly1305::aead::{Aead, Payload};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use rand::TryRngCore;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::Zeroize;
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::contacts::Contact;
+use crate::keys::prekeys::{x3dh_initiate, x3dh_respond, PrekeyBundle, PrekeyMessage, PrekeyStore, PREKEY_MESSAGE_LEN};
+use crate::storage::sealed::{lock_sealed, read_sealed, storage_key, write_sealed};
+use crate::util::get_airoi_dir;
+
+/// Message keys derived ahead within one chain at most
+pub const MAX_SKIP: u32 = 1000;
+/// Skipped message keys kept overall, the oldest are dropped first
+pub const MAX_SKIPPED_KEYS: usize = 2000;
+
+const BOOTSTRAP_INFO: &[u8] = b"airoi ratchet bootstrap v1";
+const ROOT_INFO: &[u8] = b"airoi ratchet root v1";
+const MESSAGE_INFO: &[u8] = b"airoi ratchet message v1";
+/// Ratchet starts remembered per contact
+const MAX_STARTED: usize = 32;
+/// Set on messages of a ratchet the sender started and has not heard back on
+const FLAG_INITIAL: u8 = 1;
+/// Set on initial messages of a ratchet bootstrapped by X3DH, the prekey message follows the header
+const FLAG_PREKEY: u8 = 2;
+/// Flag, ratchet key, previous chain length and message number
+const HEADER_LEN: usize = 1 + 32 + 4 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Header {
+    flags: u8,
+    dh: [u8; 32],
+    pn: u32,
+    n: u32,
+}
+
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_LEN);
+        out.push(self.flags);
+        out.extend_from_slice(&self.dh);
+        out.extend_from_slice(&self.pn.to_be_bytes());
+        out.extend_from_slice(&self.n.to_be_bytes());
+        out
+    }
+
+    fn decode(data: &[u8]) -> Result<Header> {
+        if data.len() < HEADER_LEN {
+            return Err(AiroiError::Protocol("truncated ratchet header".to_string()));
+        }
+        Ok(Header {
+            flags: data[0],
+            dh: data[1..33].try_into().unwrap(),
+            pn: u32::from_be_bytes(data[33..37].try_into().unwrap()),
+            n: u32::from_be_bytes(data[37..41].try_into().unwrap()),
+        })
+    }
+
+    /// Length of the header and the prekey message it may be followed by
+    fn len(&self) -> usize {
+        if self.flags & FLAG_PREKEY != 0 { HEADER_LEN + PREKEY_MESSAGE_LEN } else { HEADER_LEN }
+    }
+
+    fn prekey_message(&self, message: &[u8]) -> Result<Option<PrekeyMessage>> {
+        if self.flags & FLAG_PREKEY == 0 {
+            return Ok(None);
+        }
+        PrekeyMessage::decode(&message[HEADER_LEN.min(message.len())..]).map(Some)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    dh: [u8; 32],
+    n: u32,
+    key: [u8; 32],
+}
+
+/// Ratchet state with one contact
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Ratchet {
+    dh_secret: [u8; 32],
+    dh_public: [u8; 32],
+    dh_remote: [u8; 32],
+    root: [u8; 32],
+    send_chain: Option<[u8; 32]>,
+    recv_chain: Option<[u8; 32]>,
+    ns: u32,
+    nr: u32,
+    pn: u32,
+    /// Whether anything arrived on this ratchet yet
+    confirmed: bool,
+    skipped: VecDeque<SkippedKey>,
+    /// How the contact derives the bootstrap secret, sent along until the ratchet is confirmed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prekey: Option<PrekeyMessage>,
+}
+
+impl Drop for Ratchet {
+    fn drop(&mut self) {
+        self.dh_secret.zeroize();
+        self.root.zeroize();
+        self.send_chain.zeroize();
+        self.recv_chain.zeroize();
+        for skipped in self.skipped.iter_mut() {
+            skipped.key.zeroize();
+        }
+    }
+}
+
+fn kdf_root(root: &[u8; 32], dh_out: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let mut okm = [0u8; 64];
+    Hkdf::<Sha256>::new(Some(root), dh_out)
+        .expand(ROOT_INFO, &mut okm)
+        .expect("64 bytes is a valid HKDF-SHA256 length");
+    let (root, chain) = okm.split_at(32);
+    let result = (root.try_into().unwrap(), chain.try_into().unwrap());
+    okm.zeroize();
+    result
+}
+
+pub(crate) fn kdf_chain(chain: &[u8; 32]) -> ([u8; 32], [u8; 32]) {
+    let step = |byte: u8| -> [u8; 32] {
+        let mut mac = <Hmac<Sha256> as hmac::KeyInit>::new_from_slice(chain).expect("HMAC takes keys of any size");
+        mac.update(&[byte]);
+        mac.finalize().into_bytes().into()
+    };
+    (step(2), step(1))
+}
+
+pub(crate) fn message_cipher(message_key: &[u8; 32]) -> (ChaCha20Poly1305, [u8; 12]) {
+    let mut okm = [0u8; 44];
+    Hkdf::<Sha256>::new(None, message_key)
+        .expand(MESSAGE_INFO, &mut okm)
+        .expect("44 bytes is a valid HKDF-SHA256 length");
+    let cipher = ChaCha20Poly1305::new_from_slice(&okm[..32]).expect("32 byte key");
+    let nonce = okm[32..].try_into().unwrap();
+    okm.zeroize();
+    (cipher, nonce)
+}
+
+pub(crate) fn generate_secret() -> Result<[u8; 32]> {
+    let mut secret = [0u8; 32];
+    OsRng.try_fill_bytes(&mut secret)?;
+    Ok(secret)
+}
+
+fn crypto_error(e: impl std::fmt::Display) -> AiroiError {
+    AiroiError::Protocol(format!("ratchet message could not be decrypted: {}", e))
+}
+
+impl Ratchet {
+    /// Starts a ratchet towards the contact's ratchet key; we may send right away
+    pub fn initiate(shared: &[u8; 32], remote: [u8; 32]) -> Result<Ratchet> {
+        let dh_secret = generate_secret()?;
+        let (root, send_chain) = kdf_root(shared, &x25519(dh_secret, remote));
+        Ok(Ratchet {
+            dh_secret,
+            dh_public: x25519(dh_secret, X25519_BASEPOINT_BYTES),
+            dh_remote: remote,
+            root,
+            send_chain: Some(send_chain),
+            recv_chain: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            confirmed: false,
+            skipped: VecDeque::new(),
+            prekey: None,
+        })
+    }
+
+    /// Waits for a ratchet the contact starts towards our ratchet key `secret`
+    pub fn respond(shared: &[u8; 32], secret: [u8; 32]) -> Ratchet {
+        Ratchet {
+            dh_secret: secret,
+            dh_public: x25519(secret, X25519_BASEPOINT_BYTES),
+            dh_remote: [0; 32],
+            root: *shared,
+            send_chain: None,
+            recv_chain: None,
+            ns: 0,
+            nr: 0,
+            pn: 0,
+            confirmed: false,
+            skipped: VecDeque::new(),
+            prekey: None,
+        }
+    }
+
+    /// Encrypts a message; `ad` is authenticated along with the header
+    pub fn encrypt(&mut self, plaintext: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+        let chain = self.send_chain
+            .ok_or_else(|| AiroiError::Protocol("ratchet has no sending chain yet".to_string()))?;
+        let (chain, message_key) = kdf_chain(&chain);
+        self.send_chain = Some(chain);
+        let prekey = self.prekey.filter(|_| !self.confirmed);
+        let header = Header {
+            flags: if self.confirmed { 0 } else { FLAG_INITIAL } | if prekey.is_some() { FLAG_PREKEY } else { 0 },
+            dh: self.dh_public,
+            pn: self.pn,
+            n: self.ns,
+        };
+        self.ns += 1;
+
+        let mut message = header.encode();
+        if let Some(prekey) = prekey {
+            message.extend_from_slice(&prekey.encode());
+        }
+        let (cipher, nonce) = message_cipher(&message_key);
+        let aad = [ad, &message].concat();
+        let ciphertext = cipher.encrypt(&nonce.into(), Payload { msg: plaintext, aad: &aad })
+            .map_err(|e| AiroiError::Protocol(format!("ratchet encryption failed: {}", e)))?;
+        message.extend_from_slice(&ciphertext);
+        Ok(message)
+    }
+
+    /// Decrypts a message. The state only changes if the message is authentic.
+    pub fn decrypt(&mut self, message: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+        let mut next = self.clone();
+        let plaintext = next.try_decrypt(message, ad)?;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn try_decrypt(&mut self, message: &[u8], ad: &[u8]) -> Result<Vec<u8>> {
+        let header = Header::decode(message)?;
+        let header_len = header.len();
+        if message.len() < header_len {
+            return Err(AiroiError::Protocol("truncated ratchet header".to_string()));
+        }
+        let message_key = match self.skipped.iter().position(|s| s.dh == header.dh && s.n == header.n) {
+            Some(index) => self.skipped.remove(index).unwrap().key,
+            None => {
+                if header.dh != self.dh_remote || self.recv_chain.is_none() {
+                    self.skip_until(header.pn)?;
+                    self.dh_step(header.dh)?;
+                }
+                self.skip_until(header.n)?;
+                let (chain, message_key) = kdf_chain(&self.recv_chain.unwrap());
+                self.recv_chain = Some(chain);
+                self.nr += 1;
+                message_key
+            }
+        };
+
+        let (cipher, nonce) = message_cipher(&message_key);
+        let aad = [ad, &message[..header_len]].concat();
+        let plaintext = cipher.decrypt(&nonce.into(), Payload { msg: &message[header_len..], aad: &aad })
+            .map_err(crypto_error)?;
+        self.confirmed = true;
+        Ok(plaintext)
+    }
+
+    /// Stores the keys of the receiving chain's messages before `until`
+    fn skip_until(&mut self, until: u32) -> Result<()> {
+        let Some(mut chain) = self.recv_chain else {
+            return Ok(());
+        };
+        if until > self.nr.saturating_add(MAX_SKIP) {
+            return Err(crypto_error(format!("more than {} skipped messages", MAX_SKIP)));
+        }
+        while self.nr < until {
+            let (next, key) = kdf_chain(&chain);
+            self.skipped.push_back(SkippedKey { dh: self.dh_remote, n: self.nr, key });
+            chain = next;
+            self.nr += 1;
+        }
+        self.recv_chain = Some(chain);
+        while self.skipped.len() > MAX_SKIPPED_KEYS {
+            self.skipped.pop_front();
+        }
+        Ok(())
+    }
+
+    fn dh_step(&mut self, remote: [u8; 32]) -> Result<()> {
+        self.pn = self.ns;
+        self.ns = 0;
+        self.nr = 0;
+        self.dh_remote = remote;
+        let (root, recv_chain) = kdf_root(&self.root, &x25519(self.dh_secret, remote));
+        self.dh_secret = generate_secret()?;
+        self.dh_public = x25519(self.dh_secret, X25519_BASEPOINT_BYTES);
+        let (root, send_chain) = kdf_root(&root, &x25519(self.dh_secret, remote));
+        self.root = root;
+        self.recv_chain = Some(recv_chain);
+        self.send_chain = Some(send_chain);
+        Ok(())
+    }
+}
+
+/// A new ratchet towards the contact, by X3DH if there is a prekey bundle
+fn start(keys: &KeyPair, contact: &Contact, prekeys: Option<&PrekeyBundle>) -> Result<Ratchet> {
+    let Some(bundle) = prekeys else {
+        return Ratchet::initiate(&bootstrap_secret(keys, contact)?, remote_static(contact)?);
+    };
+    bundle.verify_for(contact)?;
+    let (shared, prekey, remote) = x3dh_initiate(keys, bundle)?;
+    let mut ratchet = Ratchet::initiate(&shared, remote)?;
+    ratchet.prekey = Some(prekey);
+    Ok(ratchet)
+}
+
+/// Secret both sides derive from their static keys to bootstrap a ratchet
+fn bootstrap_secret(keys: &KeyPair, contact: &Contact) -> Result<[u8; 32]> {
+    let own: [u8; 32] = keys.private_key().x25519_key_raw().try_into()
+        .map_err(|_| AiroiError::Protocol("local x25519 key is malformed".to_string()))?;
+    let remote = remote_static(contact)?;
+    let mut dh = x25519(own, remote);
+    let mut shared = [0u8; 32];
+    Hkdf::<Sha256>::new(None, &dh)
+        .expand(BOOTSTRAP_INFO, &mut shared)
+        .expect("32 bytes is a valid HKDF-SHA256 length");
+    dh.zeroize();
+    Ok(shared)
+}
+
+fn remote_static(contact: &Contact) -> Result<[u8; 32]> {
+    contact.public_key().x25519_key_raw().try_into()
+        .map_err(|_| AiroiError::Protocol(format!("x25519 key of {} is malformed", contact.name)))
+}
+
+/// Binds a message to the identities of sender and recipient
+fn associated_data(sender: &[u8], recipient: &[u8]) -> Vec<u8> {
+    [sender, recipient].concat()
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct ContactState {
+    ratchet: Ratchet,
+    /// Ratchet the contact started while ours was unconfirmed and won; their messages on it still open
+    theirs: Option<Ratchet>,
+    /// First ratchet keys of ratchets the contact started, so replaying such a first message
+    /// cannot reset ours
+    started: VecDeque<[u8; 32]>,
+}
+
+/// Ratchets with every contact, persisted encrypted
+pub struct RatchetStore {
+    path: PathBuf,
+    key: [u8; 32],
+}
+
+impl RatchetStore {
+    /// The ratchets in the airoi directory
+    pub fn open(keys: &KeyPair) -> RatchetStore {
+        RatchetStore::at(get_airoi_dir().join("ratchets.json"), keys)
+    }
+
+    pub fn at(path: PathBuf, keys: &KeyPair) -> RatchetStore {
+        RatchetStore { path, key: storage_key(keys, "ratchets") }
+    }
+
+    /// Whether the next message to the contact starts a ratchet, and could use a prekey bundle
+    pub fn needs_start(&self, contact: &Contact) -> Result<bool> {
+        let _guard = lock_sealed(&self.path)?;
+        Ok(self.load()?.get(contact.fingerprint_x()).is_none_or(|state| state.ratchet.send_chain.is_none()))
+    }
+
+    /// Encrypts a message for the contact, starting a ratchet if there is none: by X3DH if a
+    /// prekey bundle is given, from both static keys otherwise
+    pub fn encrypt(&self, keys: &KeyPair, contact: &Contact, prekeys: Option<&PrekeyBundle>, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut states = self.load()?;
+        let mut state = match states.remove(contact.fingerprint_x()) {
+            Some(state) if state.ratchet.send_chain.is_some() => state,
+            previous => ContactState {
+                ratchet: start(keys, contact, prekeys)?,
+                theirs: None,
+                started: previous.map(|p| p.started.clone()).unwrap_or_default(),
+            },
+        };
+        let ad = associated_data(keys.public_key().x25519_key_raw(), contact.public_key().x25519_key_raw());
+        let message = state.ratchet.encrypt(plaintext, &ad)?;
+        states.insert(contact.fingerprint_x().to_string(), state);
+        self.store(&states)?;
+        Ok(message)
+    }
+
+    /// Decrypts a message from the contact. A message starting a new ratchet replaces ours,
+    /// unless ours is unconfirmed and started by the smaller static key. One-time prekeys are
+    /// deleted from `prekeys` once a ratchet started with them is accepted.
+    pub fn decrypt(&self, keys: &KeyPair, contact: &Contact, prekeys: &PrekeyStore, message: &[u8]) -> Result<Vec<u8>> {
+        let header = Header::decode(message)?;
+        let _guard = lock_sealed(&self.path)?;
+        let mut states = self.load()?;
+        let ad = associated_data(contact.public_key().x25519_key_raw(), keys.public_key().x25519_key_raw());
+
+        let mut state = states.remove(contact.fingerprint_x());
+        if let Some(state) = &mut state {
+            let mut result = state.ratchet.decrypt(message, &ad);
+            if let (Err(_), Some(theirs)) = (&result, &mut state.theirs) {
+                result = theirs.decrypt(message, &ad);
+            }
+            if result.is_ok() || header.flags & FLAG_INITIAL == 0 {
+                states.insert(contact.fingerprint_x().to_string(), state.clone());
+                self.store(&states)?;
+                return result;
+            }
+            if state.started.contains(&header.dh) {
+                return Err(crypto_error("replayed start of a ratchet"));
+            }
+        }
+
+        let prekey = header.prekey_message(message)?;
+        let mut started = match &prekey {
+            Some(prekey) => {
+                let (shared, secret) = x3dh_respond(keys, prekeys, &remote_static(contact)?, prekey)?;
+                Ratchet::respond(&shared, secret)
+            }
+            None => {
+                let own: [u8; 32] = keys.private_key().x25519_key_raw().try_into()
+                    .map_err(|_| AiroiError::Protocol("local x25519 key is malformed".to_string()))?;
+                Ratchet::respond(&bootstrap_secret(keys, contact)?, own)
+            }
+        };
+        let plaintext = started.decrypt(message, &ad)?;
+        if let Some(id) = prekey.and_then(|p| p.one_time_prekey) {
+            prekeys.consume(id)?;
+        }
+        let keep_ours = state.as_ref()
+            .is_some_and(|ours| !ours.ratchet.confirmed && keys.public_key().x25519_key_raw() < contact.public_key().x25519_key_raw());
+        let mut state = match state {
+            Some(mut ours) if keep_ours => {
+                ours.theirs = Some(started);
+                ours
+            }
+            previous => ContactState {
+                ratchet: started,
+                theirs: None,
+                started: previous.map(|p| p.started.clone()).unwrap_or_default(),
+            },
+        };
+        state.started.push_back(header.dh);
+        while state.started.len() > MAX_STARTED {
+            state.started.pop_front();
+        }
+        states.insert(contact.fingerprint_x().to_string(), state);
+        self.store(&states)?;
+        Ok(plaintext)
+    }
+
+    /// Forgets the ratchet with a contact, the next message starts a new one
+    pub fn reset(&self, contact: &Contact) -> Result<()> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut states = self.load()?;
+        states.remove(contact.fingerprint_x());
+        self.store(&states)
+    }
+
+    fn load(&self) -> Result<HashMap<String, ContactState>> {
+        match read_sealed(&self.path, &self.key)? {
+            Some(plain_text) => Ok(serde_json::from_slice(&plain_text)?),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn store(&self, states: &HashMap<String, ContactState>) -> Result<()> {
+        write_sealed(&self.path, &self.key, &serde_json::to_vec(states)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::key_gen::generate_key_pair;
+
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("airoi-ratchets-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn pair() -> (Ratchet, Ratchet) {
+        let shared = [7u8; 32];
+        let bob_secret = generate_secret().unwrap();
+        let alice = Ratchet::initiate(&shared, x25519(bob_secret, X25519_BASEPOINT_BYTES)).unwrap();
+        (alice, Ratchet::respond(&shared, bob_secret))
+    }
+
+    #[test]
+    fn test_out_of_order_and_replies() {
+        let (mut alice, mut bob) = pair();
+        let first = alice.encrypt(b"one", b"ad").unwrap();
+        let second = alice.encrypt(b"two", b"ad").unwrap();
+        let third = alice.encrypt(b"three", b"ad").unwrap();
+
+        assert_eq!(bob.decrypt(&third, b"ad").unwrap(), b"three");
+        assert_eq!(bob.decrypt(&first, b"ad").unwrap(), b"one");
+        // a message key is used once
+        assert!(bob.decrypt(&first, b"ad").is_err());
+        assert!(bob.decrypt(&second, b"other ad").is_err());
+
+        let reply = bob.encrypt(b"back", b"ad").unwrap();
+        assert_eq!(alice.decrypt(&reply, b"ad").unwrap(), b"back");
+        let again = alice.encrypt(b"four", b"ad").unwrap();
+        assert_eq!(bob.decrypt(&again, b"ad").unwrap(), b"four");
+        // the straggler of the previous chain still opens
+        assert_eq!(bob.decrypt(&second, b"ad").unwrap(), b"two");
+    }
+
+    #[test]
+    fn test_tampering_leaves_state_untouched() {
+        let (mut alice, mut bob) = pair();
+        let mut message = alice.encrypt(b"hello", b"").unwrap();
+        let last = message.len() - 1;
+        message[last] ^= 1;
+        assert!(bob.decrypt(&message, b"").is_err());
+        message[last] ^= 1;
+        assert_eq!(bob.decrypt(&message, b"").unwrap(), b"hello");
+
+        let mut far = alice.encrypt(b"x", b"").unwrap();
+        far[37..41].copy_from_slice(&(MAX_SKIP + 5).to_be_bytes());
+        assert!(bob.decrypt(&far, b"").is_err());
+    }
+
+    #[test]
+    fn test_store_settles_simultaneous_start() {
+        // alice has the smaller static key, so her ratchet wins
+        let (alice, bob) = {
+            let (a, b) = (generate_key_pair().unwrap(), generate_key_pair().unwrap());
+            if a.public_key().x25519_key_raw() < b.public_key().x25519_key_raw() { (a, b) } else { (b, a) }
+        };
+        let alice_store = RatchetStore::at(temp("alice"), &alice);
+        let bob_store = RatchetStore::at(temp("bob"), &bob);
+        let alice_prekeys = PrekeyStore::at(temp("alice-prekeys"), &alice);
+        let bob_prekeys = PrekeyStore::at(temp("bob-prekeys"), &bob);
+        let to_bob = Contact::new("bob".to_string(), bob.public_key().ed25519_key_raw().to_vec(), "");
+        let to_alice = Contact::new("alice".to_string(), alice.public_key().ed25519_key_raw().to_vec(), "");
+
+        // both write first
+        let from_alice = alice_store.encrypt(&alice, &to_bob, None, b"hi bob").unwrap();
+        let from_bob = bob_store.encrypt(&bob, &to_alice, None, b"hi alice").unwrap();
+        let from_bob_again = bob_store.encrypt(&bob, &to_alice, None, b"still there?").unwrap();
+        assert_eq!(bob_store.decrypt(&bob, &to_alice, &bob_prekeys, &from_alice).unwrap(), b"hi bob");
+        assert_eq!(alice_store.decrypt(&alice, &to_bob, &alice_prekeys, &from_bob).unwrap(), b"hi alice");
+        // replaying a first message does not start over
+        assert!(alice_store.decrypt(&alice, &to_bob, &alice_prekeys, &from_bob).is_err());
+        assert!(bob_store.decrypt(&bob, &to_alice, &bob_prekeys, &from_alice).is_err());
+        assert_eq!(alice_store.decrypt(&alice, &to_bob, &alice_prekeys, &from_bob_again).unwrap(), b"still there?");
+
+        // afterwards both use the same ratchet, across reloads from disk
+        for round in 0..3 {
+            let text = format!("round {}", round);
+            let message = alice_store.encrypt(&alice, &to_bob, None, text.as_bytes()).unwrap();
+            assert_eq!(bob_store.decrypt(&bob, &to_alice, &bob_prekeys, &message).unwrap(), text.as_bytes());
+            let message = bob_store.encrypt(&bob, &to_alice, None, text.as_bytes()).unwrap();
+            assert_eq!(alice_store.decrypt(&alice, &to_bob, &alice_prekeys, &message).unwrap(), text.as_bytes());
+        }
+        assert!(!std::fs::read_to_string(&alice_store.path).unwrap().contains("dh_secret"));
+        let _ = std::fs::remove_file(&alice_store.path);
+        let _ = std::fs::remove_file(&bob_store.path);
+    }
+
+    #[test]
+    fn test_prekey_start() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let alice_store = RatchetStore::at(temp("x3dh-alice"), &alice);
+        let bob_store = RatchetStore::at(temp("x3dh-bob"), &bob);
+        let bob_prekeys = PrekeyStore::at(temp("x3dh-bob-prekeys"), &bob);
+        let to_bob = Contact::new("bob".to_string(), bob.public_key().ed25519_key_raw().to_vec(), "");
+        let to_alice = Contact::new("alice".to_string(), alice.public_key().ed25519_key_raw().to_vec(), "");
+
+        let bundle = bob_prekeys.bundle(&bob, 1).unwrap();
+        assert!(alice_store.needs_start(&to_bob).unwrap());
+        let first = alice_store.encrypt(&alice, &to_bob, Some(&bundle), b"first").unwrap();
+        let second = alice_store.encrypt(&alice, &to_bob, None, b"second").unwrap();
+        assert!(!alice_store.needs_start(&to_bob).unwrap());
+        assert_eq!(first[0], FLAG_INITIAL | FLAG_PREKEY);
+
+        // a bundle of someone else is refused
+        let carol = generate_key_pair().unwrap();
+        let to_carol = Contact::new("carol".to_string(), carol.public_key().ed25519_key_raw().to_vec(), "");
+        assert!(alice_store.encrypt(&alice, &to_carol, Some(&bundle), b"x").is_err());
+
+        assert_eq!(bob_store.decrypt(&bob, &to_alice, &bob_prekeys, &second).unwrap(), b"second");
+        assert_eq!(bob_store.decrypt(&bob, &to_alice, &bob_prekeys, &first).unwrap(), b"first");
+        // the one-time prekey is gone, a replayed first message finds nothing to start from
+        bob_store.reset(&to_alice).unwrap();
+        assert!(bob_store.decrypt(&bob, &to_alice, &bob_prekeys, &first).is_err());
+    }
+}
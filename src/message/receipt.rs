@@ -0,0 +1,36 @@
+use std::time::Duration;
+
+/// How long a sender waits for the receiver to acknowledge a message
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(60);
+/// How long `send()` lingers after the acknowledgment for a read receipt
+pub const READ_RECEIPT_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// The receiver decrypted the message
+    Delivered,
+    /// The receiver decrypted the message and the user viewed it
+    Read,
+    /// No acknowledgment arrived in time
+    TimedOut,
+    /// The receiver refused the message
+    Rejected,
+}
+
+impl DeliveryStatus {
+    pub fn is_delivered(&self) -> bool {
+        matches!(self, DeliveryStatus::Delivered | DeliveryStatus::Read)
+    }
+}
+
+impl std::fmt::Display for DeliveryStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            DeliveryStatus::Delivered => "delivered",
+            DeliveryStatus::Read => "read",
+            DeliveryStatus::TimedOut => "timed out",
+            DeliveryStatus::Rejected => "rejected",
+        };
+        write!(f, "{}", s)
+    }
+}
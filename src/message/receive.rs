@@ -1,176 +1,230 @@
-use chrono::Utc;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use inquire::{Confirm, Text};
 use sha2::{Digest, Sha256};
-use snow::params::NoiseParams;
-use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::task::JoinSet;
 use crate::error::{Result, AiroiError};
-use crate::keys::contacts::{get_contacts, store_contacts, Contact};
-use crate::message::{read_frame, write_frame, Message};
-use crate::storage::fetch_local_keypair;
-use crate::tor::config::setup_tor;
-
-pub async fn handle_connection(
-    builder: snow::Builder<'_>,
-    socket: &mut tokio::net::TcpStream,
+use crate::keys::contacts::{get_contacts, store_contacts, update_contact, Contact};
+use crate::keys::KeyPair;
+use crate::message::Message;
+use crate::message::envelope::Envelope;
+use crate::message::fragment::{read_payload, write_payload, Reassembler, DEFAULT_MAX_MESSAGE_SIZE};
+use crate::message::handshake::{exchange_identity, respond};
+use crate::message::limits::{PeerHistory, ReceiverLimits};
+use crate::message::padding::PaddingPolicy;
+use crate::message::rekey::RekeyPolicy;
+use crate::message::replay::ReplayGuard;
+use crate::message::session::{EarlyData, Session, SessionEvent, SessionRegistry};
+use crate::transport::Stream;
+use crate::util::get_downloads_dir;
+
+/// Runs an inbound connection until it closes
+pub async fn handle_connection<S: Stream>(
+    keys: &KeyPair,
+    socket: S,
+    peer_addr: &str,
     contacts: &[Contact],
     tx: mpsc::Sender<Message>,
+    config: &ReceiveConfig,
 ) -> Result<()> {
-    let mut noise = builder.build_responder()?;
-    
-    // ==================== Handshake ====================
-    
-    // Handshake msg 1: read from initiator
-    let msg1 = read_frame(socket).await?;
-    let mut buf = vec![0u8; 1024];
-    let _payload1 = noise.read_message(&msg1, &mut buf)?;
-    // ignore for now!
-    
-    // Handshake msg 2: respond
-    let mut out_buf = vec![0u8; 1024];
-    let len2 = noise.write_message(&[], &mut out_buf)?;
-    write_frame(socket, &out_buf[..len2]).await?;
-    
-    // Handshake msg 3: read the final initiator message
-    let msg3 = read_frame(socket).await?;
-    let _payload3 = noise.read_message(&msg3, &mut buf)?;
-    
-    // ==================== Handshake Done ====================
+    if let Some(session) = accept_session(keys, socket, peer_addr, contacts, tx, config).await? {
+        session.closed().await;
+    }
+    Ok(())
+}
+
+/// Performs the responder handshake, verifies the sender's Ed25519 identity and starts a session
+/// with the sender. Returns `None` if the sender is unknown and the user did not trust it.
+/// Fails with `HandshakeTimeout` or `RateLimited` according to `config.limits`; the rate of the
+/// peer's address is checked before the handshake and the rate of its key right after.
+pub async fn accept_session<S: Stream>(
+    keys: &KeyPair,
+    mut socket: S,
+    peer_addr: &str,
+    contacts: &[Contact],
+    tx: mpsc::Sender<Message>,
+    config: &ReceiveConfig,
+) -> Result<Option<Session>> {
+    let limits = &config.limits;
+    config.peer_history.check_address(peer_addr, limits)?;
+    let (handshake, identity) = tokio::time::timeout(limits.handshake_timeout, async {
+        let mut handshake = respond(&mut socket, keys.private_key().x25519_key_raw()).await?;
+        let identity = exchange_identity(&mut socket, &mut handshake, keys, false).await?;
+        Ok::<_, AiroiError>((handshake, identity))
+    })
+        .await
+        .map_err(|_| AiroiError::HandshakeTimeout(limits.handshake_timeout))??;
+    let remote_static = handshake.remote_static.as_slice();
 
     let mut matched_contact: Option<Contact> = None;
-    let remote_static_opt = noise.get_remote_static();
-    if let Some(remote_static) = remote_static_opt {
-        let mut hasher = Sha256::new();
-        hasher.update(remote_static);
-        let fingerprint = hasher.finalize();
-        let fingerprint_bs58 = bs58::encode(fingerprint).into_string();
-        println!("Handshake complete; remote static key fingerprint (sha256 base58): {}", fingerprint_bs58);
-
-        for contact in contacts {
-            if contact.fingerprint_x() == fingerprint_bs58 {
-                    matched_contact = Some(contact.clone());
-                break; 
+    let mut hasher = Sha256::new();
+    hasher.update(remote_static);
+    let fingerprint = hasher.finalize();
+    let fingerprint_bs58 = bs58::encode(fingerprint).into_string();
+    println!("Handshake complete; remote static key fingerprint (sha256 base58): {}", fingerprint_bs58);
+    config.peer_history.check(&fingerprint_bs58, limits)?;
+
+    for contact in contacts {
+        if contact.fingerprint_x() == fingerprint_bs58 {
+            let mut contact = contact.clone();
+            if contact.bind_identity(&identity)? {
+                update_contact(&contact)?;
             }
+            matched_contact = Some(contact);
+            break;
         }
-        match matched_contact {
-            None => {
-                let peer_addr = socket.peer_addr()?.to_string();
-                let new_contact = match tofu(remote_static.to_vec(), &peer_addr) {
-                    Ok(new_contact) => new_contact,
-                    Err(AiroiError::SenderNotTrusted(_)) => {
-                        eprintln!("Closing connection. Sender not trusted.");
-                        return Ok(())
-                    }
-                    Err(e) => {
-                        return Err(e);
-                    }
-                };
-                matched_contact = Some(new_contact);
+    }
+    if matched_contact.is_none() {
+        // the prompts block on the terminal, keep them off the runtime's worker threads
+        let peer_addr = peer_addr.to_string();
+        let trusted = tokio::task::spawn_blocking(move || tofu(identity.to_vec(), &peer_addr))
+            .await
+            .unwrap_or_else(|e| Err(AiroiError::SenderNotTrusted(format!("prompt failed: {}", e))));
+        match trusted {
+            Ok(new_contact) => matched_contact = Some(new_contact),
+            Err(AiroiError::SenderNotTrusted(_)) => {}
+            Err(e) => {
+                return Err(e);
             }
-            _ => {}
         }
     }
-    else {
-        return Err(AiroiError::RemoteStatic("handshake did not reveal remote static key".to_string()));
-    }
-    
-    // Convert handshake state into transport mode (symmetric encryption)
-    let mut transport = noise.into_transport_mode()?;
 
-    loop {
-        let frame = match read_frame(socket).await {
-            Ok(frame) => frame,
-            Err(e) => {
-                if e.kind() == std::io::ErrorKind::UnexpectedEof {
-                    tokio::time::sleep(std::time::Duration::from_millis(50)).await;
-                    continue;
-                }
-                eprintln!("error reading frame: {:?}", e);
-                break;
+    let mut transport = handshake.transport;
+    let early = handshake.early_payload
+        .map(|chunk| {
+            let payload = Reassembler::new(config.max_message_size)
+                .push(&chunk)?
+                .ok_or_else(|| AiroiError::Protocol("early data must be a single chunk".to_string()))?;
+            Envelope::decode(&payload)
+        })
+        .transpose()?;
+
+    let Some(contact) = matched_contact else {
+        eprintln!("Closing connection. Sender not trusted.");
+        let envelope = match early {
+            Some(envelope) => envelope,
+            None => {
+                let mut reassembler = Reassembler::new(config.max_message_size);
+                let read = read_payload(&mut socket, &mut transport, &mut reassembler);
+                let payload = tokio::time::timeout(limits.handshake_timeout, read)
+                    .await
+                    .map_err(|_| AiroiError::HandshakeTimeout(limits.handshake_timeout))??;
+                Envelope::decode(&payload)?
             }
         };
-        
-        let mut plaintext = vec![0u8; 65535]; // big enough buffer
-        match transport.read_message(&frame, &mut plaintext) { 
-            Ok(sz) => {
-                plaintext.truncate(sz);
-                let s = String::from_utf8_lossy(&plaintext).to_string();
-                let message = Message {
-                    sender: matched_contact.clone().unwrap(), // safe, we would have returned an error if this was None
-                    message: s,
-                    received: Utc::now().to_rfc3339(),
-                };
+        write_payload(&mut socket, &mut transport, &Envelope::reject(envelope.id).encode(), PaddingPolicy::default()).await?;
+        return Ok(None);
+    };
 
-                if tx.send(message).await.is_err() {
-                    eprintln!("receiver dropped, stopping connection");
-                    break;
-                }
-            }
-            Err(e) => {
-                eprintln!("error reading message: {:?}", e);
-                break;
-            }
-        }
-    }
-    
-    Ok(())
+    let early = match early {
+        Some(envelope) => EarlyData::Received(envelope),
+        None => EarlyData::None,
+    };
+    Ok(Some(Session::spawn(keys, contact, socket, transport, tx, config, early)))
 }
 
 
 pub const DEFAULT_ADDRESS: &str = "0.0.0.0:4444";
+/// A `Ping` is sent after this long without hearing from the contact
+pub const DEFAULT_KEEPALIVE: Duration = Duration::from_secs(30);
+/// A session ends after this long without hearing from the contact
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+const EVENT_CAPACITY: usize = 64;
 
-pub async fn receive(addr: Option<String>, tx: mpsc::Sender<Message>) -> Result<()> {
-    let addr = addr.unwrap_or(DEFAULT_ADDRESS.to_string());
-
-    // Tor config
-    let torrc = setup_tor().await?;
-    let hidden_service_dir = crate::tor::config::get_hidden_service_dir();
-    // Tor start
-    let mut _tor_child = crate::tor::config::start_tor_daemon(&torrc)?;
-    // Wait for onion address
-    let onion_addr = crate::tor::config::wait_for_onion(&hidden_service_dir).await?;
-    println!("Your onion service address is: {}", onion_addr);
+#[derive(Debug, Clone)]
+pub struct ReceiveConfig {
+    /// Maximum size in bytes of a single reassembled message
+    pub max_message_size: usize,
+    /// Where accepted files are stored
+    pub downloads_dir: PathBuf,
+    /// When sessions replace their sending keys
+    pub rekey: RekeyPolicy,
+    pub keepalive: Duration,
+    pub idle_timeout: Duration,
+    /// Sessions started with this config publish their lifecycle here
+    pub events: broadcast::Sender<SessionEvent>,
+    pub limits: ReceiverLimits,
+    pub peer_history: PeerHistory,
+    /// Message ids already delivered, so resent messages are surfaced only once
+    pub replay: ReplayGuard,
+}
 
-    let key_pair = fetch_local_keypair()?;
-    let local_priv = key_pair.private_key().x25519_key_raw().to_vec();
+impl Default for ReceiveConfig {
+    fn default() -> Self {
+        ReceiveConfig {
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            downloads_dir: get_downloads_dir(),
+            rekey: RekeyPolicy::default(),
+            keepalive: DEFAULT_KEEPALIVE,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            limits: ReceiverLimits::default(),
+            peer_history: PeerHistory::default(),
+            replay: ReplayGuard::default(),
+        }
+    }
+}
 
+/// Listens for inbound connections on the registry's transport; every accepted session is
+/// added to the registry so replies to that contact reuse it
+pub async fn receive(addr: Option<String>, registry: Arc<SessionRegistry>) -> Result<()> {
+    let addr = addr.unwrap_or(DEFAULT_ADDRESS.to_string());
     let contacts = get_contacts()?;
-    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse()?;
 
-    let listener = TcpListener::bind(&addr).await?;
+    let mut listener = match registry.carrier().listen(&addr).await {
+        Err(AiroiError::Io(e)) if e.kind() == std::io::ErrorKind::AddrInUse => {
+            return Err(AiroiError::Io(std::io::Error::new(e.kind(), format!(
+                "{} is already in use, e.g. by another receive or chat; pick another address", addr
+            ))));
+        }
+        listener => listener?,
+    };
     println!("aioroi receiver listening on {}", addr);
 
+    // every open connection holds a permit until its session ends
+    let max_connections = registry.config().limits.max_connections;
+    let permits = Arc::new(Semaphore::new(max_connections));
+    // connections are tracked so finished ones are reaped and the rest aborted when receiving stops
+    let mut connections = JoinSet::new();
     loop {
-        let (mut socket, peer_addr) = listener.accept().await?;
-        println!("New connection from {}", peer_addr);
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer_addr) = accepted?;
+                let Ok(permit) = permits.clone().try_acquire_owned() else {
+                    eprintln!("refusing connection from {}: {}", peer_addr, AiroiError::TooManyConnections { max: max_connections });
+                    continue;
+                };
+                println!("New connection from {}", peer_addr);
 
-        let contacts = contacts.clone();
-        let local_priv = local_priv.clone();
-        let params = params.clone();
-        let tx_clone = tx.clone();
+                let contacts = contacts.clone();
+                let registry = registry.clone();
 
-        tokio::spawn(async move {
-            let builder = snow::Builder::new(params);
-            let builder = match builder.local_private_key(&local_priv) {
-                Ok(builder) => builder,
-                Err(e) => {
-                    eprintln!("error setting local private key: {:?}", e);
-                    return;
-                }
-            };
-
-            if let Err(e) = handle_connection(builder, &mut socket, &contacts, tx_clone).await {
-                eprintln!("connection error from {}: {:?}", peer_addr, e);
+                connections.spawn(async move {
+                    match accept_session(registry.keys(), socket, &peer_addr, &contacts, registry.inbound(), registry.config()).await {
+                        Ok(Some(session)) => {
+                            let session = Arc::new(session);
+                            registry.insert(session.clone());
+                            session.closed().await;
+                        }
+                        Ok(None) => {}
+                        Err(e) => eprintln!("connection error from {}: {}", peer_addr, e),
+                    }
+                    drop(permit);
+                });
             }
-        });
+            Some(_) = connections.join_next() => {}
+        }
     }
 }
 
-pub fn tofu(raw_remote_static: Vec<u8>, peer_addr: &str) -> Result<Contact> {
+/// Stores an unknown sender whose Ed25519 identity was proven in the handshake, if the user agrees
+pub fn tofu(raw_ed_public_key: Vec<u8>, peer_addr: &str) -> Result<Contact> {
     let mut contacts = get_contacts()?;
     let name = prompt_tofu()?;
 
-    let new_contact = Contact::new_tofu(name.to_string(), raw_remote_static, peer_addr);
+    let new_contact = Contact::new(name.to_string(), raw_ed_public_key, peer_addr);
     contacts.push(new_contact.clone());
     store_contacts(contacts)?;
     println!("Contact '{}' added", name);
@@ -193,3 +247,81 @@ pub fn prompt_tofu() -> Result<String> {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::keys::key_gen::generate_key_pair;
+    use crate::message::handshake::initiate_xx;
+
+    #[tokio::test]
+    async fn test_silent_peer_hits_handshake_timeout() {
+        let keys = generate_key_pair().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let _silent = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        let timeout = Duration::from_millis(100);
+        let config = ReceiveConfig {
+            limits: ReceiverLimits { handshake_timeout: timeout, ..ReceiverLimits::default() },
+            ..ReceiveConfig::default()
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        let result = accept_session(&keys, socket, "127.0.0.1", &[], tx, &config).await;
+        assert!(matches!(result, Err(AiroiError::HandshakeTimeout(t)) if t == timeout));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_sender_that_stays_silent_times_out() {
+        let keys = generate_key_pair().unwrap();
+        let stranger = generate_key_pair().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (socket, _) = listener.accept().await.unwrap();
+
+        // the stranger completes the handshake but never sends the message to refuse
+        tokio::spawn(async move {
+            let mut handshake = initiate_xx(&mut stream, stranger.private_key().x25519_key_raw()).await.unwrap();
+            exchange_identity(&mut stream, &mut handshake, &stranger, true).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+        let timeout = Duration::from_millis(300);
+        let config = ReceiveConfig {
+            limits: ReceiverLimits { handshake_timeout: timeout, ..ReceiverLimits::default() },
+            ..ReceiveConfig::default()
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        let result = accept_session(&keys, socket, "127.0.0.1", &[], tx, &config).await;
+        assert!(matches!(result, Err(AiroiError::HandshakeTimeout(t)) if t == timeout));
+    }
+
+    #[tokio::test]
+    async fn test_address_in_use_is_explained() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = taken.local_addr().unwrap().to_string();
+        let (tx, _rx) = mpsc::channel(1);
+        let registry = SessionRegistry::new(Arc::new(crate::transport::TcpTransport), generate_key_pair().unwrap(), tx, ReceiveConfig::default());
+        let error = receive(Some(addr.clone()), registry).await.unwrap_err();
+        assert!(error.to_string().contains(&format!("{} is already in use", addr)));
+    }
+
+    #[tokio::test]
+    async fn test_address_rate_applies_before_handshake() {
+        let keys = generate_key_pair().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = ReceiveConfig {
+            limits: ReceiverLimits { address_rate: 1, handshake_timeout: Duration::from_millis(100), ..ReceiverLimits::default() },
+            ..ReceiveConfig::default()
+        };
+        let (tx, _rx) = mpsc::channel(1);
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let _silent = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+            let (socket, peer_addr) = listener.accept().await.unwrap();
+            results.push(accept_session(&keys, socket, &peer_addr.to_string(), &[], tx.clone(), &config).await);
+        }
+        assert!(matches!(results[0], Err(AiroiError::HandshakeTimeout(_))));
+        assert!(matches!(results[1], Err(AiroiError::RateLimited { max: 1, .. })));
+    }
+}
@@ -0,0 +1,157 @@
+//! Periodic rekeying of Noise transport keys.
+//!
+//! Each side rekeys its own sending direction: once the `RekeyPolicy` says so it sends a
+//! `Control::Rekey`, still encrypted under the old key, and switches to the next key right after.
+//! The peer switches its receiving key when it decrypts that message. Both directions are
+//! independent, so no round trip is needed and a compromised key only exposes the traffic sent
+//! since the previous rekey.
+use std::time::Duration;
+use snow::TransportState;
+use tokio::io::AsyncWrite;
+use tokio::time::Instant;
+use crate::error::Result;
+use crate::message::control::Control;
+use crate::message::envelope::Envelope;
+use crate::message::fragment::write_payload;
+use crate::message::padding::PaddingPolicy;
+
+pub const DEFAULT_REKEY_MESSAGES: u64 = 1000;
+pub const DEFAULT_REKEY_BYTES: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_REKEY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// When the sending key is replaced; whichever limit is reached first triggers a rekey
+#[derive(Debug, Clone, Copy)]
+pub struct RekeyPolicy {
+    pub messages: u64,
+    pub bytes: u64,
+    /// Only applies if something was sent under the current key
+    pub interval: Duration,
+}
+
+impl Default for RekeyPolicy {
+    fn default() -> Self {
+        RekeyPolicy {
+            messages: DEFAULT_REKEY_MESSAGES,
+            bytes: DEFAULT_REKEY_BYTES,
+            interval: DEFAULT_REKEY_INTERVAL,
+        }
+    }
+}
+
+/// Tracks what was sent under the current key
+pub(crate) struct Rekeyer {
+    policy: RekeyPolicy,
+    /// Applied to everything written through this rekeyer
+    padding: PaddingPolicy,
+    messages: u64,
+    bytes: u64,
+    since: Instant,
+}
+
+impl Rekeyer {
+    pub fn new(policy: RekeyPolicy, padding: PaddingPolicy) -> Rekeyer {
+        Rekeyer { policy, padding, messages: 0, bytes: 0, since: Instant::now() }
+    }
+
+    /// When the interval of the current key runs out
+    pub fn deadline(&self) -> Instant {
+        self.since + self.policy.interval
+    }
+
+    fn due(&self) -> bool {
+        self.messages >= self.policy.messages
+            || self.bytes >= self.policy.bytes
+            || (self.messages > 0 && Instant::now() >= self.deadline())
+    }
+
+    /// Counts a payload sent under the current key
+    pub fn record(&mut self, len: usize) {
+        self.messages += 1;
+        self.bytes += len as u64;
+    }
+
+    /// Whether a rekey is due; if so the counters start over for the next key.
+    /// An idle key just starts a new interval.
+    pub fn take_due(&mut self) -> bool {
+        if self.due() {
+            self.messages = 0;
+            self.bytes = 0;
+            self.since = Instant::now();
+            return true;
+        }
+        if self.messages == 0 && Instant::now() >= self.deadline() {
+            self.since = Instant::now();
+        }
+        false
+    }
+
+    /// Writes an envelope and rekeys afterwards if the policy says so
+    pub async fn write<W: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut W,
+        transport: &mut TransportState,
+        envelope: &Envelope,
+    ) -> Result<()> {
+        let payload = envelope.encode();
+        write_payload(stream, transport, &payload, self.padding).await?;
+        self.record(payload.len());
+        self.rekey_if_due(stream, transport).await
+    }
+
+    /// Rekeys if a limit was reached
+    pub async fn rekey_if_due<W: AsyncWrite + Unpin>(
+        &mut self,
+        stream: &mut W,
+        transport: &mut TransportState,
+    ) -> Result<()> {
+        if self.take_due() {
+            write_payload(stream, transport, &Control::Rekey.into_envelope().encode(), self.padding).await?;
+            transport.rekey_outgoing();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::key_gen::generate_key_pair;
+    use crate::message::envelope::MessageKind;
+    use crate::message::fragment::{read_payload, Reassembler, DEFAULT_MAX_MESSAGE_SIZE, NOISE_MAX_MSG_LEN};
+    use crate::message::handshake::{initiate_xx, respond};
+
+    #[tokio::test]
+    async fn test_rekey_after_message_limit() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let (mut a, mut b) = tokio::io::duplex(NOISE_MAX_MSG_LEN * 16);
+
+        let bob_priv = bob.private_key().x25519_key_raw().to_vec();
+        let responder = tokio::spawn(async move { respond(&mut b, &bob_priv).await.map(|h| (b, h)).unwrap() });
+        let mut initiator = initiate_xx(&mut a, alice.private_key().x25519_key_raw()).await.unwrap();
+        let (mut b, mut responder) = responder.await.unwrap();
+
+        let mut rekeyer = Rekeyer::new(RekeyPolicy { messages: 2, ..RekeyPolicy::default() }, PaddingPolicy::Buckets);
+        for i in 0..5 {
+            rekeyer.write(&mut a, &mut initiator.transport, &Envelope::text(&i.to_string())).await.unwrap();
+        }
+
+        let mut reassembler = Reassembler::new(DEFAULT_MAX_MESSAGE_SIZE);
+        let mut texts = Vec::new();
+        let mut rekeys = 0;
+        while texts.len() < 5 {
+            let payload = read_payload(&mut b, &mut responder.transport, &mut reassembler).await.unwrap();
+            let envelope = Envelope::decode(&payload).unwrap();
+            match envelope.kind {
+                MessageKind::Control => {
+                    assert_eq!(Control::decode(&envelope.body).unwrap(), Control::Rekey);
+                    responder.transport.rekey_incoming();
+                    rekeys += 1;
+                }
+                _ => texts.push(envelope.body_text()),
+            }
+        }
+        assert_eq!(texts, ["0", "1", "2", "3", "4"]);
+        assert_eq!(rekeys, 2);
+    }
+}
@@ -0,0 +1,150 @@
+//! Replay protection across sessions.
+//!
+//! Noise nonces only protect a single transport. A message resent after a reconnect, or replayed
+//! by someone who recorded it, arrives on a fresh transport with the same id. The receiver
+//! remembers the ids of the messages it handed on, per contact. The window is bounded by count
+//! and age, and anything older than the window is refused because a duplicate could no longer be
+//! detected.
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::message::envelope::{Envelope, MessageId};
+
+pub const DEFAULT_REPLAY_ENTRIES: usize = 4096;
+pub const DEFAULT_REPLAY_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Freshness {
+    /// First time this id arrives
+    New,
+    /// Seen before; acknowledge again but do not surface it
+    Duplicate,
+    /// Older than the window, it cannot be told apart from a replay
+    Stale,
+}
+
+#[derive(Debug, Default)]
+struct Window {
+    seen: HashSet<MessageId>,
+    order: VecDeque<(MessageId, i64)>,
+    /// Timestamps at or below this are outside the window
+    floor: i64,
+}
+
+/// Seen message ids per contact, shared by every session using the same config
+#[derive(Debug, Clone)]
+pub struct ReplayGuard {
+    max_entries: usize,
+    max_age: Duration,
+    windows: Arc<Mutex<HashMap<String, Window>>>,
+}
+
+impl Default for ReplayGuard {
+    fn default() -> Self {
+        ReplayGuard::new(DEFAULT_REPLAY_ENTRIES, DEFAULT_REPLAY_AGE)
+    }
+}
+
+impl ReplayGuard {
+    pub fn new(max_entries: usize, max_age: Duration) -> ReplayGuard {
+        ReplayGuard { max_entries, max_age, windows: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Checks an envelope from the contact with the given fingerprint without remembering it;
+    /// see `commit`
+    pub fn check(&self, contact: &str, envelope: &Envelope) -> Freshness {
+        let oldest = chrono::Utc::now().timestamp_millis() - self.max_age.as_millis() as i64;
+        let windows = self.windows.lock().unwrap();
+        let Some(window) = windows.get(contact) else {
+            return if envelope.timestamp <= oldest { Freshness::Stale } else { Freshness::New };
+        };
+        if window.seen.contains(&envelope.id) {
+            return Freshness::Duplicate;
+        }
+        if envelope.timestamp <= window.floor.max(oldest) {
+            return Freshness::Stale;
+        }
+        Freshness::New
+    }
+
+    /// Remembers the id of an envelope once it was handed on, so a resend of one that was not
+    /// is surfaced rather than acknowledged as a duplicate
+    pub fn commit(&self, contact: &str, envelope: &Envelope) {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut windows = self.windows.lock().unwrap();
+        let window = windows.entry(contact.to_string()).or_default();
+
+        let oldest = now - self.max_age.as_millis() as i64;
+        while window.order.front().is_some_and(|(_, timestamp)| *timestamp < oldest) {
+            let (id, _) = window.order.pop_front().unwrap();
+            window.seen.remove(&id);
+        }
+        window.floor = window.floor.max(oldest);
+
+        if !window.seen.insert(envelope.id) {
+            return;
+        }
+        // a timestamp ahead of our clock must not raise the floor past messages sent since
+        window.order.push_back((envelope.id, envelope.timestamp.min(now)));
+        while window.order.len() > self.max_entries {
+            let (id, timestamp) = window.order.pop_front().unwrap();
+            window.seen.remove(&id);
+            window.floor = window.floor.max(timestamp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Checks and, if new, commits an envelope
+    fn accept(guard: &ReplayGuard, contact: &str, envelope: &Envelope) -> Freshness {
+        let freshness = guard.check(contact, envelope);
+        if freshness == Freshness::New {
+            guard.commit(contact, envelope);
+        }
+        freshness
+    }
+
+    #[test]
+    fn test_duplicates_and_stale_messages() {
+        let guard = ReplayGuard::new(2, DEFAULT_REPLAY_AGE);
+        let first = Envelope::text("a");
+        assert_eq!(accept(&guard, "alice", &first), Freshness::New);
+        assert_eq!(accept(&guard, "alice", &first), Freshness::Duplicate);
+        // windows are per contact
+        assert_eq!(accept(&guard, "bob", &first), Freshness::New);
+
+        let mut old = Envelope::text("old");
+        old.timestamp -= DEFAULT_REPLAY_AGE.as_millis() as i64 + 1000;
+        assert_eq!(accept(&guard, "alice", &old), Freshness::Stale);
+
+        // once evicted by count, a replay of the first message is refused rather than surfaced
+        let mut second = Envelope::text("b");
+        second.timestamp = first.timestamp + 1;
+        let mut third = Envelope::text("c");
+        third.timestamp = first.timestamp + 2;
+        assert_eq!(accept(&guard, "alice", &second), Freshness::New);
+        assert_eq!(accept(&guard, "alice", &third), Freshness::New);
+        assert_eq!(accept(&guard, "alice", &first), Freshness::Stale);
+    }
+
+    #[test]
+    fn test_unhanded_messages_and_future_timestamps() {
+        let guard = ReplayGuard::new(1, DEFAULT_REPLAY_AGE);
+        // a message checked but never handed on is new again when resent
+        let dropped = Envelope::text("dropped");
+        assert_eq!(guard.check("alice", &dropped), Freshness::New);
+        assert_eq!(guard.check("alice", &dropped), Freshness::New);
+
+        // evicting a message from the future does not make honest messages stale
+        let mut future = Envelope::text("future");
+        future.timestamp += 24 * 60 * 60 * 1000;
+        assert_eq!(accept(&guard, "alice", &future), Freshness::New);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(accept(&guard, "alice", &Envelope::text("now")), Freshness::New);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(accept(&guard, "alice", &Envelope::text("later")), Freshness::New);
+    }
+}
@@ -0,0 +1,134 @@
+//! Deleting messages from the local history.
+//!
+//! Disappearing messages carry their timer in the envelope and are deleted once it runs out.
+//! Independently, the retention set with `set_retention` deletes every message older than a
+//! number of days. It is kept in `retention.json` in the airoi directory. `run_retention` applies
+//! both while `receive` or `chat` runs, `open_history` whenever another command opens the
+//! history, and both keep the search index in step.
+use std::sync::Arc;
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::keys::KeyPair;
+use crate::message::history::History;
+use crate::message::search::SearchIndex;
+use crate::util::get_airoi_dir;
+
+/// The history is pruned at least this often, to pick up changes of the retention
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+const DAY_MILLIS: i64 = 24 * 60 * 60 * 1000;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Retention {
+    /// Messages sent longer ago are deleted, all are kept if `None`
+    pub max_age_days: Option<u32>,
+}
+
+impl Retention {
+    /// Unix milliseconds of the oldest message kept at `now`
+    fn oldest(&self, now: i64) -> Option<i64> {
+        self.max_age_days.map(|days| now - days as i64 * DAY_MILLIS)
+    }
+}
+
+pub fn get_retention() -> Result<Retention> {
+    let path = get_airoi_dir().join("retention.json");
+    if !path.exists() {
+        return Ok(Retention::default());
+    }
+    Ok(serde_json::from_str(&std::fs::read_to_string(&path)?)?)
+}
+
+pub fn set_retention(retention: Retention) -> Result<()> {
+    let path = get_airoi_dir().join("retention.json");
+    std::fs::write(&path, serde_json::to_string_pretty(&retention)?)?;
+    Ok(())
+}
+
+/// Deletes expired messages and those older than the retention from the history and the search
+/// index. Returns the number of messages deleted.
+pub fn prune(history: &History, index: &SearchIndex, retention: &Retention) -> Result<usize> {
+    let deleted = history.prune(retention.oldest(chrono::Utc::now().timestamp_millis()))?;
+    if deleted > 0 {
+        index.sync(history)?;
+    }
+    Ok(deleted)
+}
+
+/// Opens the local history with expired messages and those past the retention already deleted,
+/// from the search index too
+pub fn open_history(keys: &KeyPair) -> Result<History> {
+    let history = History::open(keys);
+    prune(&history, &SearchIndex::open(keys), &get_retention()?)?;
+    Ok(history)
+}
+
+/// Prunes the history whenever a disappearing message expires, and at least every minute
+pub async fn run_retention(history: Arc<History>, index: Arc<SearchIndex>) {
+    loop {
+        // read every time, so a changed retention applies without a restart
+        if let Err(e) = get_retention().and_then(|retention| prune(&history, &index, &retention)) {
+            eprintln!("retention error: {}", e);
+        }
+        let now = chrono::Utc::now().timestamp_millis();
+        let wait = match history.next_expiry() {
+            Ok(Some(next)) => Duration::from_millis((next - now).max(0) as u64).min(PRUNE_INTERVAL),
+            _ => PRUNE_INTERVAL,
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use crate::keys::contacts::Contact;
+    use crate::keys::key_gen::generate_key_pair;
+    use crate::message::Message;
+    use crate::message::envelope::Envelope;
+    use crate::message::history::{Conversation, DeliveryState};
+    use crate::message::search::SearchFilter;
+
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("airoi-retention-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_expired_and_old_messages_are_pruned() {
+        let keys = generate_key_pair().unwrap();
+        let (history_path, index_path) = (temp("history"), temp("index"));
+        let history = History::at(history_path.clone(), &keys);
+        let index = SearchIndex::at(index_path.clone(), &keys);
+        let bob = Contact::new("bob".to_string(), generate_key_pair().unwrap().public_key().ed25519_key_raw().to_vec(), "bob");
+        let with_bob = Conversation::Contact(bob.fingerprint_x().to_string());
+
+        let mut old = Envelope::text("an old secret");
+        old.timestamp -= 3 * DAY_MILLIS;
+        history.record_sent(&bob, &old, DeliveryState::Delivered).unwrap();
+        let mut burnt = Envelope::text("a burnt secret").expiring(Some(60));
+        burnt.timestamp -= 61_000;
+        history.record_sent(&bob, &burnt, DeliveryState::Delivered).unwrap();
+        let fresh = Message::from_envelope(bob.clone(), &Envelope::text("a fresh secret").expiring(Some(60)));
+        history.record_received(&fresh).unwrap();
+        assert_eq!(index.search(&history, "secret", &SearchFilter::default(), 10).unwrap().len(), 2);
+
+        // the expired message is hidden before it is pruned
+        assert_eq!(history.conversation(&with_bob, None, 10).unwrap().len(), 2);
+        assert_eq!(prune(&history, &index, &Retention::default()).unwrap(), 1);
+        assert_eq!(prune(&history, &index, &Retention { max_age_days: Some(2) }).unwrap(), 1);
+        let left = history.conversation(&with_bob, None, 10).unwrap();
+        assert_eq!(left.iter().map(|e| e.id()).collect::<Vec<_>>(), vec![fresh.id]);
+        assert!(left[0].expires_at.is_some_and(|at| at > chrono::Utc::now().timestamp_millis()));
+        assert_eq!(index.search(&history, "secret", &SearchFilter::default(), 10).unwrap().len(), 1);
+
+        // nothing left to prune, the sealed file is not rewritten under a fresh nonce
+        let sealed = std::fs::read(&history_path).unwrap();
+        assert_eq!(prune(&history, &index, &Retention { max_age_days: Some(2) }).unwrap(), 0);
+        assert_eq!(std::fs::read(&history_path).unwrap(), sealed);
+        let _ = std::fs::remove_file(history_path);
+        let _ = std::fs::remove_file(index_path);
+    }
+}
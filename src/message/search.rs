@@ -0,0 +1,225 @@
+//! Full-text search over the message history.
+//!
+//! An inverted index maps every word of every recorded message to the messages containing it. It
+//! is kept in `search_index.json` in the airoi directory, encrypted under a key derived from the
+//! local key pair like the history itself. The history stays the source of truth: before every
+//! search the index picks up messages recorded since and forgets deleted ones, and it can be
+//! rebuilt from the history at any time.
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::error::Result;
+use crate::keys::KeyPair;
+use crate::message::history::{Conversation, Direction, History, HistoryEntry};
+use crate::storage::sealed::{lock_sealed, read_sealed, storage_key, write_sealed};
+use crate::util::get_airoi_dir;
+
+/// Lowercase words of a text, split at everything that is not a letter or digit
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+}
+
+/// Identifies a history entry; a message id alone is shared by both directions of a self-send
+fn entry_key(entry: &HistoryEntry) -> String {
+    let direction = match entry.direction {
+        Direction::Sent => "sent",
+        Direction::Received => "received",
+    };
+    format!("{}/{}", entry.id(), direction)
+}
+
+#[derive(Default, Serialize, Deserialize)]
+struct Index {
+    /// Word to the entries containing it
+    words: BTreeMap<String, BTreeSet<String>>,
+    /// Every indexed entry
+    entries: BTreeSet<String>,
+}
+
+impl Index {
+    fn add(&mut self, entry: &HistoryEntry) {
+        let key = entry_key(entry);
+        for word in words(&entry.text) {
+            self.words.entry(word).or_default().insert(key.clone());
+        }
+        self.entries.insert(key);
+    }
+
+    /// Indexes new entries and forgets deleted ones. Returns whether anything changed.
+    fn sync(&mut self, entries: &[HistoryEntry]) -> bool {
+        let keys: BTreeSet<String> = entries.iter().map(entry_key).collect();
+        let deleted: BTreeSet<String> = self.entries.difference(&keys).cloned().collect();
+        if !deleted.is_empty() {
+            self.entries.retain(|key| !deleted.contains(key));
+            for indexed in self.words.values_mut() {
+                indexed.retain(|key| !deleted.contains(key));
+            }
+            self.words.retain(|_, indexed| !indexed.is_empty());
+        }
+        let mut added = false;
+        for entry in entries {
+            if !self.entries.contains(&entry_key(entry)) {
+                self.add(entry);
+                added = true;
+            }
+        }
+        added || !deleted.is_empty()
+    }
+
+    /// Entries containing a word starting with every word of the query
+    fn lookup(&self, query: &str) -> BTreeSet<String> {
+        let mut found: Option<BTreeSet<String>> = None;
+        for prefix in words(query) {
+            let matches: BTreeSet<String> = self.words.range(prefix.clone()..)
+                .take_while(|(word, _)| word.starts_with(&prefix))
+                .flat_map(|(_, indexed)| indexed.iter().cloned())
+                .collect();
+            found = Some(match found {
+                Some(found) => found.intersection(&matches).cloned().collect(),
+                None => matches,
+            });
+        }
+        found.unwrap_or_default()
+    }
+}
+
+/// Which messages a search looks at
+#[derive(Debug, Clone, Default)]
+pub struct SearchFilter {
+    pub conversation: Option<Conversation>,
+    /// Unix milliseconds, messages sent at or after
+    pub since: Option<i64>,
+    /// Unix milliseconds, messages sent before
+    pub until: Option<i64>,
+    pub direction: Option<Direction>,
+}
+
+impl SearchFilter {
+    fn includes(&self, entry: &HistoryEntry) -> bool {
+        self.conversation.as_ref().is_none_or(|c| c.includes(entry))
+            && self.since.is_none_or(|since| entry.sent_at >= since)
+            && self.until.is_none_or(|until| entry.sent_at < until)
+            && self.direction.is_none_or(|direction| entry.direction == direction)
+    }
+}
+
+pub struct SearchIndex {
+    path: PathBuf,
+    key: [u8; 32],
+}
+
+impl SearchIndex {
+    /// The index in the airoi directory
+    pub fn open(keys: &KeyPair) -> SearchIndex {
+        SearchIndex::at(get_airoi_dir().join("search_index.json"), keys)
+    }
+
+    pub fn at(path: PathBuf, keys: &KeyPair) -> SearchIndex {
+        SearchIndex { path, key: storage_key(keys, "search index") }
+    }
+
+    /// Indexes new messages and forgets deleted and expired ones
+    pub fn sync(&self, history: &History) -> Result<()> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut index = self.load()?;
+        if index.sync(&history.entries()?) {
+            self.store(&index)?;
+        }
+        Ok(())
+    }
+
+    /// Builds the index from scratch, returning the number of messages indexed
+    pub fn rebuild(&self, history: &History) -> Result<usize> {
+        let _guard = lock_sealed(&self.path)?;
+        let mut index = Index::default();
+        index.sync(&history.entries()?);
+        self.store(&index)?;
+        Ok(index.entries.len())
+    }
+
+    /// The `limit` most recent messages matching the query and the filter, newest first. Every
+    /// word of the query has to start a word of the message, ignoring case.
+    pub fn search(&self, history: &History, query: &str, filter: &SearchFilter, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let _guard = lock_sealed(&self.path)?;
+        let entries = history.entries()?;
+        let mut index = self.load()?;
+        if index.sync(&entries) {
+            self.store(&index)?;
+        }
+        let found = index.lookup(query);
+        let mut by_key: HashMap<String, HistoryEntry> = entries.into_iter()
+            .map(|entry| (entry_key(&entry), entry))
+            .collect();
+        let mut results: Vec<HistoryEntry> = found.iter()
+            .filter_map(|key| by_key.remove(key))
+            .filter(|entry| filter.includes(entry))
+            .collect();
+        results.sort_by_key(|entry| std::cmp::Reverse(entry.sent_at));
+        results.truncate(limit);
+        Ok(results)
+    }
+
+    fn load(&self) -> Result<Index> {
+        match read_sealed(&self.path, &self.key)? {
+            Some(plain_text) => Ok(serde_json::from_slice(&plain_text)?),
+            None => Ok(Index::default()),
+        }
+    }
+
+    fn store(&self, index: &Index) -> Result<()> {
+        write_sealed(&self.path, &self.key, &serde_json::to_vec(index)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::contacts::Contact;
+    use crate::keys::key_gen::generate_key_pair;
+    use crate::message::Message;
+    use crate::message::envelope::Envelope;
+    use crate::message::history::DeliveryState;
+
+    fn temp(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("airoi-search-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_search_follows_the_history() {
+        let keys = generate_key_pair().unwrap();
+        let (history_path, index_path) = (temp("history"), temp("index"));
+        let history = History::at(history_path.clone(), &keys);
+        let index = SearchIndex::at(index_path.clone(), &keys);
+        let bob = Contact::new("bob".to_string(), generate_key_pair().unwrap().public_key().ed25519_key_raw().to_vec(), "bob");
+
+        let lunch = Envelope::text("Lunch at the harbour tomorrow?");
+        history.record_sent(&bob, &lunch, DeliveryState::Delivered).unwrap();
+        let reply = Message::from_envelope(bob.clone(), &Envelope::text("Harbour sounds good, 12:30"));
+        history.record_received(&reply).unwrap();
+
+        let all = SearchFilter::default();
+        assert_eq!(index.search(&history, "harb", &all, 10).unwrap().len(), 2);
+        assert!(!std::fs::read_to_string(&index_path).unwrap().contains("harbour"));
+        let found = index.search(&history, "HARBOUR lunch", &all, 10).unwrap();
+        assert_eq!(found.iter().map(HistoryEntry::id).collect::<Vec<_>>(), vec![lunch.id]);
+        let received = SearchFilter { direction: Some(Direction::Received), ..SearchFilter::default() };
+        assert_eq!(index.search(&history, "harbour", &received, 10).unwrap()[0].id(), reply.id);
+        let elsewhere = SearchFilter { conversation: Some(Conversation::Contact("someone".to_string())), ..SearchFilter::default() };
+        assert!(index.search(&history, "harbour", &elsewhere, 10).unwrap().is_empty());
+        assert!(index.search(&history, "dinner", &all, 10).unwrap().is_empty());
+
+        // deleted messages drop out of the index, which can be rebuilt from the history
+        assert!(history.delete(&lunch.id.to_string()).unwrap());
+        assert_eq!(index.search(&history, "harbour", &all, 10).unwrap().len(), 1);
+        assert!(!index.load().unwrap().words.contains_key("lunch"));
+        let _ = std::fs::remove_file(&index_path);
+        assert_eq!(index.rebuild(&history).unwrap(), 1);
+        assert_eq!(index.search(&history, "12", &all, 10).unwrap()[0].id(), reply.id);
+        let _ = std::fs::remove_file(history_path);
+        let _ = std::fs::remove_file(index_path);
+    }
+}
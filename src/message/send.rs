@@ -1,70 +1,119 @@
-use snow::Builder;
-use snow::params::NoiseParams;
-use tokio::net::TcpStream;
-use tokio_socks::tcp::Socks5Stream;
+use snow::TransportState;
+use tokio::sync::mpsc;
 use crate::error::{AiroiError, Result};
-use crate::keys::contacts::Contact;
+use crate::keys::{Key, KeyPair};
+use crate::keys::contacts::{update_contact, Contact};
 use crate::keys::key_gen::{get_fingerprint};
-use crate::message::{read_frame, write_frame};
+use crate::message::envelope::{Envelope, MessageKind};
+use crate::message::fragment::fragment;
+use crate::message::handshake::{exchange_identity, fits_early, initiate_ik, initiate_xx};
+use crate::message::receipt::{DeliveryStatus, READ_RECEIPT_GRACE};
+use crate::message::receive::ReceiveConfig;
+use crate::message::session::{wait_receipt, Session};
 use crate::storage::fetch_local_keypair;
+use crate::transport::{BoxStream, TorTransport, Transport};
 
-pub async fn send(contact: Contact, msg: &str) -> Result<()> {
-    let keys = fetch_local_keypair()?;
+/// An established, handshaked connection to a contact
+pub(crate) struct OutboundConnection {
+    pub stream: BoxStream,
+    pub transport: TransportState,
+}
 
+/// Connects to a contact over `carrier` and completes the handshake. IK is used since the contact's
+/// static key is known, carrying `early` in its first message if it fits; if the responder cannot
+/// complete IK (e.g. its key changed) the connection is retried with XX.
+/// Both sides then prove their Ed25519 identity; a contact without one is upgraded and stored.
+/// If the responder's static key is not the pinned one the presented key is stored as pending
+/// and `AiroiError::KeyMismatch` is returned; it has to be re-pinned explicitly.
+/// Returns whether `early` was delivered as part of the handshake.
+pub(crate) async fn connect(
+    carrier: &dyn Transport,
+    contact: &Contact,
+    keys: &KeyPair,
+    early: Option<&Envelope>,
+) -> Result<(OutboundConnection, bool)> {
     let local_priv = keys.private_key().x25519_key_raw().to_vec();
-    let _remote_pub = contact.public_key().x25519_key_raw().to_vec();
+    let remote_pub = contact.public_key().x25519_key_raw().to_vec();
 
-    let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse()?;
-    let mut builder = Builder::new(params);
-    builder = builder.local_private_key(&local_priv)?;
+    println!("Connecting to {}", contact.address());
 
-    let mut noise = builder.build_initiator()?;
+    // early data is a single padded chunk, like everything sent over the transport
+    let early = early
+        .map(|e| fragment(&e.encode(), contact.padding))
+        .filter(|chunks| chunks.len() == 1 && fits_early(&chunks[0]))
+        .map(|mut chunks| chunks.remove(0));
 
-    println!("Sending message to {}", contact.address());
-    // ==== Tor Connection ====
-    let torrc = crate::tor::config::setup_tor().await?;
-    let hidden_service_dir = crate::tor::config::get_hidden_service_dir();
-    let mut tor_child = crate::tor::config::start_tor_daemon(&torrc)?;
-    let onion_addr = crate::tor::config::wait_for_onion(&hidden_service_dir).await?;
-    println!("Your onion service address is: {}", onion_addr);
-    crate::tor::config::wait_for_tor_ready().await?;
-    println!("Tor ready");
+    let mut stream = carrier.connect(contact.address()).await?;
+    let (mut handshake, early_sent) = match initiate_ik(&mut stream, &local_priv, &remote_pub, early.as_deref()).await {
+        Ok(handshake) => (handshake, early.is_some()),
+        Err(e) => {
+            eprintln!("IK handshake failed ({}), falling back to XX", e);
+            stream = carrier.connect(contact.address()).await?;
+            (initiate_xx(&mut stream, &local_priv).await?, false)
+        }
+    };
 
-    let tor_stream =
-        Socks5Stream::connect("127.0.0.1:9050", format!("{}:4444", contact.address())).await
-            .map_err(|e| AiroiError::Onion(e.to_string()))?;
-    let mut stream: TcpStream = tor_stream.into_inner();
-    println!("connected to {}", contact.address());
+    let fingerprint = get_fingerprint(&handshake.remote_static);
+    println!("Handshake OK with remote, fingerprint: {}", fingerprint);
 
-    // Handshake
-    let mut buf = vec![0u8; 1024];
+    let identity = exchange_identity(&mut stream, &mut handshake, keys, true).await?;
+    let mut contact = contact.clone();
+    if handshake.remote_static != contact.public_key().x25519_key_raw() {
+        let presented = Key::new(identity.to_vec(), handshake.remote_static);
+        let err = AiroiError::KeyMismatch {
+            contact: contact.name.clone(),
+            pinned: contact.public_key().display_fingerprint().to_string(),
+            presented: presented.display_fingerprint().to_string(),
+        };
+        contact.pending_key = Some(presented);
+        update_contact(&contact)?;
+        return Err(err);
+    }
+    if contact.bind_identity(&identity)? {
+        update_contact(&contact)?;
+        println!("Verified ed25519 identity of {}, fingerprint: {}", contact.name, contact.fingerprint_ed());
+    }
 
-    // msg1
-    let mut msg1 = vec![0u8; 1024];
-    let len1 = noise.write_message(&[], &mut msg1)?;
-    write_frame(&mut stream, &msg1[..len1]).await?;
+    Ok((OutboundConnection { stream, transport: handshake.transport }, early_sent))
+}
 
-    // msg2
-    let msg2 = read_frame(&mut stream).await?;
-    noise.read_message(&msg2, &mut buf)?;
+/// One-shot send over Tor with the local key pair, see `send_via`
+pub async fn send(contact: Contact, msg: &str) -> Result<DeliveryStatus> {
+    let keys = fetch_local_keypair()?;
+    send_via(&TorTransport::new(), &keys, contact, msg).await
+}
 
-    // msg3
-    let mut msg3 = vec![0u8; 1024];
-    let len3 = noise.write_message(&[], &mut msg3)?;
-    write_frame(&mut stream, &msg3[..len3]).await?;
+/// One-shot send: opens a session, sends a single message and closes it again.
+/// Lingers briefly after the acknowledgment to pick up a read receipt.
+pub async fn send_via(carrier: &dyn Transport, keys: &KeyPair, contact: Contact, msg: &str) -> Result<DeliveryStatus> {
+    send_envelope_via(carrier, keys, contact, Envelope::text(msg)).await
+}
 
-    // handshake done
-    let remote_static = noise.get_remote_static().unwrap();
-    let fingerprint = get_fingerprint(&remote_static);
-    println!("Handshake OK with remote, fingerprint: {}", fingerprint);
+/// Like `send_via` with a prepared envelope, e.g. one resent from the outbox under its original id
+pub async fn send_envelope_via(carrier: &dyn Transport, keys: &KeyPair, contact: Contact, envelope: Envelope) -> Result<DeliveryStatus> {
+    let id = envelope.id;
+    let (inbound_tx, mut inbound_rx) = mpsc::channel(16);
+    let (session, early_receipt) = Session::connect(carrier, &contact, keys, inbound_tx, &ReceiveConfig::default(), Some(&envelope)).await?;
 
-    let mut transport = noise.into_transport_mode()?;
+    let mut status = match early_receipt {
+        Some(receipt) => wait_receipt(receipt).await?,
+        None => session.send(envelope).await?,
+    };
 
-    // send the actual message
-    let mut cipher = vec![0u8; 65535]; // big enough buffer
-    let len = transport.write_message(msg.as_bytes(), &mut cipher)?;
-    write_frame(&mut stream, &cipher[..len]).await?;
+    if status == DeliveryStatus::Delivered {
+        let read = tokio::time::timeout(READ_RECEIPT_GRACE, async {
+            while let Some(message) = inbound_rx.recv().await {
+                if message.kind == MessageKind::Read && message.id == id {
+                    return true;
+                }
+            }
+            false
+        }).await;
+        if let Ok(true) = read {
+            status = DeliveryStatus::Read;
+        }
+    }
 
-    tor_child.kill()?;
-    Ok(())
-}
\ No newline at end of file
+    session.close().await;
+    Ok(status)
+}
@@ -0,0 +1,788 @@
+//! Long-lived, bidirectional Noise sessions.
+//!
+//! A `Session` owns one handshaked connection to a contact. Both sides can send on it and
+//! receive from it until either side goes away. The `SessionRegistry` keeps one session per
+//! contact so repeated sends reuse the existing connection instead of a fresh Tor circuit and
+//! handshake.
+//!
+//! An idle session is kept alive with `Ping`s, answered by an `Ack`; if nothing at all arrives
+//! within the idle timeout the session ends. A side that ends a session on purpose says so with
+//! `Control::Close`. Sessions opening and closing are published as `SessionEvent`s.
+//! For contacts with cover traffic everything written goes through a `CoverQueue` instead. The
+//! first thing queued is `Control::Cover`, upon which the other side sends its own frames, its
+//! receipts included, through a `CoverQueue` with the same settings; otherwise the timing of its
+//! receipts would show which of our frames carried a message.
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use snow::TransportState;
+use tokio::io::AsyncWrite;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::time::{Instant, Interval, MissedTickBehavior};
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::contacts::Contact;
+use crate::message::{read_frame_max, Message};
+use crate::message::control::Control;
+use crate::message::cover::CoverQueue;
+use crate::message::envelope::{Envelope, MessageId, MessageKind};
+use crate::message::file::{FileAccept, FileChunk, FileComplete, FileOffer, IncomingFiles};
+use crate::message::fragment::{read_chunk, Reassembler};
+use crate::message::group::receive_group;
+use crate::message::receipt::{DeliveryStatus, ACK_TIMEOUT};
+use crate::message::receive::ReceiveConfig;
+use crate::message::rekey::Rekeyer;
+use crate::message::replay::{Freshness, ReplayGuard};
+use crate::message::send::connect;
+use crate::transport::{Stream, Transport};
+
+/// First envelope exchanged inside an IK handshake rather than over the transport
+pub(crate) enum EarlyData {
+    None,
+    /// We received it; it is handled before anything read from the transport
+    Received(Envelope),
+    /// We sent it; its receipt is tracked like any other send
+    Sent(MessageId, oneshot::Sender<Result<DeliveryStatus>>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CloseReason {
+    /// We closed the session
+    Local,
+    /// The contact sent `Control::Close`
+    Remote,
+    /// The connection dropped without a close message
+    Disconnected,
+    /// Nothing arrived within the idle timeout
+    IdleTimeout,
+    Error(String),
+}
+
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Opened(Contact),
+    Closed(Contact, CloseReason),
+}
+
+enum SessionCommand {
+    Send { envelope: Envelope, receipt: oneshot::Sender<Result<DeliveryStatus>> },
+    Close,
+}
+
+pub struct Session {
+    contact: Contact,
+    commands: mpsc::Sender<SessionCommand>,
+}
+
+impl Session {
+    /// Starts driving an established transport on its own task. `keys` are our own, used for the
+    /// keys of the groups we share with the contact.
+    pub(crate) fn spawn<S: Stream>(
+        keys: &KeyPair,
+        contact: Contact,
+        stream: S,
+        transport: TransportState,
+        inbound: mpsc::Sender<Message>,
+        config: &ReceiveConfig,
+        early: EarlyData,
+    ) -> Session {
+        let (mut reader, writer) = tokio::io::split(stream);
+        let (command_tx, command_rx) = mpsc::channel(16);
+        let (reply_tx, reply_rx) = mpsc::channel(16);
+
+        // Frames are read on their own task so we can write while waiting for input
+        let (frame_tx, frame_rx) = mpsc::channel::<Result<Vec<u8>>>(16);
+        let max_frame_size = config.limits.max_frame_size;
+        let reader_task = tokio::spawn(async move {
+            loop {
+                let frame = read_frame_max(&mut reader, max_frame_size).await;
+                let failed = frame.is_err();
+                if frame_tx.send(frame).await.is_err() || failed {
+                    break;
+                }
+            }
+        });
+
+        let driver = SessionDriver {
+            keys: keys.clone(),
+            contact: contact.clone(),
+            transport,
+            writer: Box::new(writer),
+            reassembler: Reassembler::new(config.max_message_size),
+            rekeyer: Rekeyer::new(config.rekey, contact.padding),
+            cover: contact.cover
+                .filter(|cover| match cover.validate() {
+                    Ok(()) => true,
+                    Err(e) => {
+                        eprintln!("cover traffic for {} disabled: {}", contact.name, e);
+                        false
+                    }
+                })
+                .map(CoverQueue::new),
+            replay: config.replay.clone(),
+            inbound,
+            reply_tx,
+            pending: HashMap::new(),
+            incoming_files: IncomingFiles::new(config.downloads_dir.clone(), contact.fingerprint_x()),
+            pending_offers: HashMap::new(),
+            keepalive: config.keepalive,
+            idle_timeout: config.idle_timeout,
+            last_received: Instant::now(),
+        };
+        let events = config.events.clone();
+        let _ = events.send(SessionEvent::Opened(contact.clone()));
+        tokio::spawn(async move {
+            let contact = driver.contact.clone();
+            let reason = match driver.run(early, frame_rx, command_rx, reply_rx).await {
+                Ok(reason) => reason,
+                Err(e) => {
+                    eprintln!("session with {} ended: {}", contact.name, e);
+                    CloseReason::Error(e.to_string())
+                }
+            };
+            reader_task.abort();
+            let _ = events.send(SessionEvent::Closed(contact, reason));
+        });
+
+        Session { contact, commands: command_tx }
+    }
+
+    /// Opens a session to a contact. If `first` went out inside the handshake, the receiver
+    /// for its delivery status is returned alongside.
+    pub(crate) async fn connect(
+        carrier: &dyn Transport,
+        contact: &Contact,
+        keys: &KeyPair,
+        inbound: mpsc::Sender<Message>,
+        config: &ReceiveConfig,
+        first: Option<&Envelope>,
+    ) -> Result<(Session, Option<oneshot::Receiver<Result<DeliveryStatus>>>)> {
+        // with cover traffic even the first message waits for the schedule
+        let first = first.filter(|_| contact.cover.is_none());
+        let (conn, early_sent) = connect(carrier, contact, keys, first).await?;
+        let (early, receipt) = match first {
+            Some(first) if early_sent => {
+                let (receipt_tx, receipt_rx) = oneshot::channel();
+                (EarlyData::Sent(first.id, receipt_tx), Some(receipt_rx))
+            }
+            _ => (EarlyData::None, None),
+        };
+        let session = Session::spawn(keys, contact.clone(), conn.stream, conn.transport, inbound, config, early);
+        Ok((session, receipt))
+    }
+
+    pub fn contact(&self) -> &Contact {
+        &self.contact
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.commands.is_closed()
+    }
+
+    /// Sends an envelope and waits for the receiver to acknowledge it
+    pub async fn send(&self, envelope: Envelope) -> Result<DeliveryStatus> {
+        let (receipt_tx, receipt_rx) = oneshot::channel();
+        self.commands
+            .send(SessionCommand::Send { envelope, receipt: receipt_tx })
+            .await
+            .map_err(|_| AiroiError::SessionClosed)?;
+        wait_receipt(receipt_rx).await
+    }
+
+    pub async fn send_text(&self, text: &str) -> Result<DeliveryStatus> {
+        self.send(Envelope::text(text)).await
+    }
+
+    pub async fn close(&self) {
+        let _ = self.commands.send(SessionCommand::Close).await;
+        self.closed().await;
+    }
+
+    /// Resolves once the connection has ended
+    pub async fn closed(&self) {
+        self.commands.closed().await;
+    }
+}
+
+/// Waits for the receipt of a sent envelope, giving up after `ACK_TIMEOUT`
+pub(crate) async fn wait_receipt(receipt: oneshot::Receiver<Result<DeliveryStatus>>) -> Result<DeliveryStatus> {
+    match tokio::time::timeout(ACK_TIMEOUT, receipt).await {
+        Ok(Ok(status)) => status,
+        Ok(Err(_)) => Err(AiroiError::SessionClosed),
+        Err(_) => Ok(DeliveryStatus::TimedOut),
+    }
+}
+
+struct SessionDriver {
+    keys: KeyPair,
+    contact: Contact,
+    transport: TransportState,
+    writer: Box<dyn AsyncWrite + Unpin + Send>,
+    reassembler: Reassembler,
+    rekeyer: Rekeyer,
+    cover: Option<CoverQueue>,
+    replay: ReplayGuard,
+    inbound: mpsc::Sender<Message>,
+    reply_tx: mpsc::Sender<Envelope>,
+    pending: HashMap<MessageId, oneshot::Sender<Result<DeliveryStatus>>>,
+    incoming_files: IncomingFiles,
+    pending_offers: HashMap<String, FileOffer>,
+    keepalive: Duration,
+    idle_timeout: Duration,
+    last_received: Instant,
+}
+
+impl SessionDriver {
+    async fn run(
+        mut self,
+        early: EarlyData,
+        mut frames: mpsc::Receiver<Result<Vec<u8>>>,
+        mut commands: mpsc::Receiver<SessionCommand>,
+        mut replies: mpsc::Receiver<Envelope>,
+    ) -> Result<CloseReason> {
+        if let Some(cover) = &self.cover {
+            self.write(&Control::Cover(cover.config()).into_envelope()).await?;
+        }
+        match early {
+            EarlyData::Received(envelope) => {
+                if let Some(reason) = self.handle_envelope(envelope).await? {
+                    return self.finish(reason).await;
+                }
+            }
+            EarlyData::Sent(id, receipt) => self.track(id, receipt),
+            EarlyData::None => {}
+        }
+
+        let mut keepalive = tokio::time::interval_at(Instant::now() + self.keepalive, self.keepalive);
+        let mut cover_ticks = None;
+        loop {
+            // cover starts with the session, or once the contact asks for it
+            if cover_ticks.is_none() {
+                cover_ticks = self.cover.as_ref().map(|cover| {
+                    let mut ticks = tokio::time::interval(cover.interval());
+                    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+                    ticks
+                });
+            }
+            let rekey_deadline = self.rekeyer.deadline();
+            let idle_deadline = self.last_received + self.idle_timeout;
+            tokio::select! {
+                frame = frames.recv() => {
+                    let frame = match frame {
+                        Some(Ok(frame)) => frame,
+                        Some(Err(AiroiError::Io(e))) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                            return Ok(CloseReason::Disconnected);
+                        }
+                        Some(Err(e)) => return Err(e),
+                        None => return Ok(CloseReason::Disconnected),
+                    };
+                    self.last_received = Instant::now();
+                    let Some(payload) = read_chunk(&mut self.transport, &mut self.reassembler, &frame)? else {
+                        continue;
+                    };
+                    let envelope = match Envelope::decode(&payload) {
+                        Ok(envelope) => envelope,
+                        Err(e) => {
+                            eprintln!("dropping malformed envelope: {}", e);
+                            continue;
+                        }
+                    };
+                    if let Some(reason) = self.handle_envelope(envelope).await? {
+                        return self.finish(reason).await;
+                    }
+                }
+                command = commands.recv() => match command {
+                    Some(SessionCommand::Send { envelope, receipt }) => match self.write(&envelope).await {
+                        Ok(()) => self.track(envelope.id, receipt),
+                        // only this message is refused, the session goes on
+                        Err(e @ (AiroiError::CoverQueueFull { .. } | AiroiError::MessageTooLarge { .. })) => {
+                            let _ = receipt.send(Err(e));
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    Some(SessionCommand::Close) | None => return self.finish(CloseReason::Local).await,
+                },
+                Some(reply) = replies.recv() => {
+                    let mut finished = None;
+                    if reply.kind == MessageKind::FileAccept {
+                        let accept: FileAccept = serde_json::from_slice(&reply.body)?;
+                        if let Some(offer) = self.pending_offers.remove(&accept.transfer) {
+                            finished = self.incoming_files.accept(offer).await?.1;
+                        }
+                    }
+                    self.write(&reply).await?;
+                    if let Some((complete, saved)) = finished {
+                        self.complete_file(complete, saved).await?;
+                    }
+                }
+                _ = tokio::time::sleep_until(rekey_deadline) => {
+                    self.rekey_if_due().await?;
+                }
+                _ = next_tick(&mut cover_ticks) => {
+                    if let Some(cover) = &mut self.cover {
+                        cover.tick(&mut self.writer, &mut self.transport).await?;
+                    }
+                }
+                _ = keepalive.tick() => {
+                    self.pending.retain(|_, receipt| !receipt.is_closed());
+                    if self.last_received.elapsed() >= self.keepalive {
+                        self.write(&Envelope::new(MessageKind::Ping, Vec::new())).await?;
+                    }
+                }
+                _ = tokio::time::sleep_until(idle_deadline) => {
+                    return self.finish(CloseReason::IdleTimeout).await;
+                }
+            }
+        }
+    }
+
+    /// Remembers a sent envelope until its receipt arrives. Receipts nobody waits for any more,
+    /// because `wait_receipt` gave up, are dropped here and on every keepalive tick.
+    fn track(&mut self, id: MessageId, receipt: oneshot::Sender<Result<DeliveryStatus>>) {
+        self.pending.retain(|_, receipt| !receipt.is_closed());
+        self.pending.insert(id, receipt);
+    }
+
+    /// Tells the contact we are leaving unless they left first
+    async fn finish(&mut self, reason: CloseReason) -> Result<CloseReason> {
+        if reason != CloseReason::Remote {
+            let _ = self.write(&Control::Close.into_envelope()).await;
+        }
+        if let Some(cover) = &mut self.cover {
+            let _ = cover.flush(&mut self.writer, &mut self.transport).await;
+        }
+        Ok(reason)
+    }
+
+    async fn write(&mut self, envelope: &Envelope) -> Result<()> {
+        let Some(cover) = &mut self.cover else {
+            return self.rekeyer.write(&mut self.writer, &mut self.transport, envelope).await;
+        };
+        let payload = envelope.encode();
+        cover.push(&payload)?;
+        self.rekeyer.record(payload.len());
+        if self.rekeyer.take_due() {
+            cover.push_rekey(&Control::Rekey.into_envelope().encode());
+        }
+        Ok(())
+    }
+
+    async fn rekey_if_due(&mut self) -> Result<()> {
+        match &mut self.cover {
+            Some(cover) => {
+                if self.rekeyer.take_due() {
+                    cover.push_rekey(&Control::Rekey.into_envelope().encode());
+                }
+                Ok(())
+            }
+            None => self.rekeyer.rekey_if_due(&mut self.writer, &mut self.transport).await,
+        }
+    }
+
+    /// Handles one inbound envelope; returns a reason once the session should end
+    async fn handle_envelope(&mut self, envelope: Envelope) -> Result<Option<CloseReason>> {
+        match envelope.kind {
+            MessageKind::Text | MessageKind::GroupText | MessageKind::GroupControl => {
+                match self.replay.check(self.contact.fingerprint_x(), &envelope) {
+                    Freshness::New => {}
+                    Freshness::Duplicate => {
+                        self.write(&Envelope::ack(envelope.id)).await?;
+                        return Ok(None);
+                    }
+                    Freshness::Stale => {
+                        eprintln!("refusing message {} from {}: outside the replay window", envelope.id, self.contact.name);
+                        self.write(&Envelope::reject(envelope.id)).await?;
+                        return Ok(None);
+                    }
+                }
+                let mut message = match envelope.kind {
+                    MessageKind::Text => Message::from_envelope(self.contact.clone(), &envelope),
+                    _ => match receive_group(&self.contact, &self.keys, &envelope) {
+                        Ok(message) => message,
+                        Err(e) => {
+                            eprintln!("refusing group message {} from {}: {}", envelope.id, self.contact.name, e);
+                            self.write(&Envelope::reject(envelope.id)).await?;
+                            return Ok(None);
+                        }
+                    },
+                };
+                message.responder = Some(self.reply_tx.clone());
+                if self.inbound.send(message).await.is_err() {
+                    eprintln!("receiver dropped, stopping connection");
+                    self.write(&Envelope::reject(envelope.id)).await?;
+                    return Ok(Some(CloseReason::Local));
+                }
+                self.replay.commit(self.contact.fingerprint_x(), &envelope);
+                self.write(&Envelope::ack(envelope.id)).await?;
+            }
+            MessageKind::Ack | MessageKind::Reject => {
+                let status = if envelope.kind == MessageKind::Ack {
+                    DeliveryStatus::Delivered
+                } else {
+                    DeliveryStatus::Rejected
+                };
+                if let Some(receipt) = self.pending.remove(&envelope.referenced_id()?) {
+                    let _ = receipt.send(Ok(status));
+                }
+            }
+            MessageKind::Control => match Control::decode(&envelope.body)? {
+                Control::Rekey => self.transport.rekey_incoming(),
+                Control::Close => return Ok(Some(CloseReason::Remote)),
+                Control::Cover(cover) if self.cover.is_none() => match cover.validate() {
+                    Ok(()) => self.cover = Some(CoverQueue::new(cover)),
+                    Err(e) => eprintln!("ignoring cover traffic asked for by {}: {}", self.contact.name, e),
+                },
+                Control::Cover(_) => {}
+                other => eprintln!("ignoring unexpected control message {:?}", other),
+            },
+            MessageKind::Read => {
+                // surfaced as a message so the UI can update the delivery state of what it sent
+                let mut message = Message::from_envelope(self.contact.clone(), &envelope);
+                message.id = envelope.referenced_id()?;
+                let _ = self.inbound.send(message).await;
+            }
+            MessageKind::FileOffer => {
+                let offer: FileOffer = serde_json::from_slice(&envelope.body)?;
+                // a transfer the user already accepted from this contact is resumed without asking again
+                if self.incoming_files.resume_offset(&offer).is_some() {
+                    let (accept, finished) = self.incoming_files.accept(offer).await?;
+                    let reply = Envelope::new(MessageKind::FileAccept, serde_json::to_vec(&accept)?);
+                    self.write(&reply).await?;
+                    if let Some((complete, saved)) = finished {
+                        self.complete_file(complete, saved).await?;
+                    }
+                    return Ok(None);
+                }
+                let mut message = Message::from_envelope(self.contact.clone(), &envelope);
+                message.message = format!("offers file '{}' ({} bytes)", offer.name, offer.size);
+                message.responder = Some(self.reply_tx.clone());
+                message.file = Some(offer.clone());
+                self.pending_offers.insert(offer.transfer.clone(), offer);
+                if self.inbound.send(message).await.is_err() {
+                    self.write(&Envelope::reject(envelope.id)).await?;
+                    return Ok(Some(CloseReason::Local));
+                }
+            }
+            MessageKind::FileChunk => {
+                let chunk = FileChunk::decode(&envelope.body)?;
+                if let Some((complete, saved)) = self.incoming_files.write_chunk(chunk).await? {
+                    self.complete_file(complete, saved).await?;
+                }
+            }
+            MessageKind::Ping => self.write(&Envelope::ack(envelope.id)).await?,
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Tells the sender how a transfer ended and the user where the file was saved
+    async fn complete_file(&mut self, complete: FileComplete, saved: Option<PathBuf>) -> Result<()> {
+        let reply = Envelope::new(MessageKind::FileComplete, serde_json::to_vec(&complete)?);
+        self.write(&reply).await?;
+        if let Some(saved) = saved {
+            let mut message = Message::from_envelope(self.contact.clone(), &reply);
+            message.message = format!("sent a file, saved to {}", saved.display());
+            let _ = self.inbound.send(message).await;
+        }
+        Ok(())
+    }
+}
+
+async fn next_tick(ticks: &mut Option<Interval>) {
+    match ticks {
+        Some(ticks) => {
+            ticks.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+/// One open session per contact, shared by everything that sends or receives
+pub struct SessionRegistry {
+    carrier: Arc<dyn Transport>,
+    keys: KeyPair,
+    inbound: mpsc::Sender<Message>,
+    config: ReceiveConfig,
+    sessions: Mutex<HashMap<String, Arc<Session>>>,
+}
+
+impl SessionRegistry {
+    pub fn new(
+        carrier: Arc<dyn Transport>,
+        keys: KeyPair,
+        inbound: mpsc::Sender<Message>,
+        config: ReceiveConfig,
+    ) -> Arc<SessionRegistry> {
+        Arc::new(SessionRegistry {
+            carrier,
+            keys,
+            inbound,
+            config,
+            sessions: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Transport sessions are opened and accepted over
+    pub fn carrier(&self) -> &dyn Transport {
+        self.carrier.as_ref()
+    }
+
+    pub fn keys(&self) -> &KeyPair {
+        &self.keys
+    }
+
+    pub fn inbound(&self) -> mpsc::Sender<Message> {
+        self.inbound.clone()
+    }
+
+    pub fn config(&self) -> &ReceiveConfig {
+        &self.config
+    }
+
+    /// Session lifecycle events of everything using this registry's config
+    pub fn events(&self) -> broadcast::Receiver<SessionEvent> {
+        self.config.events.subscribe()
+    }
+
+    /// Registers a session, e.g. one the contact opened to us
+    pub fn insert(&self, session: Arc<Session>) {
+        let key = session.contact().fingerprint_x().to_string();
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.is_open());
+        sessions.insert(key, session);
+    }
+
+    /// Open session with the contact, connecting if there is none
+    pub async fn session(&self, contact: &Contact) -> Result<Arc<Session>> {
+        if let Some(session) = self.existing(contact) {
+            return Ok(session);
+        }
+        let (session, _) = self.open(contact, None).await?;
+        Ok(session)
+    }
+
+    async fn open(
+        &self,
+        contact: &Contact,
+        first: Option<&Envelope>,
+    ) -> Result<(Arc<Session>, Option<oneshot::Receiver<Result<DeliveryStatus>>>)> {
+        let (session, receipt) = Session::connect(self.carrier(), contact, &self.keys, self.inbound.clone(), &self.config, first).await?;
+        let session = Arc::new(session);
+        self.insert(session.clone());
+        Ok((session, receipt))
+    }
+
+    fn existing(&self, contact: &Contact) -> Option<Arc<Session>> {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, s| s.is_open());
+        sessions.get(contact.fingerprint_x()).cloned()
+    }
+
+    /// Sends an envelope over the existing session, or over a new one with the envelope
+    /// riding in the handshake. Reconnects once if the existing session died underneath.
+    pub async fn send(&self, contact: &Contact, envelope: Envelope) -> Result<DeliveryStatus> {
+        if let Some(session) = self.existing(contact) {
+            match session.send(envelope.clone()).await {
+                Err(AiroiError::SessionClosed) => {}
+                result => return result,
+            }
+        }
+        let (session, receipt) = self.open(contact, Some(&envelope)).await?;
+        match receipt {
+            Some(receipt) => wait_receipt(receipt).await,
+            None => session.send(envelope).await,
+        }
+    }
+
+    pub async fn send_text(&self, contact: &Contact, text: &str) -> Result<DeliveryStatus> {
+        self.send(contact, Envelope::text(text)).await
+    }
+
+    /// Closes the session with a contact, the next send reconnects
+    pub async fn close(&self, contact: &Contact) {
+        let session = self.sessions.lock().unwrap().remove(contact.fingerprint_x());
+        if let Some(session) = session {
+            session.close().await;
+        }
+    }
+
+    pub async fn close_all(&self) {
+        let sessions: Vec<_> = self.sessions.lock().unwrap().drain().map(|(_, s)| s).collect();
+        for session in sessions {
+            session.close().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::{TcpListener, TcpStream};
+    use crate::keys::key_gen::generate_key_pair;
+    use crate::message::cover::CoverTraffic;
+    use crate::message::handshake::{initiate_xx, respond, Handshake};
+    use crate::message::receive::{DEFAULT_IDLE_TIMEOUT, DEFAULT_KEEPALIVE};
+
+    /// Handshaked loopback TCP connection: (initiator stream, initiator, responder stream, responder)
+    async fn connected() -> (TcpStream, Handshake, TcpStream, Handshake) {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let bob_priv = bob.private_key().x25519_key_raw().to_vec();
+        let responder = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let handshake = respond(&mut socket, &bob_priv).await.unwrap();
+            (socket, handshake)
+        });
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let initiator = initiate_xx(&mut stream, alice.private_key().x25519_key_raw()).await.unwrap();
+        let (socket, responder) = responder.await.unwrap();
+        (stream, initiator, socket, responder)
+    }
+
+    fn contact(name: &str) -> Contact {
+        let keys = generate_key_pair().unwrap();
+        Contact::new(name.to_string(), keys.public_key().ed25519_key_raw().to_vec(), "")
+    }
+
+    fn config(keepalive: Duration, idle_timeout: Duration) -> ReceiveConfig {
+        ReceiveConfig { keepalive, idle_timeout, events: broadcast::channel(16).0, ..ReceiveConfig::default() }
+    }
+
+    async fn next_close(events: &mut broadcast::Receiver<SessionEvent>) -> CloseReason {
+        loop {
+            if let SessionEvent::Closed(_, reason) = events.recv().await.unwrap() {
+                return reason;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_close_is_announced_to_peer() {
+        let (stream, initiator, socket, responder) = connected().await;
+        let alice_config = config(DEFAULT_KEEPALIVE, DEFAULT_IDLE_TIMEOUT);
+        let bob_config = config(DEFAULT_KEEPALIVE, DEFAULT_IDLE_TIMEOUT);
+        let mut alice_events = alice_config.events.subscribe();
+        let mut bob_events = bob_config.events.subscribe();
+        let (tx, mut rx) = mpsc::channel(16);
+        let alice = Session::spawn(&generate_key_pair().unwrap(), contact("bob"), stream, initiator.transport, tx.clone(), &alice_config, EarlyData::None);
+        let bob = Session::spawn(&generate_key_pair().unwrap(), contact("alice"), socket, responder.transport, tx, &bob_config, EarlyData::None);
+
+        assert_eq!(alice.send_text("hi").await.unwrap(), DeliveryStatus::Delivered);
+        assert_eq!(rx.recv().await.unwrap().message, "hi");
+        alice.close().await;
+        assert_eq!(next_close(&mut alice_events).await, CloseReason::Local);
+        assert_eq!(next_close(&mut bob_events).await, CloseReason::Remote);
+        bob.closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_resent_message_is_surfaced_once() {
+        let config = config(DEFAULT_KEEPALIVE, DEFAULT_IDLE_TIMEOUT);
+        let alice_contact = contact("alice");
+        let envelope = Envelope::text("only once");
+        let (tx, mut rx) = mpsc::channel(16);
+
+        // the same envelope over two separate connections, e.g. a retry after reconnecting
+        for _ in 0..2 {
+            let (stream, initiator, socket, responder) = connected().await;
+            let alice = Session::spawn(&generate_key_pair().unwrap(), contact("bob"), stream, initiator.transport, tx.clone(), &config, EarlyData::None);
+            let bob = Session::spawn(&generate_key_pair().unwrap(), alice_contact.clone(), socket, responder.transport, tx.clone(), &config, EarlyData::None);
+            assert_eq!(alice.send(envelope.clone()).await.unwrap(), DeliveryStatus::Delivered);
+            alice.close().await;
+            bob.closed().await;
+        }
+        assert_eq!(rx.recv().await.unwrap().message, "only once");
+        drop(tx);
+        assert!(rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cover_traffic_session() {
+        let (stream, initiator, socket, responder) = connected().await;
+        let config = config(DEFAULT_KEEPALIVE, DEFAULT_IDLE_TIMEOUT);
+        let mut bob_contact = contact("bob");
+        bob_contact.cover = Some(CoverTraffic { frame_size: 64, bandwidth: 64 * 100 });
+        let (tx, mut rx) = mpsc::channel(16);
+        let alice = Session::spawn(&generate_key_pair().unwrap(), bob_contact, stream, initiator.transport, tx.clone(), &config, EarlyData::None);
+        let bob = Session::spawn(&generate_key_pair().unwrap(), contact("alice"), socket, responder.transport, tx, &config, EarlyData::None);
+
+        // dummies flow before and after; a message spanning several frames still arrives whole
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let text = "x".repeat(300);
+        assert_eq!(alice.send_text(&text).await.unwrap(), DeliveryStatus::Delivered);
+        assert_eq!(rx.recv().await.unwrap().message, text);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(bob.is_open());
+        alice.close().await;
+        bob.closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_cover_traffic_is_returned() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let (stream, initiator, socket, responder) = connected().await;
+        let config = config(DEFAULT_KEEPALIVE, DEFAULT_IDLE_TIMEOUT);
+        let mut bob_contact = contact("bob");
+        bob_contact.cover = Some(CoverTraffic { frame_size: 64, bandwidth: 64 * 100 });
+        let (tx, mut rx) = mpsc::channel(16);
+        let alice = Session::spawn(&generate_key_pair().unwrap(), bob_contact, stream, initiator.transport, tx.clone(), &config, EarlyData::None);
+
+        // bob, who has no cover traffic set up for alice, writes through a tap noting frame lengths
+        let (bob_end, tap) = tokio::io::duplex(64 * 1024);
+        let (mut tap_read, mut tap_write) = tokio::io::split(tap);
+        let (mut socket_read, mut socket_write) = socket.into_split();
+        tokio::spawn(async move { tokio::io::copy(&mut socket_read, &mut tap_write).await });
+        let lengths = Arc::new(Mutex::new(Vec::new()));
+        let seen = lengths.clone();
+        tokio::spawn(async move {
+            loop {
+                let mut len = [0u8; 2];
+                if tap_read.read_exact(&mut len).await.is_err() {
+                    break;
+                }
+                let mut frame = vec![0u8; u16::from_be_bytes(len) as usize];
+                tap_read.read_exact(&mut frame).await.unwrap();
+                seen.lock().unwrap().push(frame.len());
+                socket_write.write_all(&len).await.unwrap();
+                socket_write.write_all(&frame).await.unwrap();
+            }
+        });
+        let bob = Session::spawn(&generate_key_pair().unwrap(), contact("alice"), bob_end, responder.transport, tx, &config, EarlyData::None);
+
+        // bob's receipt goes out as one more frame of the cover size, among dummies
+        assert_eq!(alice.send_text("hi").await.unwrap(), DeliveryStatus::Delivered);
+        assert_eq!(rx.recv().await.unwrap().message, "hi");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let lengths = lengths.lock().unwrap().clone();
+        assert!(lengths.len() > 3);
+        assert!(lengths.iter().all(|&len| len == 64 + crate::message::fragment::NOISE_TAG_LEN));
+        alice.close().await;
+        bob.closed().await;
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_and_idle_timeout() {
+        let (stream, initiator, socket, responder) = connected().await;
+        let config = config(Duration::from_millis(50), Duration::from_millis(300));
+        let (tx, _rx) = mpsc::channel(16);
+        let alice = Session::spawn(&generate_key_pair().unwrap(), contact("bob"), stream, initiator.transport, tx.clone(), &config, EarlyData::None);
+        let bob = Session::spawn(&generate_key_pair().unwrap(), contact("alice"), socket, responder.transport, tx.clone(), &config, EarlyData::None);
+
+        // pings keep an idle session open well past the idle timeout
+        tokio::time::sleep(Duration::from_millis(600)).await;
+        assert!(alice.is_open() && bob.is_open());
+        alice.close().await;
+        bob.closed().await;
+
+        // a peer that never answers is given up on
+        let (stream, initiator, _silent, _) = connected().await;
+        let mut events = config.events.subscribe();
+        let alice = Session::spawn(&generate_key_pair().unwrap(), contact("bob"), stream, initiator.transport, tx, &config, EarlyData::None);
+        assert_eq!(next_close(&mut events).await, CloseReason::IdleTimeout);
+        alice.closed().await;
+    }
+}
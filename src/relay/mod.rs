@@ -0,0 +1,291 @@
+//! Store-and-forward delivery through a relay, for contacts that are not online at the same time.
+//!
+//! The sender encrypts an envelope with the Double Ratchet it shares with the recipient, seals
+//! the result to the recipient's static key with a one-way Noise `X` message and deposits the
+//! blob at a relay, addressed to the recipient's Ed25519 key. The relay sees the recipient, the
+//! padded blob length, when it arrived and, from the handshake, the key of the depositing client;
+//! it stores only the first three and keeps the depositor in memory while the blob waits. The
+//! recipient later fetches its blobs and deletes them once opened. `X` authenticates the sender
+//! through its static key and hides the ratchet header from the relay; forward secrecy comes
+//! from the ratchet.
+//!
+//! Clients may also publish a prekey bundle at their relay. A sender without a ratchet towards
+//! the recipient yet fetches the bundle first, which hands out each one-time prekey once, so
+//! the very first message already gets forward secrecy.
+//!
+//! Relay and client speak over an XX handshake followed by the usual identity exchange, so the
+//! relay knows which Ed25519 key a client holds and hands out only that key's blobs. Requests
+//! and responses are JSON payloads on the Noise transport.
+use base64::Engine;
+use base64::engine::general_purpose;
+use serde::{Deserialize, Serialize};
+use snow::TransportState;
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::contacts::Contact;
+use crate::keys::key_gen::get_fingerprint;
+use crate::keys::prekeys::{PrekeyBundle, PrekeyStore};
+use crate::message::Message;
+use crate::message::envelope::{Envelope, MessageKind};
+use crate::message::fragment::{fragment_within, read_payload, write_payload, Reassembler, NOISE_MAX_MSG_LEN};
+use crate::message::handshake::{build, exchange_identity, initiate_xx};
+use crate::message::padding::PaddingPolicy;
+use crate::message::ratchet::RatchetStore;
+use crate::transport::{BoxStream, Transport};
+
+pub mod server;
+
+const NOISE_X: &str = "Noise_X_25519_ChaChaPoly_BLAKE2s";
+/// Overhead of a sealed blob: ephemeral key, encrypted static key and payload tag
+const SEAL_OVERHEAD: usize = 32 + 32 + 16 + 16;
+/// Largest sealed blob, a single Noise message
+pub const MAX_BLOB_SIZE: usize = NOISE_MAX_MSG_LEN;
+/// Blob bytes returned by one `Fetch`
+const FETCH_BATCH_BYTES: usize = 1024 * 1024;
+/// Largest request payload, a deposit of the largest blob in base64
+const MAX_REQUEST_SIZE: usize = 2 * MAX_BLOB_SIZE;
+/// Largest response payload, a full batch in base64
+const MAX_RESPONSE_SIZE: usize = 2 * FETCH_BATCH_BYTES + MAX_REQUEST_SIZE;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum RelayRequest {
+    /// Store a blob for the recipient with this Ed25519 key (base58)
+    Deposit { recipient: String, blob: String },
+    /// Blobs for the authenticated client, oldest first, starting after `after`
+    Fetch { after: Option<(i64, String)> },
+    /// Remove the authenticated client's blobs with these ids
+    Delete { ids: Vec<String> },
+    /// Replace the authenticated client's prekey bundle
+    PublishPrekeys { bundle: PrekeyBundle },
+    /// The bundle of the identity with this Ed25519 key (base58), with at most one one-time prekey
+    FetchPrekeys { identity: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum RelayResponse {
+    Stored { id: String },
+    Blobs { blobs: Vec<RelayBlob> },
+    Deleted { count: usize },
+    Published { one_time_prekeys: usize },
+    Prekeys { bundle: Option<PrekeyBundle> },
+    Refused { reason: String },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct RelayBlob {
+    pub id: String,
+    /// Unix milliseconds
+    pub stored_at: i64,
+    /// Base64
+    pub blob: String,
+}
+
+/// Seals a payload so only the contact can open it, padded like a transport chunk
+pub fn seal(keys: &KeyPair, contact: &Contact, payload: &[u8]) -> Result<Vec<u8>> {
+    let chunks = fragment_within(payload, contact.padding, MAX_BLOB_SIZE - SEAL_OVERHEAD);
+    if chunks.len() != 1 {
+        return Err(AiroiError::MessageTooLarge { size: payload.len() + SEAL_OVERHEAD, max: MAX_BLOB_SIZE });
+    }
+    let mut noise = build(NOISE_X, keys.private_key().x25519_key_raw(), Some(contact.public_key().x25519_key_raw()), true)?;
+    let mut blob = vec![0u8; MAX_BLOB_SIZE];
+    let len = noise.write_message(&chunks[0], &mut blob)?;
+    blob.truncate(len);
+    Ok(blob)
+}
+
+/// Opens a blob sealed to us. Returns the sender's static key and the payload.
+pub fn open(keys: &KeyPair, blob: &[u8]) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut noise = build(NOISE_X, keys.private_key().x25519_key_raw(), None, false)?;
+    let mut chunk = vec![0u8; blob.len()];
+    let len = noise.read_message(blob, &mut chunk)?;
+    let sender = noise
+        .get_remote_static()
+        .ok_or_else(|| AiroiError::RemoteStatic("sealed blob did not reveal the sender".to_string()))?
+        .to_vec();
+    let payload = Reassembler::new(MAX_BLOB_SIZE)
+        .push(&chunk[..len])?
+        .ok_or_else(|| AiroiError::Protocol("sealed blob must be a single chunk".to_string()))?;
+    Ok((sender, payload))
+}
+
+/// An authenticated connection to a relay
+struct RelayConnection {
+    stream: BoxStream,
+    transport: TransportState,
+}
+
+impl RelayConnection {
+    async fn connect(carrier: &dyn Transport, keys: &KeyPair, relay: &str) -> Result<RelayConnection> {
+        let mut stream = carrier.connect(relay).await?;
+        let mut handshake = initiate_xx(&mut stream, keys.private_key().x25519_key_raw()).await?;
+        let identity = exchange_identity(&mut stream, &mut handshake, keys, true).await?;
+        println!("Connected to relay {}, fingerprint: {}", relay, get_fingerprint(&identity));
+        Ok(RelayConnection { stream, transport: handshake.transport })
+    }
+
+    async fn request(&mut self, request: &RelayRequest) -> Result<RelayResponse> {
+        let payload = serde_json::to_vec(request)?;
+        write_payload(&mut self.stream, &mut self.transport, &payload, PaddingPolicy::default()).await?;
+        let mut reassembler = Reassembler::new(MAX_RESPONSE_SIZE);
+        let payload = read_payload(&mut self.stream, &mut self.transport, &mut reassembler).await?;
+        match serde_json::from_slice(&payload)? {
+            RelayResponse::Refused { reason } => Err(AiroiError::Relay(reason)),
+            response => Ok(response),
+        }
+    }
+}
+
+fn unexpected(response: RelayResponse) -> AiroiError {
+    AiroiError::Relay(format!("unexpected response {:?}", response))
+}
+
+/// Publishes our prekey bundle with `one_time` fresh one-time prekeys at the relay, replacing
+/// the one there. Returns how many one-time prekeys the relay kept.
+pub async fn publish_prekeys(
+    carrier: &dyn Transport,
+    keys: &KeyPair,
+    prekeys: &PrekeyStore,
+    relay: &str,
+    one_time: usize,
+) -> Result<usize> {
+    let mut connection = RelayConnection::connect(carrier, keys, relay).await?;
+    let bundle = prekeys.bundle(keys, one_time)?;
+    match connection.request(&RelayRequest::PublishPrekeys { bundle }).await? {
+        RelayResponse::Published { one_time_prekeys } => Ok(one_time_prekeys),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// The contact's bundle published at the relay, or the one from their card
+async fn contact_prekeys(connection: &mut RelayConnection, contact: &Contact) -> Result<Option<PrekeyBundle>> {
+    let request = RelayRequest::FetchPrekeys { identity: contact.public_key().ed25519_key().to_string() };
+    match connection.request(&request).await? {
+        RelayResponse::Prekeys { bundle: Some(bundle) } => {
+            bundle.verify_for(contact)?;
+            Ok(Some(bundle))
+        }
+        RelayResponse::Prekeys { bundle: None } => Ok(contact.prekeys.clone()),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Encrypts an envelope for the contact and leaves it at the relay. Returns the blob's id there.
+pub async fn deposit(
+    carrier: &dyn Transport,
+    keys: &KeyPair,
+    ratchets: &RatchetStore,
+    relay: &str,
+    contact: &Contact,
+    envelope: &Envelope,
+) -> Result<String> {
+    if contact.public_key().ed25519_key().is_empty() {
+        return Err(AiroiError::Relay(format!("{} has no proven ed25519 key to address", contact.name)));
+    }
+    let mut connection = RelayConnection::connect(carrier, keys, relay).await?;
+    let prekeys = match ratchets.needs_start(contact)? {
+        true => contact_prekeys(&mut connection, contact).await?,
+        false => None,
+    };
+    let blob = seal(keys, contact, &ratchets.encrypt(keys, contact, prekeys.as_ref(), &envelope.encode())?)?;
+    let request = RelayRequest::Deposit {
+        recipient: contact.public_key().ed25519_key().to_string(),
+        blob: general_purpose::STANDARD.encode(blob),
+    };
+    match connection.request(&request).await? {
+        RelayResponse::Stored { id } => Ok(id),
+        other => Err(unexpected(other)),
+    }
+}
+
+/// Fetches the text messages waiting at the relay and deletes them there. Blobs from senders
+/// that are not contacts are left in place, they can be fetched once the sender was added.
+pub async fn fetch(
+    carrier: &dyn Transport,
+    keys: &KeyPair,
+    ratchets: &RatchetStore,
+    prekeys: &PrekeyStore,
+    relay: &str,
+    contacts: &[Contact],
+) -> Result<Vec<Message>> {
+    let mut connection = RelayConnection::connect(carrier, keys, relay).await?;
+    let mut messages = vec![];
+    let mut after = None;
+    loop {
+        let blobs = match connection.request(&RelayRequest::Fetch { after: after.clone() }).await? {
+            RelayResponse::Blobs { blobs } => blobs,
+            other => return Err(unexpected(other)),
+        };
+        let Some(last) = blobs.last() else {
+            break;
+        };
+        after = Some((last.stored_at, last.id.clone()));
+
+        let mut done = vec![];
+        for blob in blobs {
+            let opened = general_purpose::STANDARD.decode(&blob.blob)
+                .map_err(AiroiError::from)
+                .and_then(|bytes| open(keys, &bytes));
+            let (sender, payload) = match opened {
+                Ok(opened) => opened,
+                Err(e) => {
+                    eprintln!("dropping unreadable blob {}: {}", blob.id, e);
+                    done.push(blob.id);
+                    continue;
+                }
+            };
+            let fingerprint = get_fingerprint(&sender);
+            let Some(contact) = contacts.iter().find(|c| c.fingerprint_x() == fingerprint) else {
+                eprintln!("keeping blob {} from unknown sender {}", blob.id, fingerprint);
+                continue;
+            };
+            let envelope = match ratchets.decrypt(keys, contact, prekeys, &payload).and_then(|p| Envelope::decode(&p)) {
+                Ok(envelope) => envelope,
+                Err(e) => {
+                    eprintln!("dropping blob {} from {}: {}", blob.id, contact.name, e);
+                    done.push(blob.id);
+                    continue;
+                }
+            };
+            if envelope.kind == MessageKind::Text {
+                messages.push(Message::from_envelope(contact.clone(), &envelope));
+            }
+            done.push(blob.id);
+        }
+        if !done.is_empty() {
+            match connection.request(&RelayRequest::Delete { ids: done }).await? {
+                RelayResponse::Deleted { .. } => {}
+                other => return Err(unexpected(other)),
+            }
+        }
+    }
+    Ok(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::key_gen::generate_key_pair;
+
+    #[test]
+    fn test_seal_and_open() {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let to_bob = Contact::new("bob".to_string(), bob.public_key().ed25519_key_raw().to_vec(), "bob");
+        let payload = Envelope::text("see you later").encode();
+
+        let blob = seal(&alice, &to_bob, &payload).unwrap();
+        // padded to the first bucket
+        assert_eq!(blob.len(), 256 + SEAL_OVERHEAD);
+        let (sender, opened) = open(&bob, &blob).unwrap();
+        assert_eq!(sender, alice.public_key().x25519_key_raw());
+        assert_eq!(opened, payload);
+        assert!(open(&alice, &blob).is_err());
+
+        // anything that fits a blob is padded within it
+        assert_eq!(seal(&alice, &to_bob, &[0; 20000]).unwrap().len(), MAX_BLOB_SIZE);
+
+        assert!(matches!(seal(&alice, &to_bob, &[0; MAX_BLOB_SIZE]), Err(AiroiError::MessageTooLarge { .. })));
+    }
+}
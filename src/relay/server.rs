@@ -0,0 +1,433 @@
+//! The relay side: blobs on disk, one directory per recipient, with quotas and a time to live.
+//!
+//! Blobs are stored as `<stored_at>-<id>.blob` so their age and order are known without an index.
+//! Who deposited a blob is only kept in memory, for the per-sender quota, and never written to
+//! disk; after a restart earlier blobs no longer count towards it. Anyone who completes the
+//! handshake may deposit, and keys cost nothing to make, so the per-peer connection rate of
+//! `ReceiverLimits` only slows down a single key.
+//! The quotas bound the damage: per recipient they keep a mailbox from filling the disk, per
+//! sender and recipient they keep one key from taking a whole mailbox. A flood from many fresh
+//! keys can still fill a mailbox until its blobs are fetched or expire, and every mailbox can be
+//! filled that way, so disk use is only bounded by the number of recipients times their quota.
+//! A published prekey bundle lives next to the blobs in the owner's mailbox as `prekeys.json`.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use base64::Engine;
+use base64::engine::general_purpose;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+use crate::keys::key_gen::generate_key_pair;
+use crate::keys::prekeys::PrekeyBundle;
+use crate::message::envelope::MessageId;
+use crate::message::fragment::{read_payload, write_payload, Reassembler};
+use crate::message::handshake::{exchange_identity, respond};
+use crate::message::limits::{PeerHistory, ReceiverLimits};
+use crate::message::padding::PaddingPolicy;
+use crate::relay::{RelayBlob, RelayRequest, RelayResponse, FETCH_BATCH_BYTES, MAX_BLOB_SIZE, MAX_REQUEST_SIZE};
+use crate::storage::serialize_keypair;
+use crate::transport::{Listener, Stream};
+
+pub const DEFAULT_RELAY_ADDRESS: &str = "0.0.0.0:4445";
+pub const DEFAULT_BLOB_TTL: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+pub const DEFAULT_MAX_BLOBS: usize = 1000;
+pub const DEFAULT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+pub const DEFAULT_MAX_BLOBS_PER_SENDER: usize = 100;
+/// One-time prekeys kept per published bundle
+pub const MAX_ONE_TIME_PREKEYS: usize = 500;
+const PURGE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const PREKEYS_FILE: &str = "prekeys.json";
+
+#[derive(Debug, Clone)]
+pub struct RelayLimits {
+    /// Blobs older than this are deleted
+    pub ttl: Duration,
+    /// Blobs stored per recipient
+    pub max_blobs: usize,
+    /// Bytes stored per recipient
+    pub max_bytes: u64,
+    /// Blobs stored per recipient from a single sender
+    pub max_blobs_per_sender: usize,
+    /// Connection count, handshake deadline and per-peer rate; the handshake timeout also
+    /// applies between two requests
+    pub connections: ReceiverLimits,
+}
+
+impl Default for RelayLimits {
+    fn default() -> Self {
+        RelayLimits {
+            ttl: DEFAULT_BLOB_TTL,
+            max_blobs: DEFAULT_MAX_BLOBS,
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_blobs_per_sender: DEFAULT_MAX_BLOBS_PER_SENDER,
+            connections: ReceiverLimits::default(),
+        }
+    }
+}
+
+struct StoredBlob {
+    id: String,
+    stored_at: i64,
+    path: PathBuf,
+    len: u64,
+}
+
+/// Ed25519 key (base58) of the client that deposited each blob, by mailbox and blob id
+type Depositors = HashMap<PathBuf, HashMap<String, String>>;
+
+pub struct RelayStore {
+    dir: PathBuf,
+    limits: RelayLimits,
+    peer_history: PeerHistory,
+    depositors: Mutex<Depositors>,
+}
+
+impl RelayStore {
+    pub fn new(dir: PathBuf, limits: RelayLimits) -> Result<RelayStore> {
+        std::fs::create_dir_all(&dir)?;
+        Ok(RelayStore { dir, limits, peer_history: PeerHistory::default(), depositors: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn limits(&self) -> &RelayLimits {
+        &self.limits
+    }
+
+    /// Stores a blob from the sender for the recipient's Ed25519 key (both base58), returning
+    /// its id
+    pub fn deposit(&self, sender: &str, recipient: &str, blob: &[u8]) -> Result<String> {
+        if blob.len() > MAX_BLOB_SIZE {
+            return Err(AiroiError::MessageTooLarge { size: blob.len(), max: MAX_BLOB_SIZE });
+        }
+        let dir = self.mailbox(recipient)?;
+        let mut depositors = self.depositors.lock().unwrap();
+        let stored = self.blobs(&dir)?;
+        let bytes = stored.iter().map(|b| b.len).sum::<u64>() + blob.len() as u64;
+        if stored.len() >= self.limits.max_blobs || bytes > self.limits.max_bytes {
+            return Err(AiroiError::Relay(format!("mailbox of {} is full", recipient)));
+        }
+        // fetched, deleted and expired blobs are forgotten here
+        let mailbox = depositors.entry(dir.clone()).or_default();
+        mailbox.retain(|id, _| stored.iter().any(|b| &b.id == id));
+        if mailbox.values().filter(|depositor| *depositor == sender).count() >= self.limits.max_blobs_per_sender {
+            return Err(AiroiError::Relay(format!("too many messages for {} waiting from {}", recipient, sender)));
+        }
+        std::fs::create_dir_all(&dir)?;
+        let id = MessageId::random().to_string();
+        let name = format!("{}-{}.blob", chrono::Utc::now().timestamp_millis(), id);
+        std::fs::write(dir.join(name), blob)?;
+        mailbox.insert(id.clone(), sender.to_string());
+        Ok(id)
+    }
+
+    /// Blobs for the recipient, oldest first, after the given position and up to a batch size
+    pub(crate) fn pending(&self, recipient: &str, after: Option<&(i64, String)>) -> Result<Vec<RelayBlob>> {
+        let dir = self.mailbox(recipient)?;
+        let _guard = self.depositors.lock().unwrap();
+        let mut batch = vec![];
+        let mut bytes = 0;
+        for stored in self.blobs(&dir)? {
+            if after.is_some_and(|(stored_at, id)| (stored.stored_at, &stored.id) <= (*stored_at, id)) {
+                continue;
+            }
+            if bytes + stored.len as usize > FETCH_BATCH_BYTES {
+                break;
+            }
+            bytes += stored.len as usize;
+            batch.push(RelayBlob {
+                id: stored.id,
+                stored_at: stored.stored_at,
+                blob: general_purpose::STANDARD.encode(std::fs::read(&stored.path)?),
+            });
+        }
+        Ok(batch)
+    }
+
+    /// Deletes the recipient's blobs with these ids, returning how many existed
+    pub fn delete(&self, recipient: &str, ids: &[String]) -> Result<usize> {
+        let dir = self.mailbox(recipient)?;
+        let _guard = self.depositors.lock().unwrap();
+        let mut count = 0;
+        for stored in self.blobs(&dir)? {
+            if ids.contains(&stored.id) {
+                std::fs::remove_file(&stored.path)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Deletes every blob past its time to live, returning how many
+    pub fn purge_expired(&self) -> Result<usize> {
+        let _guard = self.depositors.lock().unwrap();
+        let oldest = chrono::Utc::now().timestamp_millis() - self.limits.ttl.as_millis() as i64;
+        let mut count = 0;
+        for mailbox in std::fs::read_dir(&self.dir)? {
+            let mailbox = mailbox?.path();
+            if !mailbox.is_dir() {
+                continue;
+            }
+            for stored in self.list(&mailbox)? {
+                if stored.stored_at < oldest {
+                    std::fs::remove_file(&stored.path)?;
+                    count += 1;
+                }
+            }
+        }
+        Ok(count)
+    }
+
+    /// Replaces the client's prekey bundle, returning how many one-time prekeys were kept
+    pub fn publish_prekeys(&self, client: &str, mut bundle: PrekeyBundle) -> Result<usize> {
+        if bs58::encode(&bundle.identity).into_string() != client {
+            return Err(AiroiError::Relay("prekey bundle is not signed by the client".to_string()));
+        }
+        bundle.verify()?;
+        bundle.one_time_prekeys.truncate(MAX_ONE_TIME_PREKEYS);
+        let dir = self.mailbox(client)?;
+        let _guard = self.depositors.lock().unwrap();
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join(PREKEYS_FILE), serde_json::to_vec(&bundle)?)?;
+        Ok(bundle.one_time_prekeys.len())
+    }
+
+    /// The identity's prekey bundle with its next one-time prekey, which is handed out only once
+    pub fn take_prekeys(&self, identity: &str) -> Result<Option<PrekeyBundle>> {
+        let path = self.mailbox(identity)?.join(PREKEYS_FILE);
+        let _guard = self.depositors.lock().unwrap();
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut bundle: PrekeyBundle = serde_json::from_slice(&std::fs::read(&path)?)?;
+        let one_time_prekeys = match bundle.one_time_prekeys.is_empty() {
+            true => vec![],
+            false => vec![bundle.one_time_prekeys.remove(0)],
+        };
+        std::fs::write(&path, serde_json::to_vec(&bundle)?)?;
+        Ok(Some(PrekeyBundle { one_time_prekeys, ..bundle }))
+    }
+
+    fn mailbox(&self, recipient: &str) -> Result<PathBuf> {
+        // also keeps the name a plain path component
+        match bs58::decode(recipient).into_vec() {
+            Ok(key) if key.len() == 32 => Ok(self.dir.join(recipient)),
+            _ => Err(AiroiError::Relay(format!("invalid recipient {}", recipient))),
+        }
+    }
+
+    /// Unexpired blobs in a mailbox, oldest first
+    fn blobs(&self, dir: &Path) -> Result<Vec<StoredBlob>> {
+        let oldest = chrono::Utc::now().timestamp_millis() - self.limits.ttl.as_millis() as i64;
+        Ok(self.list(dir)?.into_iter().filter(|b| b.stored_at >= oldest).collect())
+    }
+
+    fn list(&self, dir: &Path) -> Result<Vec<StoredBlob>> {
+        if !dir.exists() {
+            return Ok(vec![]);
+        }
+        let mut blobs = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some((stored_at, id)) = name.strip_suffix(".blob").and_then(|stem| stem.split_once('-')) else {
+                continue;
+            };
+            let Ok(stored_at) = stored_at.parse() else {
+                continue;
+            };
+            blobs.push(StoredBlob { id: id.to_string(), stored_at, path: entry.path(), len: entry.metadata()?.len() });
+        }
+        blobs.sort_by(|a, b| (a.stored_at, &a.id).cmp(&(b.stored_at, &b.id)));
+        Ok(blobs)
+    }
+
+    fn handle(&self, client: &str, request: RelayRequest) -> Result<RelayResponse> {
+        match request {
+            RelayRequest::Deposit { recipient, blob } => {
+                let blob = general_purpose::STANDARD.decode(blob)?;
+                Ok(RelayResponse::Stored { id: self.deposit(client, &recipient, &blob)? })
+            }
+            RelayRequest::Fetch { after } => Ok(RelayResponse::Blobs { blobs: self.pending(client, after.as_ref())? }),
+            RelayRequest::Delete { ids } => Ok(RelayResponse::Deleted { count: self.delete(client, &ids)? }),
+            RelayRequest::PublishPrekeys { bundle } => {
+                Ok(RelayResponse::Published { one_time_prekeys: self.publish_prekeys(client, bundle)? })
+            }
+            RelayRequest::FetchPrekeys { identity } => Ok(RelayResponse::Prekeys { bundle: self.take_prekeys(&identity)? }),
+        }
+    }
+}
+
+/// The relay's key pair from `path`, generated and stored there on first start
+pub fn load_or_generate_keys(path: &Path) -> Result<KeyPair> {
+    if path.exists() {
+        return Ok(serde_json::from_slice(&std::fs::read(path)?)?);
+    }
+    let keys = generate_key_pair()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, serialize_keypair(&keys)?)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+    }
+    Ok(keys)
+}
+
+/// Accepts relay clients until the listener fails, purging expired blobs along the way
+pub async fn serve(mut listener: Box<dyn Listener>, keys: KeyPair, store: Arc<RelayStore>) -> Result<()> {
+    let keys = Arc::new(keys);
+    let max_connections = store.limits.connections.max_connections;
+    let permits = Arc::new(Semaphore::new(max_connections));
+    let mut connections = JoinSet::new();
+    let mut purge = tokio::time::interval(PURGE_INTERVAL);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (socket, peer_addr) = accepted?;
+                let Ok(permit) = permits.clone().try_acquire_owned() else {
+                    eprintln!("refusing connection from {}: {}", peer_addr, AiroiError::TooManyConnections { max: max_connections });
+                    continue;
+                };
+                let keys = keys.clone();
+                let store = store.clone();
+                connections.spawn(async move {
+                    if let Err(e) = handle_client(&keys, socket, &store).await {
+                        eprintln!("relay client error from {}: {}", peer_addr, e);
+                    }
+                    drop(permit);
+                });
+            }
+            Some(_) = connections.join_next() => {}
+            _ = purge.tick() => match store.purge_expired() {
+                Ok(0) => {}
+                Ok(count) => println!("Purged {} expired blobs", count),
+                Err(e) => eprintln!("purge error: {}", e),
+            },
+        }
+    }
+}
+
+/// Authenticates a client and answers its requests until it disconnects or goes quiet
+pub async fn handle_client<S: Stream>(keys: &KeyPair, mut socket: S, store: &RelayStore) -> Result<()> {
+    let limits = &store.limits.connections;
+    let (mut handshake, identity) = tokio::time::timeout(limits.handshake_timeout, async {
+        let mut handshake = respond(&mut socket, keys.private_key().x25519_key_raw()).await?;
+        let identity = exchange_identity(&mut socket, &mut handshake, keys, false).await?;
+        Ok::<_, AiroiError>((handshake, identity))
+    })
+        .await
+        .map_err(|_| AiroiError::HandshakeTimeout(limits.handshake_timeout))??;
+    let client = bs58::encode(identity).into_string();
+    store.peer_history.check(&client, limits)?;
+
+    let mut reassembler = Reassembler::new(MAX_REQUEST_SIZE);
+    loop {
+        let read = read_payload(&mut socket, &mut handshake.transport, &mut reassembler);
+        let Ok(Ok(payload)) = tokio::time::timeout(limits.handshake_timeout, read).await else {
+            return Ok(());
+        };
+        let response = serde_json::from_slice::<RelayRequest>(&payload)
+            .map_err(AiroiError::from)
+            .and_then(|request| store.handle(&client, request))
+            .unwrap_or_else(|e| RelayResponse::Refused { reason: e.to_string() });
+        write_payload(&mut socket, &mut handshake.transport, &serde_json::to_vec(&response)?, PaddingPolicy::default()).await?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(name: &str, limits: RelayLimits) -> RelayStore {
+        let dir = std::env::temp_dir().join(format!("airoi-relay-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        RelayStore::new(dir, limits).unwrap()
+    }
+
+    fn recipient() -> String {
+        generate_key_pair().unwrap().public_key().ed25519_key().to_string()
+    }
+
+    #[test]
+    fn test_quotas() {
+        let store = store("quotas", RelayLimits { max_blobs: 2, max_bytes: 100, ..RelayLimits::default() });
+        let (alice, bob) = (recipient(), recipient());
+        store.deposit(&alice, &bob, &[1; 40]).unwrap();
+        store.deposit(&alice, &bob, &[2; 40]).unwrap();
+        assert!(matches!(store.deposit(&alice, &bob, &[3; 10]), Err(AiroiError::Relay(_))));
+        // quotas are per recipient
+        let carol = recipient();
+        assert!(matches!(store.deposit(&alice, &carol, &[4; 101]), Err(AiroiError::Relay(_))));
+        store.deposit(&alice, &carol, &[4; 100]).unwrap();
+        assert!(store.deposit(&alice, "../escape", &[5]).is_err());
+
+        let pending = store.pending(&bob, None).unwrap();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(store.pending(&bob, Some(&(pending[0].stored_at, pending[0].id.clone()))).unwrap().len(), 1);
+        assert_eq!(store.delete(&bob, &[pending[0].id.clone()]).unwrap(), 1);
+        store.deposit(&alice, &bob, &[3; 10]).unwrap();
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+
+    #[test]
+    fn test_sender_quota() {
+        let store = store("senders", RelayLimits { max_blobs_per_sender: 2, ..RelayLimits::default() });
+        let (alice, bob, carol) = (recipient(), recipient(), recipient());
+        store.deposit(&alice, &bob, &[1; 10]).unwrap();
+        store.deposit(&alice, &bob, &[2; 10]).unwrap();
+        assert!(matches!(store.deposit(&alice, &bob, &[3; 10]), Err(AiroiError::Relay(_))));
+        // other senders and other recipients are not affected
+        store.deposit(&carol, &bob, &[4; 10]).unwrap();
+        store.deposit(&alice, &carol, &[5; 10]).unwrap();
+
+        let pending = store.pending(&bob, None).unwrap();
+        assert_eq!(pending.len(), 3);
+        // fetched messages no longer count
+        store.delete(&bob, &pending.into_iter().map(|b| b.id).collect::<Vec<_>>()).unwrap();
+        store.deposit(&alice, &bob, &[3; 10]).unwrap();
+        // senders stay off the disk
+        let names = std::fs::read_dir(store.mailbox(&bob).unwrap()).unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert!(names.iter().all(|name| !name.contains(&alice) && !name.contains(&carol)));
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+
+    #[test]
+    fn test_prekeys_are_handed_out_once() {
+        let store = store("prekeys", RelayLimits::default());
+        let bob = generate_key_pair().unwrap();
+        let client = bob.public_key().ed25519_key().to_string();
+        let path = std::env::temp_dir().join(format!("airoi-relay-prekeys-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let bundle = crate::keys::prekeys::PrekeyStore::at(path.clone(), &bob).bundle(&bob, 2).unwrap();
+
+        assert!(store.publish_prekeys(&recipient(), bundle.clone()).is_err());
+        assert!(store.take_prekeys(&client).unwrap().is_none());
+        assert_eq!(store.publish_prekeys(&client, bundle.clone()).unwrap(), 2);
+        for expected in [Some(&bundle.one_time_prekeys[0]), Some(&bundle.one_time_prekeys[1]), None] {
+            let taken = store.take_prekeys(&client).unwrap().unwrap();
+            assert_eq!(taken.signed_prekey, bundle.signed_prekey);
+            assert_eq!(taken.one_time_prekeys.first(), expected);
+        }
+        // the bundle file is not mistaken for a blob
+        assert!(store.pending(&client, None).unwrap().is_empty());
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+
+    #[test]
+    fn test_expired_blobs_are_dropped() {
+        let store = store("ttl", RelayLimits { ttl: Duration::ZERO, ..RelayLimits::default() });
+        let (alice, bob) = (recipient(), recipient());
+        store.deposit(&alice, &bob, &[1; 10]).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert!(store.pending(&bob, None).unwrap().is_empty());
+        assert_eq!(store.purge_expired().unwrap(), 1);
+        let _ = std::fs::remove_dir_all(&store.dir);
+    }
+}
@@ -65,7 +65,7 @@ struct EncryptedKeystore {
 
 fn keystore_path() -> Result<PathBuf> {
     let mut p = config_dir().ok_or_else(|| {
-        return std::io::Error::new(std::io::ErrorKind::NotFound, "No config dir found")
+        std::io::Error::new(std::io::ErrorKind::NotFound, "No config dir found")
     })?;
     p.push("airoi");
     std::fs::create_dir_all(&p)?;
@@ -105,8 +105,8 @@ pub fn save_keypair_to_encrypted_file(kp: &KeyPair, passphrase: &str) -> Result<
     key_mut.zeroize();
 
     let enc = EncryptedKeystore {
-        salt_b64: general_purpose::STANDARD.encode(&salt),
-        nonce_b64: general_purpose::STANDARD.encode(&nonce),
+        salt_b64: general_purpose::STANDARD.encode(salt),
+        nonce_b64: general_purpose::STANDARD.encode(nonce),
         ct_b64: general_purpose::STANDARD.encode(&ct),
         argon_m: m,
         argon_t: t,
@@ -7,7 +7,7 @@ use crate::storage::serialize_keypair;
 use keyring::{Entry};
 
 pub fn save_keypair_to_keyring(service: &str, account: &str, kp: &KeyPair) -> Result<()> {
-    let serialized = serialize_keypair(&kp)?;
+    let serialized = serialize_keypair(kp)?;
     let encoded = general_purpose::STANDARD.encode(&serialized);
 
     let kr = Entry::new(service, account)?;
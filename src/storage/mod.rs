@@ -1,5 +1,6 @@
 mod keyring;
 mod encrypted_file;
+pub(crate) mod sealed;
 
 use crate::keys::KeyPair;
 use zeroize::Zeroize;
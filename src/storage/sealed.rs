@@ -0,0 +1,88 @@
+//! Local files encrypted under a key derived from the local key pair, so they can be read
+//! without asking for a passphrase again but not by anyone without the private key.
+use std::ffi::OsString;
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use base64::engine::general_purpose;
+use chacha20poly1305::{KeyInit, XChaCha20Poly1305};
+use chacha20poly1305::aead::Aead;
+use rand::TryRngCore;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use crate::error::{AiroiError, Result};
+use crate::keys::KeyPair;
+
+#[derive(Debug, serde::Deserialize, serde::Serialize)]
+struct SealedFile {
+    nonce_b64: String,
+    ct_b64: String,
+}
+
+/// Key for the local file used for `purpose`; every purpose gets its own key
+pub(crate) fn storage_key(keys: &KeyPair, purpose: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"airoi storage v1");
+    hasher.update(purpose.as_bytes());
+    hasher.update(keys.private_key().x25519_key_raw());
+    hasher.finalize().into()
+}
+
+pub(crate) fn write_sealed(path: &Path, key: &[u8; 32], plain_text: &[u8]) -> Result<()> {
+    let mut nonce = [0u8; 24];
+    OsRng.try_fill_bytes(&mut nonce)?;
+    let cipher = XChaCha20Poly1305::new_from_slice(key)
+        .map_err(|e| AiroiError::XChaCha20Poly1305(e.to_string()))?;
+    let ct = cipher.encrypt(&nonce.into(), plain_text)
+        .map_err(|e| AiroiError::XChaCha20Poly1305(e.to_string()))?;
+
+    let sealed = SealedFile {
+        nonce_b64: general_purpose::STANDARD.encode(nonce),
+        ct_b64: general_purpose::STANDARD.encode(&ct),
+    };
+    // written next to the target and renamed, so a crash never leaves half a file. The name is
+    // unique so writers in other processes never share the temporary file.
+    let tmp = sibling(path, &format!("{}-{}.tmp", std::process::id(), bs58::encode(rand::random::<[u8; 8]>()).into_string()));
+    std::fs::write(&tmp, serde_json::to_string(&sealed)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// `path` with `suffix` appended to its file name
+fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().map(OsString::from).unwrap_or_default();
+    name.push(".");
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+/// Exclusive lock on a sealed file, released when dropped
+pub(crate) struct SealedLock {
+    _file: File,
+}
+
+/// Locks a sealed file against other threads and processes. Held across a read and the write
+/// that follows, so concurrent airoi processes do not lose each other's updates.
+pub(crate) fn lock_sealed(path: &Path) -> Result<SealedLock> {
+    let file = OpenOptions::new().create(true).truncate(false).write(true).open(sibling(path, "lock"))?;
+    file.lock()?;
+    Ok(SealedLock { _file: file })
+}
+
+/// Contents of a sealed file, `None` if it does not exist yet
+pub(crate) fn read_sealed(path: &Path, key: &[u8; 32]) -> Result<Option<Vec<u8>>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let sealed: SealedFile = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let nonce = general_purpose::STANDARD.decode(&sealed.nonce_b64)?;
+    let ct = general_purpose::STANDARD.decode(&sealed.ct_b64)?;
+    if nonce.len() != 24 {
+        return Err(AiroiError::XChaCha20Poly1305("invalid nonce length".to_string()));
+    }
+    let cipher = XChaCha20Poly1305::new_from_slice(key)
+        .map_err(|e| AiroiError::XChaCha20Poly1305(e.to_string()))?;
+    let plain_text = cipher.decrypt(nonce.as_slice().into(), ct.as_ref())
+        .map_err(|e| AiroiError::XChaCha20Poly1305(e.to_string()))?;
+    Ok(Some(plain_text))
+}
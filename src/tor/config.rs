@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tokio::io::AsyncWriteExt;
 use crate::error::{AiroiError, Result};
 use crate::util::get_airoi_dir;
@@ -35,7 +35,24 @@ pub fn kill_tor_daemon(child: &mut Child) -> Result<()> {
     Ok(())
 }
 
-pub async fn read_onion_addr(hidden_service_dir: &PathBuf) -> Result<String> {
+/// Tor daemon that is killed once the connection depending on it goes away
+pub struct TorDaemon {
+    child: Child,
+}
+
+impl TorDaemon {
+    pub fn new(child: Child) -> TorDaemon {
+        TorDaemon { child }
+    }
+}
+
+impl Drop for TorDaemon {
+    fn drop(&mut self) {
+        let _ = kill_tor_daemon(&mut self.child);
+    }
+}
+
+pub async fn read_onion_addr(hidden_service_dir: &Path) -> Result<String> {
     let hostname_path = hidden_service_dir.join("hostname");
     let addr = tokio::fs::read_to_string(hostname_path).await?;
     Ok(addr.trim().to_string())
@@ -43,16 +60,14 @@ pub async fn read_onion_addr(hidden_service_dir: &PathBuf) -> Result<String> {
 
 pub fn get_hidden_service_dir() -> PathBuf {
     let airoi_dir = get_airoi_dir();
-    let hidden_service_dir = airoi_dir.join("tor_service");
-    hidden_service_dir
+    airoi_dir.join("tor_service")
 }
 
-pub async fn wait_for_onion(hidden_service_dir: &PathBuf) -> Result<String> {
+pub async fn wait_for_onion(hidden_service_dir: &Path) -> Result<String> {
     println!("Waiting for .onion to be ready...");
-    let hostname_path = hidden_service_dir.join("hostname");
     for _ in 0..20 {
-        if let Ok(addr) = tokio::fs::read_to_string(&hostname_path).await {
-            return Ok(addr.trim().to_string());
+        if let Ok(addr) = read_onion_addr(hidden_service_dir).await {
+            return Ok(addr);
         }
         tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
     }
@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use tokio::io::{duplex, DuplexStream};
+use tokio::sync::mpsc;
+use crate::error::{AiroiError, Result};
+use crate::transport::{BoxStream, Listener, Transport};
+
+const BUFFER_SIZE: usize = 256 * 1024;
+const BACKLOG: usize = 16;
+
+/// In-process pipes. Clones share the same address space, so one side can listen on a name
+/// and another connect to it, e.g. in tests.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryTransport {
+    listeners: Arc<Mutex<HashMap<String, mpsc::Sender<DuplexStream>>>>,
+}
+
+impl MemoryTransport {
+    pub fn new() -> MemoryTransport {
+        MemoryTransport::default()
+    }
+}
+
+#[async_trait]
+impl Transport for MemoryTransport {
+    async fn connect(&self, address: &str) -> Result<BoxStream> {
+        let listener = self.listeners.lock().unwrap().get(address).cloned();
+        let refused = || AiroiError::Io(std::io::ErrorKind::ConnectionRefused.into());
+        let listener = listener.ok_or_else(refused)?;
+        let (local, remote) = duplex(BUFFER_SIZE);
+        listener.send(remote).await.map_err(|_| refused())?;
+        Ok(Box::new(local))
+    }
+
+    async fn listen(&self, address: &str) -> Result<Box<dyn Listener>> {
+        let (tx, rx) = mpsc::channel(BACKLOG);
+        self.listeners.lock().unwrap().insert(address.to_string(), tx);
+        Ok(Box::new(MemoryListener { address: address.to_string(), incoming: rx, listeners: self.listeners.clone() }))
+    }
+}
+
+struct MemoryListener {
+    address: String,
+    incoming: mpsc::Receiver<DuplexStream>,
+    listeners: Arc<Mutex<HashMap<String, mpsc::Sender<DuplexStream>>>>,
+}
+
+impl Drop for MemoryListener {
+    /// Frees the address, unless another listener took it over since
+    fn drop(&mut self) {
+        self.incoming.close();
+        self.listeners.lock().unwrap().retain(|_, tx| !tx.is_closed());
+    }
+}
+
+#[async_trait]
+impl Listener for MemoryListener {
+    async fn accept(&mut self) -> Result<(BoxStream, String)> {
+        let stream = self.incoming.recv().await
+            .ok_or_else(|| AiroiError::Io(std::io::ErrorKind::NotConnected.into()))?;
+        Ok((Box::new(stream), format!("memory:{}", self.address)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_dropped_listener_frees_its_address() {
+        let transport = MemoryTransport::new();
+        let listener = transport.listen("bob").await.unwrap();
+        drop(listener);
+        assert!(transport.listeners.lock().unwrap().is_empty());
+        assert!(matches!(transport.connect("bob").await, Err(AiroiError::Io(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused));
+
+        // a replaced listener going away leaves its successor in place
+        let first = transport.listen("bob").await.unwrap();
+        let mut second = transport.listen("bob").await.unwrap();
+        drop(first);
+        transport.connect("bob").await.unwrap();
+        second.accept().await.unwrap();
+    }
+}
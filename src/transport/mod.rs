@@ -0,0 +1,41 @@
+//! Carriers that messaging runs over.
+//!
+//! Handshakes, sessions and receipts only need a byte stream. A `Transport` dials an address
+//! or listens on one and yields such streams, so the same code runs over Tor, plain TCP, Unix
+//! domain sockets or an in-memory pipe in tests.
+mod memory;
+mod tcp;
+mod tor;
+#[cfg(unix)]
+mod unix;
+
+use async_trait::async_trait;
+use tokio::io::{AsyncRead, AsyncWrite};
+use crate::error::Result;
+
+pub use memory::MemoryTransport;
+pub use tcp::TcpTransport;
+pub use tor::TorTransport;
+#[cfg(unix)]
+pub use unix::UnixTransport;
+
+/// A connected, bidirectional byte stream
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> Stream for T {}
+
+pub type BoxStream = Box<dyn Stream>;
+
+#[async_trait]
+pub trait Transport: Send + Sync {
+    /// Opens a stream to a contact's address
+    async fn connect(&self, address: &str) -> Result<BoxStream>;
+    /// Starts accepting streams on a local address
+    async fn listen(&self, address: &str) -> Result<Box<dyn Listener>>;
+}
+
+#[async_trait]
+pub trait Listener: Send {
+    /// Next inbound stream together with a description of where it came from
+    async fn accept(&mut self) -> Result<(BoxStream, String)>;
+}
@@ -0,0 +1,27 @@
+use async_trait::async_trait;
+use tokio::net::{TcpListener, TcpStream};
+use crate::error::Result;
+use crate::transport::{BoxStream, Listener, Transport};
+
+/// Direct TCP, addresses are `host:port`
+#[derive(Debug, Clone, Default)]
+pub struct TcpTransport;
+
+#[async_trait]
+impl Transport for TcpTransport {
+    async fn connect(&self, address: &str) -> Result<BoxStream> {
+        Ok(Box::new(TcpStream::connect(address).await?))
+    }
+
+    async fn listen(&self, address: &str) -> Result<Box<dyn Listener>> {
+        Ok(Box::new(TcpListener::bind(address).await?))
+    }
+}
+
+#[async_trait]
+impl Listener for TcpListener {
+    async fn accept(&mut self) -> Result<(BoxStream, String)> {
+        let (stream, peer_addr) = TcpListener::accept(self).await?;
+        Ok((Box::new(stream), peer_addr.to_string()))
+    }
+}
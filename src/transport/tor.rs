@@ -0,0 +1,59 @@
+use async_trait::async_trait;
+use tokio::net::TcpListener;
+use tokio::sync::OnceCell;
+use tokio_socks::tcp::Socks5Stream;
+use crate::error::{AiroiError, Result};
+use crate::tor::config::{get_hidden_service_dir, setup_tor, start_tor_daemon, wait_for_onion, wait_for_tor_ready, TorDaemon};
+use crate::transport::{BoxStream, Listener, Transport};
+
+const SOCKS_PROXY: &str = "127.0.0.1:9050";
+/// Port of the hidden service, used when a contact's address has none
+pub const ONION_PORT: u16 = 4444;
+
+/// Tor hidden services, addresses are onion hosts. The Tor daemon is started on first use and
+/// shared by every connection and listener of this transport until it is dropped.
+#[derive(Default)]
+pub struct TorTransport {
+    daemon: OnceCell<TorDaemon>,
+}
+
+impl TorTransport {
+    pub fn new() -> TorTransport {
+        TorTransport::default()
+    }
+
+    async fn start(&self) -> Result<()> {
+        self.daemon.get_or_try_init(|| async {
+            let torrc = setup_tor().await?;
+            let daemon = TorDaemon::new(start_tor_daemon(&torrc)?);
+            let onion_addr = wait_for_onion(&get_hidden_service_dir()).await?;
+            println!("Your onion service address is: {}", onion_addr);
+            wait_for_tor_ready().await?;
+            println!("Tor ready");
+            Ok::<_, AiroiError>(daemon)
+        }).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Transport for TorTransport {
+    async fn connect(&self, address: &str) -> Result<BoxStream> {
+        self.start().await?;
+        let target = if address.contains(':') {
+            address.to_string()
+        } else {
+            format!("{}:{}", address, ONION_PORT)
+        };
+        let stream = Socks5Stream::connect(SOCKS_PROXY, target).await
+            .map_err(|e| AiroiError::Onion(e.to_string()))?;
+        println!("connected to {}", address);
+        Ok(Box::new(stream.into_inner()))
+    }
+
+    /// Listens on the local end of the hidden service
+    async fn listen(&self, address: &str) -> Result<Box<dyn Listener>> {
+        self.start().await?;
+        Ok(Box::new(TcpListener::bind(address).await?))
+    }
+}
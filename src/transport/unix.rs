@@ -0,0 +1,44 @@
+use std::io;
+use std::os::unix::fs::FileTypeExt;
+use async_trait::async_trait;
+use tokio::net::{UnixListener, UnixStream};
+use crate::error::Result;
+use crate::transport::{BoxStream, Listener, Transport};
+
+/// Unix domain sockets, addresses are socket paths
+#[derive(Debug, Clone, Default)]
+pub struct UnixTransport;
+
+#[async_trait]
+impl Transport for UnixTransport {
+    async fn connect(&self, address: &str) -> Result<BoxStream> {
+        Ok(Box::new(UnixStream::connect(address).await?))
+    }
+
+    async fn listen(&self, address: &str) -> Result<Box<dyn Listener>> {
+        // a socket file left behind by an earlier listener would make bind fail, anything else at
+        // the address is left alone
+        match std::fs::symlink_metadata(address) {
+            Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(address)?,
+            Ok(_) => return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", address),
+            ).into()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Box::new(UnixListener::bind(address)?))
+    }
+}
+
+#[async_trait]
+impl Listener for UnixListener {
+    async fn accept(&mut self) -> Result<(BoxStream, String)> {
+        let (stream, peer_addr) = UnixListener::accept(self).await?;
+        let peer = peer_addr
+            .as_pathname()
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|| "unix socket".to_string());
+        Ok((Box::new(stream), peer))
+    }
+}
@@ -5,4 +5,7 @@ pub fn get_airoi_dir() -> PathBuf {
     path.push("airoi");
     std::fs::create_dir_all(&path).unwrap();
     path
-}
\ No newline at end of file
+}
+pub fn get_downloads_dir() -> PathBuf {
+    get_airoi_dir().join("downloads")
+}
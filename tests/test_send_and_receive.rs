@@ -1,50 +1,68 @@
 #[cfg(test)]
 mod integration {
-    use std::sync::Arc;
-    use snow::Builder;
-    use snow::params::NoiseParams;
-    use tokio::net::TcpListener;
     use airoi_core::keys::contacts::Contact;
     use airoi_core::keys::key_gen::generate_key_pair;
-    use airoi_core::message::receive::handle_connection;
-    use airoi_core::message::send::send;
+    use airoi_core::message::receive::{handle_connection, ReceiveConfig};
+    use airoi_core::message::send::send_via;
+    use airoi_core::transport::{MemoryTransport, TcpTransport, Transport};
 
-    #[tokio::test]
-    async fn test_send_and_receive() {
-        let params: NoiseParams = "Noise_XX_25519_ChaChaPoly_BLAKE2s".parse().unwrap();
-
-        let keypair = generate_key_pair().unwrap();
-
-        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
-        let addr = listener.local_addr().unwrap();
-
-        let contacts = Arc::new(vec![Contact::new(
-            "test-self".to_string(),
-            keypair.public_key().ed25519_key_raw().to_vec(),
-            &addr.to_string()
-        )]);
+    /// Alice sends to Bob, who listens on `address` of `carrier`
+    async fn send_and_receive(carrier: &dyn Transport, address: &str) {
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
 
+        let mut listener = carrier.listen(address).await.unwrap();
+        let address = address.to_string();
         let (tx, mut rx) = tokio::sync::mpsc::channel(1);
 
         {
-            let contacts = contacts.clone();
-            let params = params.clone();
-            let keypair = keypair.clone();
-            let tx = tx.clone();
-
+            let bob = bob.clone();
+            let contacts = vec![Contact::new(
+                "alice".to_string(),
+                alice.public_key().ed25519_key_raw().to_vec(),
+                "alice",
+            )];
             tokio::spawn(async move {
-                let (mut socket, _) = listener.accept().await.unwrap();
-                let mut builder = Builder::new(params.clone());
-                builder = builder.local_private_key(keypair.private_key().x25519_key_raw()).unwrap();
-                let _ = handle_connection(builder, &mut socket, &contacts, tx).await;
+                let (socket, peer_addr) = listener.accept().await.unwrap();
+                let _ = handle_connection(&bob, socket, &peer_addr, &contacts, tx, &ReceiveConfig::default()).await;
             });
         }
-        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
 
-        let contact = contacts[0].clone();
-        send(contact, "test message").await.unwrap();
+        let contact = Contact::new("bob".to_string(), bob.public_key().ed25519_key_raw().to_vec(), &address);
+        let status = send_via(carrier, &alice, contact, "test message").await.unwrap();
+        assert!(status.is_delivered());
 
         let received = rx.recv().await.unwrap();
         assert_eq!(received.message, "test message");
+        assert_eq!(received.sender.name, "alice");
+    }
+
+    #[tokio::test]
+    async fn test_send_and_receive() {
+        send_and_receive(&MemoryTransport::new(), "bob").await;
+    }
+
+    #[tokio::test]
+    async fn test_send_and_receive_over_tcp() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        send_and_receive(&TcpTransport, &format!("127.0.0.1:{}", port)).await;
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_send_and_receive_over_unix_socket() {
+        let path = std::env::temp_dir().join(format!("airoi-test-{}.sock", std::process::id()));
+        send_and_receive(&airoi_core::transport::UnixTransport, path.to_str().unwrap()).await;
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_unix_listen_keeps_files_that_are_not_sockets() {
+        let path = std::env::temp_dir().join(format!("airoi-test-{}.not-a-sock", std::process::id()));
+        std::fs::write(&path, "keep me").unwrap();
+        assert!(airoi_core::transport::UnixTransport.listen(path.to_str().unwrap()).await.is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "keep me");
+        let _ = std::fs::remove_file(path);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,77 @@
+#[cfg(test)]
+mod integration {
+    use std::sync::Arc;
+    use airoi_core::keys::KeyPair;
+    use airoi_core::keys::contacts::Contact;
+    use airoi_core::keys::key_gen::generate_key_pair;
+    use airoi_core::keys::prekeys::PrekeyStore;
+    use airoi_core::message::envelope::Envelope;
+    use airoi_core::message::ratchet::RatchetStore;
+    use airoi_core::relay::server::{serve, RelayLimits, RelayStore};
+    use airoi_core::relay::{deposit, fetch, publish_prekeys};
+    use airoi_core::transport::{TcpTransport, Transport};
+
+    fn contact(name: &str, keys: &KeyPair) -> Contact {
+        Contact::new(name.to_string(), keys.public_key().ed25519_key_raw().to_vec(), name)
+    }
+
+    fn ratchets(name: &str, keys: &KeyPair) -> RatchetStore {
+        let path = std::env::temp_dir().join(format!("airoi-relay-ratchets-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        RatchetStore::at(path, keys)
+    }
+
+    fn prekeys(name: &str, keys: &KeyPair) -> PrekeyStore {
+        let path = std::env::temp_dir().join(format!("airoi-relay-prekeys-{}-{}.json", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        PrekeyStore::at(path, keys)
+    }
+
+    /// Starts a relay on a loopback port and returns its address
+    async fn start_relay() -> String {
+        let port = std::net::TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address = format!("127.0.0.1:{}", port);
+        let dir = std::env::temp_dir().join(format!("airoi-relay-test-{}", port));
+        let store = Arc::new(RelayStore::new(dir, RelayLimits::default()).unwrap());
+        let listener = TcpTransport.listen(&address).await.unwrap();
+        tokio::spawn(serve(listener, generate_key_pair().unwrap(), store));
+        address
+    }
+
+    #[tokio::test]
+    async fn test_store_and_forward() {
+        let relay = start_relay().await;
+        let alice = generate_key_pair().unwrap();
+        let bob = generate_key_pair().unwrap();
+        let carol = generate_key_pair().unwrap();
+        let (alice_ratchets, bob_ratchets, carol_ratchets) = (ratchets("alice", &alice), ratchets("bob", &bob), ratchets("carol", &carol));
+        let (alice_prekeys, bob_prekeys) = (prekeys("alice", &alice), prekeys("bob", &bob));
+
+        // bob publishes prekeys, so the first message to him already starts from one of them
+        assert_eq!(publish_prekeys(&TcpTransport, &bob, &bob_prekeys, &relay, 1).await.unwrap(), 1);
+
+        let envelope = Envelope::text("left while you were away");
+        deposit(&TcpTransport, &alice, &alice_ratchets, &relay, &contact("bob", &bob), &envelope).await.unwrap();
+        deposit(&TcpTransport, &carol, &carol_ratchets, &relay, &contact("bob", &bob), &Envelope::text("hi, it's carol")).await.unwrap();
+
+        // carol is not a contact of bob yet, her message waits
+        let messages = fetch(&TcpTransport, &bob, &bob_ratchets, &bob_prekeys, &relay, &[contact("alice", &alice)]).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!((messages[0].id, messages[0].message.as_str()), (envelope.id, "left while you were away"));
+        assert_eq!(messages[0].sender.name, "alice");
+
+        // only the recipient's own mailbox is handed out
+        assert!(fetch(&TcpTransport, &alice, &alice_ratchets, &alice_prekeys, &relay, &[contact("carol", &carol)]).await.unwrap().is_empty());
+
+        let contacts = [contact("alice", &alice), contact("carol", &carol)];
+        let messages = fetch(&TcpTransport, &bob, &bob_ratchets, &bob_prekeys, &relay, &contacts).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].sender.name, "carol");
+        assert!(fetch(&TcpTransport, &bob, &bob_ratchets, &bob_prekeys, &relay, &contacts).await.unwrap().is_empty());
+
+        // replies continue the ratchet bob now shares with alice
+        deposit(&TcpTransport, &bob, &bob_ratchets, &relay, &contact("alice", &alice), &Envelope::text("back now")).await.unwrap();
+        let messages = fetch(&TcpTransport, &alice, &alice_ratchets, &alice_prekeys, &relay, &[contact("bob", &bob)]).await.unwrap();
+        assert_eq!(messages[0].message, "back now");
+    }
+}